@@ -0,0 +1,27 @@
+-- Full-text index over note titles and bodies, kept in sync with `notes`
+create virtual table notes_fts using fts5
+(
+    title,
+    body,
+    content = 'notes',
+    content_rowid = 'rowid',
+    tokenize = 'unicode61 remove_diacritics 2'
+);
+
+insert into notes_fts(notes_fts) values ('rebuild');
+
+create trigger notes_fts_insert after insert on notes
+begin
+    insert into notes_fts(rowid, title, body) values (new.rowid, new.title, new.body);
+end;
+
+create trigger notes_fts_delete after delete on notes
+begin
+    insert into notes_fts(notes_fts, rowid, title, body) values ('delete', old.rowid, old.title, old.body);
+end;
+
+create trigger notes_fts_update after update of title, body on notes
+begin
+    insert into notes_fts(notes_fts, rowid, title, body) values ('delete', old.rowid, old.title, old.body);
+    insert into notes_fts(rowid, title, body) values (new.rowid, new.title, new.body);
+end;
@@ -0,0 +1,22 @@
+create table note_revisions
+(
+    id          varchar not null primary key,
+    note_id     varchar not null,
+    user_id     varchar not null,
+    created_at  datetime not null,
+    group_id    varchar null,
+    title       varchar not null,
+    date_tag    datetime null,
+    body        varchar not null,
+    public      int not null,
+    pinned      int not null
+);
+
+create index note_revisions_note_id on note_revisions (note_id, created_at);
+
+-- seed every existing note with its current state as the first revision
+insert into note_revisions
+select lower(hex(randomblob(4)) || '-' || hex(randomblob(2)) || '-4' || substr(hex(randomblob(2)), 2) || '-' ||
+             substr('89ab', 1 + (abs(random()) % 4), 1) || substr(hex(randomblob(2)), 2) || '-' || hex(randomblob(6))),
+       id, user_id, datetime('now'), group_id, title, date_tag, body, public, pinned
+from notes;
@@ -0,0 +1,2 @@
+drop table note_tags;
+drop table tags;
@@ -0,0 +1,18 @@
+create table tags
+(
+    id          varchar not null primary key,
+    user_id     varchar not null,
+    name        varchar not null,
+    created_at  datetime not null,
+    unique (user_id, name)
+);
+
+create table note_tags
+(
+    id          varchar not null primary key,
+    note_id     varchar not null,
+    tag_id      varchar not null,
+    unique (note_id, tag_id)
+);
+
+create index note_tags_tag_id on note_tags (tag_id);
@@ -0,0 +1,14 @@
+create table attachments
+(
+    id          varchar not null primary key,
+    note_id     varchar not null,
+    user_id     varchar not null,
+    filename    varchar not null,
+    mime_type   varchar not null,
+    size        bigint not null,
+    sha256      varchar not null,
+    created_at  datetime not null
+);
+
+create index attachments_note_id on attachments (note_id);
+create index attachments_sha256 on attachments (sha256);
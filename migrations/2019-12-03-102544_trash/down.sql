@@ -0,0 +1,5 @@
+drop index groups_deleted_at;
+drop index notes_deleted_at;
+
+alter table groups drop column deleted_at;
+alter table notes drop column deleted_at;
@@ -0,0 +1,5 @@
+alter table notes add column deleted_at datetime null;
+alter table groups add column deleted_at datetime null;
+
+create index notes_deleted_at on notes (deleted_at);
+create index groups_deleted_at on groups (deleted_at);
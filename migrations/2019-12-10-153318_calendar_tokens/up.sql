@@ -0,0 +1,10 @@
+create table calendar_tokens
+(
+    id          varchar not null primary key,
+    user_id     varchar not null,
+    token_hash  varchar not null unique,
+    created_at  datetime not null,
+    revoked_at  datetime
+);
+
+create index calendar_tokens_user_id on calendar_tokens (user_id);
@@ -0,0 +1,3 @@
+drop table notification_deliveries;
+drop table notifications;
+drop table reminders;
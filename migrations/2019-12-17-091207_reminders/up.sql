@@ -0,0 +1,42 @@
+create table reminders
+(
+    id              varchar not null primary key,
+    note_id         varchar not null,
+    user_id         varchar not null,
+    offset_minutes  integer,
+    remind_at       datetime,
+    fire_at         datetime,
+    fired_at        datetime,
+    created_at      datetime not null
+);
+
+create index reminders_note_id on reminders (note_id);
+create index reminders_due on reminders (fired_at, fire_at);
+
+create table notifications
+(
+    id           varchar not null primary key,
+    user_id      varchar not null,
+    note_id      varchar,
+    reminder_id  varchar unique,
+    title        varchar not null,
+    body         varchar not null,
+    created_at   datetime not null,
+    read_at      datetime
+);
+
+create index notifications_user_id on notifications (user_id, created_at);
+
+create table notification_deliveries
+(
+    id               varchar not null primary key,
+    notification_id  varchar not null,
+    channel          varchar not null,
+    attempts         integer not null default 0,
+    next_attempt_at  datetime not null,
+    delivered_at     datetime,
+    last_error       varchar,
+    unique (notification_id, channel)
+);
+
+create index notification_deliveries_pending on notification_deliveries (delivered_at, next_attempt_at);
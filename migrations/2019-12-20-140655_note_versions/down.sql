@@ -0,0 +1,2 @@
+alter table notes drop column updated_at;
+alter table notes drop column version;
@@ -0,0 +1,9 @@
+alter table notes add column version integer not null default 1;
+alter table notes add column updated_at datetime not null default '1970-01-01 00:00:00';
+
+update notes
+set version    = max(1, (select count(*) from note_revisions r where r.note_id = notes.id)),
+    updated_at = coalesce(
+        (select max(r.created_at) from note_revisions r where r.note_id = notes.id),
+        datetime('now')
+    );
@@ -0,0 +1,2 @@
+drop table note_comments;
+drop table note_shares;
@@ -0,0 +1,27 @@
+create table note_shares
+(
+    id             varchar not null primary key,
+    note_id        varchar not null,
+    user_id        varchar not null,
+    token_hash     varchar not null unique,
+    permission     varchar not null default 'read',
+    password_hash  varchar,
+    expires_at     datetime,
+    views          integer not null default 0,
+    created_at     datetime not null,
+    revoked_at     datetime
+);
+
+create index note_shares_note_id on note_shares (note_id);
+
+create table note_comments
+(
+    id           varchar not null primary key,
+    note_id      varchar not null,
+    share_id     varchar,
+    author_name  varchar not null,
+    body         varchar not null,
+    created_at   datetime not null
+);
+
+create index note_comments_note_id on note_comments (note_id, created_at);
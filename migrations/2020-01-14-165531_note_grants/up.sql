@@ -0,0 +1,12 @@
+create table note_grants
+(
+    id          varchar not null primary key,
+    note_id     varchar not null,
+    user_id     varchar not null,
+    role        varchar not null,
+    granted_by  varchar not null,
+    created_at  datetime not null,
+    unique (note_id, user_id)
+);
+
+create index note_grants_user_id on note_grants (user_id);
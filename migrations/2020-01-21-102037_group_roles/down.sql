@@ -0,0 +1 @@
+alter table group_links drop column role;
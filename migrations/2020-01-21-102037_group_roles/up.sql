@@ -0,0 +1,13 @@
+alter table group_links add column role varchar not null default 'member';
+
+-- creators own their groups
+update group_links
+set role = 'owner'
+where user_id = (select g.created_by from groups g where g.id = group_links.group_id);
+
+insert into group_links (id, user_id, group_id, role)
+select lower(hex(randomblob(4)) || '-' || hex(randomblob(2)) || '-4' || substr(hex(randomblob(2)), 2) || '-' ||
+             substr('89ab', 1 + (abs(random()) % 4), 1) || substr(hex(randomblob(2)), 2) || '-' || hex(randomblob(6))),
+       g.created_by, g.id, 'owner'
+from groups g
+where not exists (select 1 from group_links l where l.group_id = g.id and l.user_id = g.created_by);
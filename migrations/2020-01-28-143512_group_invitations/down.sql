@@ -0,0 +1,3 @@
+drop table group_join_requests;
+drop table group_invitations;
+alter table groups drop column join_policy;
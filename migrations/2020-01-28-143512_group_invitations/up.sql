@@ -0,0 +1,31 @@
+alter table groups add column join_policy varchar not null default 'open';
+
+create table group_invitations
+(
+    id          varchar not null primary key,
+    group_id    varchar not null,
+    email       varchar,
+    user_id     varchar,
+    role        varchar not null,
+    status      varchar not null default 'pending',
+    invited_by  varchar not null,
+    created_at  datetime not null,
+    expires_at  datetime not null
+);
+
+create index group_invitations_group_id on group_invitations (group_id);
+
+create table group_join_requests
+(
+    id           varchar not null primary key,
+    group_id     varchar not null,
+    user_id      varchar not null,
+    status       varchar not null default 'pending',
+    created_at   datetime not null,
+    resolved_at  datetime,
+    resolved_by  varchar
+);
+
+-- one open request per user and group
+create unique index group_join_requests_pending on group_join_requests (group_id, user_id)
+    where status = 'pending';
@@ -0,0 +1 @@
+drop table group_join_codes;
@@ -0,0 +1,14 @@
+create table group_join_codes
+(
+    id          varchar not null primary key,
+    group_id    varchar not null,
+    code        varchar not null unique,
+    created_by  varchar not null,
+    created_at  datetime not null,
+    expires_at  datetime not null,
+    max_uses    integer,
+    uses        integer not null default 0,
+    revoked_at  datetime
+);
+
+create index group_join_codes_group_id on group_join_codes (group_id);
@@ -0,0 +1,6 @@
+alter table group_links add column joined_at datetime not null default '1970-01-01 00:00:00';
+
+-- the real join date of existing members is unknown, the group's creation
+-- is the closest we have
+update group_links
+set joined_at = (select g.created_at from groups g where g.id = group_links.group_id);
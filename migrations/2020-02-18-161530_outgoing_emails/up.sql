@@ -0,0 +1,14 @@
+create table outgoing_emails
+(
+    id               varchar not null primary key,
+    recipient        varchar not null,
+    subject          varchar not null,
+    body             text not null,
+    created_at       datetime not null,
+    attempts         integer not null default 0,
+    next_attempt_at  datetime not null,
+    sent_at          datetime,
+    last_error       text
+);
+
+create index outgoing_emails_pending on outgoing_emails (sent_at, next_attempt_at);
@@ -0,0 +1,14 @@
+-- changes whenever the password does, which ends every existing session
+alter table users add column security_stamp varchar not null default '';
+update users set security_stamp = lower(hex(randomblob(16)));
+
+create table password_resets
+(
+    id          varchar not null primary key,
+    user_id     varchar not null,
+    token_hash  varchar not null unique,
+    expires_at  datetime not null,
+    resolved    integer not null default 0
+);
+
+create index password_resets_user_id on password_resets (user_id);
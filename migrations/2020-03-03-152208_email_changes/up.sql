@@ -0,0 +1,11 @@
+create table email_changes
+(
+    id          varchar not null primary key,
+    user_id     varchar not null,
+    new_email   varchar not null,
+    token_hash  varchar not null unique,
+    expires_at  datetime not null,
+    resolved    integer not null default 0
+);
+
+create index email_changes_user_id on email_changes (user_id);
@@ -0,0 +1,16 @@
+-- a family is the chain of tokens created by rotating one login; reusing a
+-- rotated token revokes the whole family
+create table refresh_tokens
+(
+    id             varchar not null primary key,
+    user_id        varchar not null,
+    family_id      varchar not null,
+    token_hash     varchar not null unique,
+    security_stamp varchar not null,
+    created_at     datetime not null default current_timestamp,
+    expires_at     datetime not null,
+    revoked_at     datetime
+);
+
+create index refresh_tokens_user_id on refresh_tokens (user_id);
+create index refresh_tokens_family_id on refresh_tokens (family_id);
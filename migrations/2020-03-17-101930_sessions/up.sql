@@ -0,0 +1,17 @@
+-- one row per login, from the browser (cookie) or a token client; the
+-- refresh tokens of a token session use its id as their family_id
+create table sessions
+(
+    id             varchar not null primary key,
+    user_id        varchar not null,
+    kind           varchar not null,
+    user_agent     varchar,
+    ip_address     varchar,
+    security_stamp varchar not null,
+    created_at     datetime not null default current_timestamp,
+    last_seen_at   datetime not null default current_timestamp,
+    expires_at     datetime not null,
+    revoked_at     datetime
+);
+
+create index sessions_user_id on sessions (user_id);
@@ -0,0 +1,18 @@
+-- scopes are stored space separated, e.g. 'notes:read notes:write'
+-- revoked_reason is only set when the token was revoked along with the
+-- user's sessions
+create table personal_access_tokens
+(
+    id           varchar not null primary key,
+    user_id      varchar not null,
+    name         varchar not null,
+    token_hash   varchar not null unique,
+    scopes       varchar not null,
+    created_at   datetime not null default current_timestamp,
+    expires_at   datetime,
+    last_used_at datetime,
+    revoked_at   datetime,
+    revoked_reason varchar
+);
+
+create index personal_access_tokens_user_id on personal_access_tokens (user_id);
@@ -0,0 +1,31 @@
+-- enabled_at stays null until the user confirmed a first code
+create table totp_credentials
+(
+    user_id        varchar not null primary key,
+    secret         varchar not null,
+    created_at     datetime not null default current_timestamp,
+    enabled_at     datetime,
+    last_used_step bigint
+);
+
+create table recovery_codes
+(
+    id        varchar not null primary key,
+    user_id   varchar not null,
+    code_hash varchar not null,
+    used_at   datetime
+);
+
+create index recovery_codes_user_id on recovery_codes (user_id);
+
+-- second step of a login; kind is the session kind it will start
+create table mfa_challenges
+(
+    id         varchar not null primary key,
+    user_id    varchar not null,
+    token_hash varchar not null unique,
+    kind       varchar not null,
+    attempts   integer not null default 0,
+    expires_at datetime not null,
+    resolved   integer not null default 0
+);
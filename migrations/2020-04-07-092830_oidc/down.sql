@@ -0,0 +1,2 @@
+drop table external_identities;
+drop table oidc_states;
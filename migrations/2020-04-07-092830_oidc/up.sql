@@ -0,0 +1,27 @@
+-- a login started at an identity provider, waiting for its callback;
+-- user_id is set when a signed in user links another provider
+create table oidc_states
+(
+    id            varchar not null primary key,
+    provider      varchar not null,
+    user_id       varchar,
+    state_hash    varchar not null unique,
+    nonce         varchar not null,
+    code_verifier varchar not null,
+    expires_at    datetime not null,
+    resolved      integer not null default 0
+);
+
+create table external_identities
+(
+    id            varchar not null primary key,
+    user_id       varchar not null,
+    provider      varchar not null,
+    subject       varchar not null,
+    email         varchar,
+    created_at    datetime not null default current_timestamp,
+    last_login_at datetime not null default current_timestamp,
+    unique (provider, subject)
+);
+
+create index external_identities_user_id on external_identities (user_id);
@@ -0,0 +1,16 @@
+-- token buckets, keyed by route group and client or account
+create table rate_limit_buckets
+(
+    id          varchar not null primary key,
+    tokens      double not null,
+    refilled_at datetime not null
+);
+
+-- failed credential checks per account, for the progressive lockout
+create table login_failures
+(
+    id              varchar not null primary key,
+    failures        integer not null,
+    last_failure_at datetime not null,
+    locked_until    datetime
+);
@@ -0,0 +1,41 @@
+-- notes has a text primary key, so its rowid can change on VACUUM and the
+-- full-text index would point at the wrong notes. It gets a key of its own,
+-- which only the triggers below maintain.
+alter table notes add column search_id integer;
+update notes set search_id = rowid;
+create unique index notes_search_id on notes (search_id);
+
+drop trigger notes_fts_update;
+drop trigger notes_fts_delete;
+drop trigger notes_fts_insert;
+drop table notes_fts;
+
+create virtual table notes_fts using fts5
+(
+    title,
+    body,
+    content = 'notes',
+    content_rowid = 'search_id',
+    tokenize = 'unicode61 remove_diacritics 2'
+);
+
+insert into notes_fts(notes_fts) values ('rebuild');
+
+create trigger notes_fts_insert after insert on notes
+begin
+    update notes set search_id = (select ifnull(max(search_id), 0) + 1 from notes)
+    where id = new.id and search_id is null;
+    insert into notes_fts(rowid, title, body)
+    select search_id, title, body from notes where id = new.id;
+end;
+
+create trigger notes_fts_delete after delete on notes
+begin
+    insert into notes_fts(notes_fts, rowid, title, body) values ('delete', old.search_id, old.title, old.body);
+end;
+
+create trigger notes_fts_update after update of title, body on notes
+begin
+    insert into notes_fts(notes_fts, rowid, title, body) values ('delete', old.search_id, old.title, old.body);
+    insert into notes_fts(rowid, title, body) values (new.search_id, new.title, new.body);
+end;
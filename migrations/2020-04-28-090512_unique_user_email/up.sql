@@ -0,0 +1,2 @@
+-- invitations and logins find accounts by email, so one address is one account
+create unique index users_email on users (email);
@@ -0,0 +1,140 @@
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "op", content = "line", rename_all = "lowercase")]
+pub enum DiffLine {
+    Equal(String),
+    Insert(String),
+    Delete(String),
+}
+
+/// Size of the LCS table past which `line_diff` gives up, so a diff of two
+/// huge revisions can't take all memory. 4 bytes a cell.
+const MAX_TABLE_CELLS: usize = 4_000_000;
+
+/// Line based diff of `old` against `new` using the longest common subsequence
+/// of the two inputs. Deletions are emitted before insertions within a change.
+/// Lines both share at the start and the end don't count towards the limit;
+/// `None` means the lines in between are too many to compare.
+pub fn line_diff(old: &str, new: &str) -> Option<Vec<DiffLine>> {
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+
+    let prefix = old.iter().zip(&new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let (old_mid, new_mid) = (&old[prefix..old.len() - suffix], &new[prefix..new.len() - suffix]);
+    let (rows, cols) = (old_mid.len() + 1, new_mid.len() + 1);
+    if rows.saturating_mul(cols) > MAX_TABLE_CELLS {
+        return None;
+    }
+
+    // lcs[i * cols + j] holds the LCS length of old_mid[i..] and new_mid[j..]
+    let mut lcs = vec![0u32; rows * cols];
+    for i in (0..old_mid.len()).rev() {
+        for j in (0..new_mid.len()).rev() {
+            lcs[i * cols + j] = if old_mid[i] == new_mid[j] {
+                lcs[(i + 1) * cols + j + 1] + 1
+            } else {
+                lcs[(i + 1) * cols + j].max(lcs[i * cols + j + 1])
+            };
+        }
+    }
+
+    let mut out = Vec::with_capacity(old.len().max(new.len()));
+    out.extend(old[..prefix].iter().map(|line| DiffLine::Equal(line.to_string())));
+    let (mut i, mut j) = (0, 0);
+    while i < old_mid.len() && j < new_mid.len() {
+        if old_mid[i] == new_mid[j] {
+            out.push(DiffLine::Equal(old_mid[i].to_string()));
+            i += 1;
+            j += 1;
+        } else if lcs[(i + 1) * cols + j] >= lcs[i * cols + j + 1] {
+            out.push(DiffLine::Delete(old_mid[i].to_string()));
+            i += 1;
+        } else {
+            out.push(DiffLine::Insert(new_mid[j].to_string()));
+            j += 1;
+        }
+    }
+    out.extend(old_mid[i..].iter().map(|line| DiffLine::Delete(line.to_string())));
+    out.extend(new_mid[j..].iter().map(|line| DiffLine::Insert(line.to_string())));
+    out.extend(old[old.len() - suffix..].iter().map(|line| DiffLine::Equal(line.to_string())));
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use DiffLine::*;
+
+    fn diff(old: &str, new: &str) -> Vec<DiffLine> {
+        line_diff(old, new).expect("small inputs are always compared")
+    }
+
+    fn eq(line: &str) -> DiffLine {
+        Equal(line.to_string())
+    }
+
+    fn ins(line: &str) -> DiffLine {
+        Insert(line.to_string())
+    }
+
+    fn del(line: &str) -> DiffLine {
+        Delete(line.to_string())
+    }
+
+    #[test]
+    fn identical_texts_are_all_equal() {
+        assert_eq!(diff("a\nb\nc", "a\nb\nc"), vec![eq("a"), eq("b"), eq("c")]);
+        assert_eq!(diff("", ""), vec![]);
+    }
+
+    #[test]
+    fn from_and_to_empty() {
+        assert_eq!(diff("", "a\nb"), vec![ins("a"), ins("b")]);
+        assert_eq!(diff("a\nb", ""), vec![del("a"), del("b")]);
+    }
+
+    #[test]
+    fn insertion_and_deletion_in_the_middle() {
+        assert_eq!(diff("a\nc", "a\nb\nc"), vec![eq("a"), ins("b"), eq("c")]);
+        assert_eq!(diff("a\nb\nc", "a\nc"), vec![eq("a"), del("b"), eq("c")]);
+    }
+
+    #[test]
+    fn replaced_lines_delete_before_insert() {
+        assert_eq!(
+            diff("a\nb\nc\nd", "a\nx\ny\nd"),
+            vec![eq("a"), del("b"), del("c"), ins("x"), ins("y"), eq("d")]
+        );
+    }
+
+    #[test]
+    fn keeps_the_longest_common_subsequence() {
+        assert_eq!(
+            diff("a\nb\nc\nd\ne", "b\nx\nd\ne\nf"),
+            vec![del("a"), eq("b"), del("c"), ins("x"), eq("d"), eq("e"), ins("f")]
+        );
+    }
+
+    #[test]
+    fn shared_ends_do_not_count_towards_the_limit() {
+        let common: String = (0..5000).map(|n| format!("line {}\n", n)).collect();
+        let old = format!("{}old\n{}", common, common);
+        let new = format!("{}new\n{}", common, common);
+        let lines = diff(&old, &new);
+        assert_eq!(lines.len(), 10_002);
+        assert_eq!(lines[5000], del("old"));
+        assert_eq!(lines[5001], ins("new"));
+    }
+
+    #[test]
+    fn refuses_too_many_changed_lines() {
+        let old: String = (0..3000).map(|n| format!("old {}\n", n)).collect();
+        let new: String = (0..3000).map(|n| format!("new {}\n", n)).collect();
+        assert_eq!(line_diff(&old, &new), None);
+    }
+}
@@ -0,0 +1,273 @@
+use actix::{Actor, AsyncContext, Context};
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::prelude::*;
+use diesel::r2d2::ConnectionManager;
+use lettre::smtp::authentication::Credentials;
+use lettre::{ClientSecurity, ClientTlsParameters, SendableEmail, SmtpClient, Transport};
+use lettre_email::EmailBuilder;
+use native_tls::TlsConnector;
+use r2d2::Pool;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use uuid::Uuid;
+
+use crate::errors::ServiceError;
+use crate::models::OutgoingEmail;
+
+type SqlPool = Pool<ConnectionManager<SqliteConnection>>;
+
+/// Emails are given up on after this many failed attempts.
+const MAX_ATTEMPTS: i32 = 8;
+/// Upper bound of emails sent per tick.
+const BATCH_SIZE: i64 = 50;
+
+/// The account emails we send. Each renders to a subject and a plain text
+/// body.
+pub enum Template<'a> {
+    ConfirmRegistration { name: &'a str, link: String },
+    GroupInvitation { group: &'a str, inviter: &'a str, link: String },
+    PasswordReset { name: &'a str, link: String },
+    ConfirmEmailChange { name: &'a str, link: String },
+    EmailChanged { name: &'a str, new_email: &'a str },
+}
+
+impl<'a> Template<'a> {
+    pub fn render(&self) -> (String, String) {
+        match self {
+            Template::ConfirmRegistration { name, link } => (
+                String::from("Confirm your registration"),
+                format!(
+                    "Hi {},\n\nplease confirm your registration by opening the link below \
+                     within 24 hours:\n\n{}\n\nIf you did not sign up, you can ignore this email.\n",
+                    name, link
+                ),
+            ),
+            Template::GroupInvitation { group, inviter, link } => (
+                format!("{} invited you to {}", inviter, group),
+                format!(
+                    "Hi,\n\n{} invited you to join the group \"{}\". You can accept or decline \
+                     the invitation here:\n\n{}\n\nThe invitation expires in 7 days.\n",
+                    inviter, group, link
+                ),
+            ),
+            Template::PasswordReset { name, link } => (
+                String::from("Reset your password"),
+                format!(
+                    "Hi {},\n\nsomeone asked to reset the password of your account. You can \
+                     choose a new one within the next hour here:\n\n{}\n\nIf this wasn't you, \
+                     you can ignore this email and your password stays the same.\n",
+                    name, link
+                ),
+            ),
+            Template::ConfirmEmailChange { name, link } => (
+                String::from("Confirm your new email address"),
+                format!(
+                    "Hi {},\n\nplease confirm that this is your new email address by opening \
+                     the link below within 24 hours:\n\n{}\n\nUntil then you keep using your \
+                     old address.\n",
+                    name, link
+                ),
+            ),
+            Template::EmailChanged { name, new_email } => (
+                String::from("Your email address was changed"),
+                format!(
+                    "Hi {},\n\nyour account now uses {} as its email address. If you did not \
+                     make this change, reset your password right away.\n",
+                    name, new_email
+                ),
+            ),
+        }
+    }
+}
+
+/// Builds an absolute link into the frontend.
+pub fn frontend_link(path: &str) -> String {
+    let frontend_address = env::var("FRONTEND_ADDRESS").unwrap_or_default();
+    format!("{}{}", frontend_address.trim_end_matches('/'), path)
+}
+
+/// Puts an email in the outbox. Call it inside the transaction that causes
+/// the email so that neither happens without the other.
+pub fn queue(conn: &SqliteConnection, to: &str, template: Template) -> Result<(), ServiceError> {
+    use crate::schema::outgoing_emails::dsl::outgoing_emails;
+    let (subject, body) = template.render();
+    diesel::insert_into(outgoing_emails)
+        .values(&OutgoingEmail::from(to, subject, body))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Something that can hand an email over for delivery.
+pub trait Mailer: Send {
+    fn send(&self, email: &OutgoingEmail) -> Result<(), String>;
+}
+
+fn build_message(from: &str, email: &OutgoingEmail) -> Result<SendableEmail, String> {
+    EmailBuilder::new()
+        .to(email.recipient.as_str())
+        .from(from)
+        .subject(email.subject.as_str())
+        .text(email.body.as_str())
+        .build()
+        .map(Into::into)
+        .map_err(|err| err.to_string())
+}
+
+/// Sends mail through an SMTP relay. `tls` connects with implicit TLS, as on
+/// the submissions port; without it the connection is unencrypted, which is
+/// only meant for relays on the local network.
+pub struct SmtpMailer {
+    pub from: String,
+    pub host: String,
+    pub port: u16,
+    pub tls: bool,
+    pub credentials: Option<(String, String)>,
+}
+
+impl Mailer for SmtpMailer {
+    fn send(&self, email: &OutgoingEmail) -> Result<(), String> {
+        let security = if self.tls {
+            let connector = TlsConnector::new().map_err(|err| err.to_string())?;
+            ClientSecurity::Wrapper(ClientTlsParameters::new(self.host.clone(), connector))
+        } else {
+            ClientSecurity::None
+        };
+        let client = SmtpClient::new((self.host.as_str(), self.port), security)
+            .map_err(|err| err.to_string())?;
+        let client = match &self.credentials {
+            Some((username, password)) => {
+                client.credentials(Credentials::new(username.clone(), password.clone()))
+            }
+            None => client,
+        };
+        client
+            .transport()
+            .send(build_message(&self.from, email)?)
+            .map(|_| ())
+            .map_err(|err| err.to_string())
+    }
+}
+
+/// Writes every email into a maildir, so development setups and tests can
+/// read what would have been sent.
+pub struct MaildirMailer {
+    pub from: String,
+    pub dir: PathBuf,
+}
+
+impl Mailer for MaildirMailer {
+    fn send(&self, email: &OutgoingEmail) -> Result<(), String> {
+        let message = build_message(&self.from, email)?
+            .message_to_string()
+            .map_err(|err| err.to_string())?;
+        let name = format!("{}.{}.eml", Utc::now().timestamp(), Uuid::new_v4().to_simple());
+        for sub in &["tmp", "new", "cur"] {
+            fs::create_dir_all(self.dir.join(sub)).map_err(|err| err.to_string())?;
+        }
+        // written to tmp first so readers never see half a message
+        let staged = self.dir.join("tmp").join(&name);
+        fs::write(&staged, message).map_err(|err| err.to_string())?;
+        fs::rename(&staged, self.dir.join("new").join(&name)).map_err(|err| err.to_string())
+    }
+}
+
+/// Builds the mailer selected by the `MAILER` setting, `maildir` (the
+/// default) or `smtp`, from the `MAIL_*` and `SMTP_*` settings.
+pub fn mailer_from_env() -> Box<dyn Mailer> {
+    let from = env::var("MAIL_FROM").unwrap_or_else(|_| "noreply@localhost".into());
+    match env::var("MAILER").unwrap_or_else(|_| "maildir".into()).as_str() {
+        "maildir" => Box::new(MaildirMailer {
+            from,
+            dir: PathBuf::from(env::var("MAIL_DIR").unwrap_or_else(|_| "mail".into())),
+        }),
+        "smtp" => {
+            let tls = env::var("SMTP_TLS").map(|tls| tls != "off").unwrap_or(true);
+            Box::new(SmtpMailer {
+                from,
+                host: env::var("SMTP_HOST").expect("SMTP_HOST is not set"),
+                port: env::var("SMTP_PORT")
+                    .ok()
+                    .map(|port| port.parse::<u16>().expect("SMTP_PORT must be a number"))
+                    .unwrap_or(if tls { 465 } else { 25 }),
+                tls,
+                credentials: match (env::var("SMTP_USERNAME"), env::var("SMTP_PASSWORD")) {
+                    (Ok(username), Ok(password)) => Some((username, password)),
+                    _ => None,
+                },
+            })
+        }
+        other => panic!("Unknown mailer '{}'", other),
+    }
+}
+
+/// Sends what is due in the outbox. An email is only marked sent after the
+/// mailer accepted it; failures are retried with exponential backoff.
+pub fn send_pending(
+    conn: &SqliteConnection,
+    mailer: &dyn Mailer,
+    now: NaiveDateTime,
+) -> Result<usize, ServiceError> {
+    use crate::schema::outgoing_emails::dsl::*;
+    let pending = outgoing_emails
+        .filter(sent_at.is_null())
+        .filter(next_attempt_at.le(now))
+        .filter(attempts.lt(MAX_ATTEMPTS))
+        .order(next_attempt_at.asc())
+        .limit(BATCH_SIZE)
+        .load::<OutgoingEmail>(conn)?;
+    let mut sent = 0;
+    for email in pending {
+        match mailer.send(&email) {
+            Ok(()) => {
+                diesel::update(&email)
+                    .set(sent_at.eq(Utc::now().naive_utc()))
+                    .execute(conn)?;
+                sent += 1;
+            }
+            Err(err) => {
+                println!("Sending email to {} failed: {}", email.recipient, err);
+                let backoff = Duration::minutes(1 << email.attempts.min(10));
+                diesel::update(&email)
+                    .set((
+                        attempts.eq(email.attempts + 1),
+                        next_attempt_at.eq(now + backoff),
+                        last_error.eq(err),
+                    ))
+                    .execute(conn)?;
+            }
+        }
+    }
+    Ok(sent)
+}
+
+/// Background actor that works through the email outbox.
+pub struct MailSender {
+    pub pool: SqlPool,
+    pub mailer: Box<dyn Mailer>,
+    pub interval: std::time::Duration,
+}
+
+impl MailSender {
+    fn tick(&self) {
+        let conn = match self.pool.get() {
+            Ok(conn) => conn,
+            Err(err) => {
+                println!("Mail run skipped, no database connection: {}", err);
+                return;
+            }
+        };
+        if let Err(err) = send_pending(&conn, self.mailer.as_ref(), Utc::now().naive_utc()) {
+            println!("Sending emails failed: {}", err);
+        }
+    }
+}
+
+impl Actor for MailSender {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.tick();
+        ctx.run_interval(self.interval, |sender, _| sender.tick());
+    }
+}
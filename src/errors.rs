@@ -1,4 +1,4 @@
-use actix_web::{error::ResponseError, HttpResponse};
+use actix_web::{dev::Body, error::ResponseError, http::header, HttpResponse};
 use derive_more::Display;
 use diesel::result::{DatabaseErrorKind, Error as DBError};
 use std::convert::From;
@@ -17,6 +17,15 @@ pub enum ServiceError {
 
     #[display(fmt = "Forbidden")]
     Forbidden,
+
+    /// The resource changed since the client last saw it; carries the
+    /// current server copy.
+    #[display(fmt = "Precondition Failed")]
+    PreconditionFailed(serde_json::Value),
+
+    /// Throttled; carries the seconds until the client may try again.
+    #[display(fmt = "Too Many Requests")]
+    TooManyRequests(u64),
 }
 
 impl ResponseError for ServiceError {
@@ -28,6 +37,29 @@ impl ResponseError for ServiceError {
             ServiceError::BadRequest(ref message) => HttpResponse::BadRequest().json(message),
             ServiceError::Unauthorized => HttpResponse::Unauthorized().json("Unauthorized"),
             ServiceError::Forbidden => HttpResponse::Forbidden().json("Forbidden"),
+            ServiceError::PreconditionFailed(ref current) => HttpResponse::PreconditionFailed()
+                .json(serde_json::json!({
+                    "message": "The resource was changed by someone else",
+                    "current": current,
+                })),
+            ServiceError::TooManyRequests(retry_after) => HttpResponse::TooManyRequests()
+                .header(header::RETRY_AFTER, retry_after.to_string())
+                .json("Too Many Requests"),
+        }
+    }
+
+    fn render_response(&self) -> HttpResponse {
+        match self {
+            // keep the JSON body, other errors are rendered as plain text
+            ServiceError::PreconditionFailed(_) => self.error_response(),
+            _ => {
+                let mut resp = self.error_response();
+                resp.headers_mut().insert(
+                    header::CONTENT_TYPE,
+                    header::HeaderValue::from_static("text/plain; charset=utf-8"),
+                );
+                resp.set_body(Body::from(self.to_string()))
+            }
         }
     }
 }
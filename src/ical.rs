@@ -0,0 +1,66 @@
+use chrono::Utc;
+
+use crate::models::Note;
+
+const DATE_TIME_FORMAT: &str = "%Y%m%dT%H%M%S";
+const MAX_LINE_OCTETS: usize = 75;
+
+/// Escapes a TEXT property value (RFC 5545, section 3.3.11).
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            ';' => escaped.push_str("\\;"),
+            ',' => escaped.push_str("\\,"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => {}
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Appends a content line, folding it so no physical line is longer than
+/// 75 octets. Continuation lines start with a single space.
+fn push_line(out: &mut String, line: &str) {
+    let mut octets = 0;
+    for c in line.chars() {
+        if octets + c.len_utf8() > MAX_LINE_OCTETS {
+            out.push_str("\r\n ");
+            octets = 1;
+        }
+        out.push(c);
+        octets += c.len_utf8();
+    }
+    out.push_str("\r\n");
+}
+
+/// Renders an iCalendar document with one event per dated note. Date tags
+/// carry no time zone, so events use floating local times.
+pub fn render(name: &str, notes: &[Note]) -> String {
+    let stamp = Utc::now().format(DATE_TIME_FORMAT);
+    let mut out = String::new();
+    push_line(&mut out, "BEGIN:VCALENDAR");
+    push_line(&mut out, "VERSION:2.0");
+    push_line(&mut out, "PRODID:-//DC//Notes//EN");
+    push_line(&mut out, "CALSCALE:GREGORIAN");
+    push_line(&mut out, &format!("X-WR-CALNAME:{}", escape(name)));
+    for note in notes {
+        let start = match note.date_tag {
+            Some(date_tag) => date_tag,
+            None => continue,
+        };
+        push_line(&mut out, "BEGIN:VEVENT");
+        push_line(&mut out, &format!("UID:{}@dc-notes", note.id));
+        push_line(&mut out, &format!("DTSTAMP:{}Z", stamp));
+        push_line(&mut out, &format!("DTSTART:{}", start.format(DATE_TIME_FORMAT)));
+        push_line(&mut out, &format!("SUMMARY:{}", escape(&note.title)));
+        if !note.body.is_empty() {
+            push_line(&mut out, &format!("DESCRIPTION:{}", escape(&note.body)));
+        }
+        push_line(&mut out, "END:VEVENT");
+    }
+    push_line(&mut out, "END:VCALENDAR");
+    out
+}
@@ -3,20 +3,39 @@ extern crate diesel;
 #[macro_use]
 extern crate serde_derive;
 
+use actix::Actor;
 use actix_cors::Cors;
 use actix_identity::{CookieIdentityPolicy, IdentityService};
 use actix_web::{http::header, middleware, web, App, HttpServer};
 use diesel::prelude::*;
 use diesel::r2d2::{self, ConnectionManager};
 use std::env;
+use std::sync::Arc;
 
-use crate::routes::auth;
+use crate::routes::{auth, shares};
+use email_service::{mailer_from_env, MailSender};
 use routes::get_api;
+use notify::{channels_from_config, ReminderScheduler};
+use realtime::Hub;
+use storage::{LocalStore, Storage};
+use trash::TrashPurger;
 
+mod diff;
+mod email_service;
 mod errors;
+mod ical;
 mod models;
+mod notify;
+mod oidc;
+mod policy;
+mod ratelimit;
+mod realtime;
 mod routes;
 mod schema;
+mod storage;
+mod tokens;
+mod totp;
+mod trash;
 
 fn main() {
     dotenv::dotenv().ok();
@@ -29,15 +48,56 @@ fn main() {
     let bind_address = env::var("BIND_ADDRESS").expect("BIND_ADDRESS is not set");
     let fronend_address = env::var("FRONTEND_ADDRESS").expect("FRONTEND_ADDRESS is not set");
     let database_url = env::var("DATABASE_URL").expect("DATABASE_URL is not set");
+    let attachments_dir = env::var("ATTACHMENTS_DIR").unwrap_or_else(|_| "attachments".into());
+    let trash_retention_days = env::var("TRASH_RETENTION_DAYS")
+        .ok()
+        .map(|days| days.parse::<i64>().expect("TRASH_RETENTION_DAYS must be a number"))
+        .unwrap_or(30);
+    let notify_channels = env::var("NOTIFY_CHANNELS").unwrap_or_default();
 
     let manager = ConnectionManager::<SqliteConnection>::new(database_url);
     let pool = r2d2::Pool::builder()
         .build(manager)
         .expect("Failed to create pool.");
+    let storage: Storage = Arc::new(
+        LocalStore::new(&attachments_dir).expect("Failed to open attachment storage."),
+    );
+
+    TrashPurger {
+        pool: pool.clone(),
+        storage: storage.clone(),
+        retention: chrono::Duration::days(trash_retention_days),
+        interval: std::time::Duration::from_secs(60 * 60),
+    }
+    .start();
+
+    ReminderScheduler {
+        pool: pool.clone(),
+        channels: channels_from_config(&notify_channels),
+        interval: std::time::Duration::from_secs(30),
+    }
+    .start();
+
+    // sending blocks on the relay, so it gets a thread of its own
+    let mail_pool = pool.clone();
+    let mailer = mailer_from_env();
+    MailSender::start_in_arbiter(&actix::Arbiter::new(), move |_| MailSender {
+        pool: mail_pool,
+        mailer,
+        interval: std::time::Duration::from_secs(10),
+    });
+
+    let hub = Hub::default().start();
+    let oidc_providers = oidc::providers_from_env();
+    let limiter = ratelimit::limiter_from_env(pool.clone());
 
     HttpServer::new(move || {
         App::new()
             .data(pool.clone())
+            .data(storage.clone())
+            .data(hub.clone())
+            .data(oidc_providers.clone())
+            .data(limiter.clone())
             .data(web::PayloadConfig::new(1 << 25))
             .data(web::JsonConfig::default().limit(1024 * 1024 * 50))
             .wrap(
@@ -49,7 +109,10 @@ fn main() {
                         header::ACCEPT,
                         header::CONTENT_TYPE,
                         header::ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                        header::IF_MATCH,
+                        header::HeaderName::from_static(shares::SHARE_PASSWORD_HEADER),
                     ])
+                    .expose_headers(vec![header::ETAG, header::RETRY_AFTER])
                     .supports_credentials()
                     .max_age(3600),
             )
@@ -58,7 +121,7 @@ fn main() {
                 CookieIdentityPolicy::new(auth::SECRET_KEY.as_bytes())
                     .name("auth")
                     .path("/")
-                    .max_age_time(chrono::Duration::days(1))
+                    .max_age_time(chrono::Duration::hours(auth::COOKIE_SESSION_HOURS))
                     .secure(false), // https
             ))
             .service(get_api())
@@ -1,6 +1,10 @@
+use crate::policy::Scope;
 use crate::routes::auth::hash_password;
-use crate::schema::{invitations, notes, users, groups, group_links};
-use chrono::{NaiveDateTime, Utc};
+use crate::schema::{
+    attachments, calendar_tokens, email_changes, external_identities, group_invitations, group_join_codes, group_join_requests, group_links, groups, invitations, login_failures, mfa_challenges, note_comments, note_grants,
+    note_revisions, note_shares, note_tags, notes, notification_deliveries, notifications, oidc_states, outgoing_emails, password_resets, personal_access_tokens, rate_limit_buckets, recovery_codes, refresh_tokens, reminders, sessions, tags, totp_credentials, users,
+};
+use chrono::{NaiveDate, NaiveDateTime, Utc};
 use uuid::Uuid;
 
 #[derive(
@@ -12,6 +16,8 @@ pub struct User {
     pub email: String,
     pub password: String,
     pub active: i32,
+    #[serde(skip)]
+    pub security_stamp: String,
 }
 
 #[derive(Clone, Debug, Serialize)]
@@ -29,7 +35,7 @@ pub struct NewUser {
     pub password: String,
 }
 
-#[derive(Debug, Serialize, Deserialize, Identifiable)]
+#[derive(Clone, Debug, Serialize, Deserialize, Identifiable)]
 #[table_name = "users"]
 pub struct LoggedUser {
     pub id: String,
@@ -57,6 +63,10 @@ impl LoggedUser {
     }
 }
 
+pub fn new_security_stamp() -> String {
+    Uuid::new_v4().to_simple().to_string()
+}
+
 impl User {
     pub fn from(user: NewUser) -> Self {
         User {
@@ -65,6 +75,356 @@ impl User {
             email: user.email,
             password: hash_password(&user.password).unwrap(),
             active: 0,
+            security_stamp: new_security_stamp(),
+        }
+    }
+
+    /// An account created on the first sign in through an identity
+    /// provider. It has no password until the user sets one right after
+    /// signing in, or through a password reset.
+    pub fn provisioned(name: String, email: String) -> Self {
+        User {
+            id: Uuid::new_v4().to_string(),
+            name,
+            email,
+            password: String::new(),
+            active: 1,
+            security_stamp: new_security_stamp(),
+        }
+    }
+}
+
+/// Single-use token for setting a new password. Only the hash of the token
+/// is stored.
+#[derive(Clone, Debug, Insertable, Queryable, Identifiable)]
+pub struct PasswordReset {
+    pub id: String,
+    pub user_id: String,
+    pub token_hash: String,
+    pub expires_at: NaiveDateTime,
+    pub resolved: i32,
+}
+
+impl PasswordReset {
+    pub fn from(user: &User, token_hash: String) -> Self {
+        PasswordReset {
+            id: Uuid::new_v4().to_string(),
+            user_id: user.id.clone(),
+            token_hash,
+            expires_at: Utc::now().naive_utc() + chrono::Duration::hours(1),
+            resolved: 0,
+        }
+    }
+}
+
+/// Pending switch to a new email address, confirmed with a token sent to
+/// that address.
+#[derive(Clone, Debug, Insertable, Queryable, Identifiable)]
+pub struct EmailChange {
+    pub id: String,
+    pub user_id: String,
+    pub new_email: String,
+    pub token_hash: String,
+    pub expires_at: NaiveDateTime,
+    pub resolved: i32,
+}
+
+impl EmailChange {
+    pub fn from(user: &User, new_email: String, token_hash: String) -> Self {
+        EmailChange {
+            id: Uuid::new_v4().to_string(),
+            user_id: user.id.clone(),
+            new_email,
+            token_hash,
+            expires_at: Utc::now().naive_utc() + chrono::Duration::hours(24),
+            resolved: 0,
+        }
+    }
+}
+
+/// Long-lived token traded for a new access token. Every use replaces it
+/// with a fresh one from the same family; only the hash is stored.
+#[derive(Clone, Debug, Insertable, Queryable, Identifiable)]
+pub struct RefreshToken {
+    pub id: String,
+    pub user_id: String,
+    pub family_id: String,
+    pub token_hash: String,
+    pub security_stamp: String,
+    pub created_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+    pub revoked_at: Option<NaiveDateTime>,
+}
+
+impl RefreshToken {
+    pub fn from(user: &User, family_id: String, token_hash: String) -> Self {
+        let now = Utc::now().naive_utc();
+        RefreshToken {
+            id: Uuid::new_v4().to_string(),
+            user_id: user.id.clone(),
+            family_id,
+            token_hash,
+            security_stamp: user.security_stamp.clone(),
+            created_at: now,
+            expires_at: now + chrono::Duration::days(30),
+            revoked_at: None,
+        }
+    }
+}
+
+/// A login on one device. `kind` is `cookie` for the browser and `token`
+/// for clients using bearer and refresh tokens.
+#[derive(Clone, Debug, Serialize, Insertable, Queryable, Identifiable)]
+pub struct Session {
+    pub id: String,
+    #[serde(skip)]
+    pub user_id: String,
+    pub kind: String,
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+    #[serde(skip)]
+    pub security_stamp: String,
+    pub created_at: NaiveDateTime,
+    pub last_seen_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+    #[serde(skip)]
+    pub revoked_at: Option<NaiveDateTime>,
+}
+
+impl Session {
+    pub fn from(
+        user: &User,
+        kind: &str,
+        user_agent: Option<String>,
+        ip_address: Option<String>,
+        lifetime: chrono::Duration,
+    ) -> Self {
+        let now = Utc::now().naive_utc();
+        Session {
+            id: Uuid::new_v4().to_string(),
+            user_id: user.id.clone(),
+            kind: kind.to_string(),
+            user_agent,
+            ip_address,
+            security_stamp: user.security_stamp.clone(),
+            created_at: now,
+            last_seen_at: now,
+            expires_at: now + lifetime,
+            revoked_at: None,
+        }
+    }
+
+    pub fn is_live(&self, now: NaiveDateTime) -> bool {
+        self.revoked_at.is_none() && self.expires_at > now
+    }
+}
+
+/// A session as listed to its owner.
+#[derive(Clone, Debug, Serialize)]
+pub struct ListedSession {
+    #[serde(flatten)]
+    pub session: Session,
+    pub current: bool,
+}
+
+/// Token for scripts, limited to the scopes it was created with. Only the
+/// hash of the token is stored.
+#[derive(Clone, Debug, Serialize, Insertable, Queryable, Identifiable)]
+pub struct PersonalAccessToken {
+    pub id: String,
+    #[serde(skip)]
+    pub user_id: String,
+    pub name: String,
+    #[serde(skip)]
+    pub token_hash: String,
+    pub scopes: String,
+    pub created_at: NaiveDateTime,
+    pub expires_at: Option<NaiveDateTime>,
+    pub last_used_at: Option<NaiveDateTime>,
+    pub revoked_at: Option<NaiveDateTime>,
+    /// Set when the token ended with the user's sessions:
+    /// `password_changed` or `signed_out_everywhere`.
+    pub revoked_reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewAccessToken {
+    pub name: String,
+    pub scopes: Vec<String>,
+    pub expires_at: Option<String>,
+}
+
+impl PersonalAccessToken {
+    pub fn from(
+        user: &LoggedUser,
+        name: String,
+        scopes: &[Scope],
+        expires_at: Option<NaiveDateTime>,
+        token_hash: String,
+    ) -> Self {
+        let scopes: Vec<&str> = scopes.iter().map(|scope| scope.as_str()).collect();
+        PersonalAccessToken {
+            id: Uuid::new_v4().to_string(),
+            user_id: user.id.clone(),
+            name,
+            token_hash,
+            scopes: scopes.join(" "),
+            created_at: Utc::now().naive_utc(),
+            expires_at,
+            last_used_at: None,
+            revoked_at: None,
+            revoked_reason: None,
+        }
+    }
+
+    pub fn is_usable(&self, now: NaiveDateTime) -> bool {
+        self.revoked_at.is_none() && self.expires_at.map(|expiry| expiry > now).unwrap_or(true)
+    }
+
+    pub fn has_scope(&self, scope: Scope) -> bool {
+        self.scopes.split(' ').any(|granted| granted == scope.as_str())
+    }
+}
+
+/// A freshly created token. The secret is only ever shown in this response.
+#[derive(Debug, Serialize)]
+pub struct CreatedAccessToken {
+    #[serde(flatten)]
+    pub token: PersonalAccessToken,
+    pub secret: String,
+}
+
+/// Shared secret of a user's authenticator app.
+#[derive(Clone, Debug, Insertable, Queryable, Identifiable)]
+#[primary_key(user_id)]
+pub struct TotpCredential {
+    pub user_id: String,
+    pub secret: String,
+    pub created_at: NaiveDateTime,
+    pub enabled_at: Option<NaiveDateTime>,
+    pub last_used_step: Option<i64>,
+}
+
+impl TotpCredential {
+    pub fn from(user: &LoggedUser, secret: String) -> Self {
+        TotpCredential {
+            user_id: user.id.clone(),
+            secret,
+            created_at: Utc::now().naive_utc(),
+            enabled_at: None,
+            last_used_step: None,
+        }
+    }
+}
+
+/// One-time code that stands in for the authenticator app. Only its hash is
+/// stored.
+#[derive(Clone, Debug, Insertable, Queryable, Identifiable)]
+pub struct RecoveryCode {
+    pub id: String,
+    pub user_id: String,
+    pub code_hash: String,
+    pub used_at: Option<NaiveDateTime>,
+}
+
+impl RecoveryCode {
+    pub fn from(user: &LoggedUser, code_hash: String) -> Self {
+        RecoveryCode {
+            id: Uuid::new_v4().to_string(),
+            user_id: user.id.clone(),
+            code_hash,
+            used_at: None,
+        }
+    }
+}
+
+/// A login waiting for its second factor.
+#[derive(Clone, Debug, Insertable, Queryable, Identifiable)]
+pub struct MfaChallenge {
+    pub id: String,
+    pub user_id: String,
+    pub token_hash: String,
+    pub kind: String,
+    pub attempts: i32,
+    pub expires_at: NaiveDateTime,
+    pub resolved: i32,
+}
+
+impl MfaChallenge {
+    pub fn from(user: &User, kind: &str, token_hash: String) -> Self {
+        MfaChallenge {
+            id: Uuid::new_v4().to_string(),
+            user_id: user.id.clone(),
+            token_hash,
+            kind: kind.to_string(),
+            attempts: 0,
+            expires_at: Utc::now().naive_utc() + chrono::Duration::minutes(5),
+            resolved: 0,
+        }
+    }
+}
+
+/// A sign in started at an identity provider, or a signed in user linking
+/// one when `user_id` is set. Only the hash of the state parameter is
+/// stored.
+#[derive(Clone, Debug, Insertable, Queryable, Identifiable)]
+pub struct OidcState {
+    pub id: String,
+    pub provider: String,
+    pub user_id: Option<String>,
+    pub state_hash: String,
+    pub nonce: String,
+    pub code_verifier: String,
+    pub expires_at: NaiveDateTime,
+    pub resolved: i32,
+}
+
+impl OidcState {
+    pub fn from(
+        provider: &str,
+        user: Option<&LoggedUser>,
+        state_hash: String,
+        nonce: String,
+        code_verifier: String,
+    ) -> Self {
+        OidcState {
+            id: Uuid::new_v4().to_string(),
+            provider: provider.to_string(),
+            user_id: user.map(|user| user.id.clone()),
+            state_hash,
+            nonce,
+            code_verifier,
+            expires_at: Utc::now().naive_utc() + chrono::Duration::minutes(10),
+            resolved: 0,
+        }
+    }
+}
+
+/// Account of a user at an identity provider, known by its subject.
+#[derive(Clone, Debug, Serialize, Insertable, Queryable, Identifiable)]
+#[table_name = "external_identities"]
+pub struct ExternalIdentity {
+    pub id: String,
+    #[serde(skip)]
+    pub user_id: String,
+    pub provider: String,
+    pub subject: String,
+    pub email: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub last_login_at: NaiveDateTime,
+}
+
+impl ExternalIdentity {
+    pub fn from(user: &User, provider: &str, subject: String, email: Option<String>) -> Self {
+        let now = Utc::now().naive_utc();
+        ExternalIdentity {
+            id: Uuid::new_v4().to_string(),
+            user_id: user.id.clone(),
+            provider: provider.to_string(),
+            subject,
+            email,
+            created_at: now,
+            last_login_at: now,
         }
     }
 }
@@ -96,9 +456,12 @@ impl Invitation {
     }
 }
 
-#[derive(Clone, Debug, Serialize, Queryable, Associations, Insertable, Identifiable)]
+#[derive(
+    Clone, Debug, Serialize, Queryable, QueryableByName, Associations, Insertable, Identifiable,
+)]
 #[belongs_to(Group)]
 #[belongs_to(User)]
+#[table_name = "notes"]
 pub struct Note {
     pub id: String,
     pub group_id: Option<String>,
@@ -108,6 +471,9 @@ pub struct Note {
     pub body: String,
     pub public: i32,
     pub pinned: i32,
+    pub deleted_at: Option<NaiveDateTime>,
+    pub version: i32,
+    pub updated_at: NaiveDateTime,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -118,6 +484,21 @@ pub struct NewNote {
     pub body: String,
     pub public: i32,
     pub pinned: i32,
+    #[serde(default)]
+    pub tags: Option<Vec<String>>,
+}
+
+#[derive(Clone, Debug, Serialize, QueryableByName)]
+pub struct SearchHit {
+    #[diesel(embed)]
+    #[serde(flatten)]
+    pub note: Note,
+    #[sql_type = "diesel::sql_types::Text"]
+    pub title_snippet: String,
+    #[sql_type = "diesel::sql_types::Text"]
+    pub body_snippet: String,
+    #[sql_type = "diesel::sql_types::Double"]
+    pub rank: f64,
 }
 
 #[derive(Clone, Debug, Serialize)]
@@ -126,9 +507,30 @@ pub struct GroupedNotes {
     pub notes: Vec<Note>,
 }
 
+#[derive(Clone, Debug, Serialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub next_cursor: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct GroupNotesPage {
+    pub group: Group,
+    #[serde(flatten)]
+    pub page: Page<Note>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct NotePatch {
+    #[serde(flatten)]
+    pub changes: NoteChanges,
+    #[serde(default)]
+    pub tags: Option<Vec<String>>,
+}
+
 #[derive(Clone, Debug, AsChangeset, Deserialize)]
 #[table_name = "notes"]
-pub struct NotePatch {
+pub struct NoteChanges {
     pub user_id: Option<String>,
     pub group_id: Option<String>,
     pub title: Option<String>,
@@ -138,6 +540,18 @@ pub struct NotePatch {
     pub pinned: Option<i32>,
 }
 
+impl NoteChanges {
+    pub fn is_empty(&self) -> bool {
+        self.user_id.is_none()
+            && self.group_id.is_none()
+            && self.title.is_none()
+            && self.date_tag.is_none()
+            && self.body.is_none()
+            && self.public.is_none()
+            && self.pinned.is_none()
+    }
+}
+
 impl Note {
     pub fn from(note: NewNote, user: LoggedUser) -> Self {
         Note {
@@ -152,6 +566,140 @@ impl Note {
             body: note.body,
             public: note.public,
             pinned: note.pinned,
+            deleted_at: None,
+            version: 1,
+            updated_at: Utc::now().naive_utc(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Queryable, Associations, Insertable, Identifiable)]
+#[belongs_to(Note)]
+pub struct NoteRevision {
+    pub id: String,
+    pub note_id: String,
+    pub user_id: String,
+    pub created_at: NaiveDateTime,
+    pub group_id: Option<String>,
+    pub title: String,
+    pub date_tag: Option<NaiveDateTime>,
+    pub body: String,
+    pub public: i32,
+    pub pinned: i32,
+}
+
+#[derive(Clone, Debug, AsChangeset)]
+#[table_name = "notes"]
+#[changeset_options(treat_none_as_null = "true")]
+pub struct RevisionRestore {
+    pub title: String,
+    pub date_tag: Option<NaiveDateTime>,
+    pub body: String,
+    pub public: i32,
+    pub pinned: i32,
+}
+
+impl NoteRevision {
+    pub fn from(note: &Note, author: &LoggedUser) -> Self {
+        NoteRevision {
+            id: Uuid::new_v4().to_string(),
+            note_id: note.id.clone(),
+            user_id: author.id.clone(),
+            created_at: Utc::now().naive_utc(),
+            group_id: note.group_id.clone(),
+            title: note.title.clone(),
+            date_tag: note.date_tag,
+            body: note.body.clone(),
+            public: note.public,
+            pinned: note.pinned,
+        }
+    }
+
+    pub fn restore(&self) -> RevisionRestore {
+        RevisionRestore {
+            title: self.title.clone(),
+            date_tag: self.date_tag,
+            body: self.body.clone(),
+            public: self.public,
+            pinned: self.pinned,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, Insertable, Queryable, Identifiable)]
+pub struct Tag {
+    pub id: String,
+    pub user_id: String,
+    pub name: String,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct NewTag {
+    pub name: String,
+}
+
+impl Tag {
+    pub fn from(tag: NewTag, user: &LoggedUser) -> Self {
+        Tag {
+            id: Uuid::new_v4().to_string(),
+            user_id: user.id.clone(),
+            name: tag.name.trim().to_string(),
+            created_at: Utc::now().naive_utc(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Associations, Insertable, Queryable, Identifiable)]
+#[belongs_to(Note)]
+#[belongs_to(Tag)]
+pub struct NoteTag {
+    pub id: String,
+    pub note_id: String,
+    pub tag_id: String,
+}
+
+impl NoteTag {
+    pub fn from(note_id: &str, tag_id: &str) -> Self {
+        NoteTag {
+            id: Uuid::new_v4().to_string(),
+            note_id: note_id.to_string(),
+            tag_id: tag_id.to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Associations, Insertable, Queryable, Identifiable)]
+#[belongs_to(Note)]
+pub struct Attachment {
+    pub id: String,
+    pub note_id: String,
+    pub user_id: String,
+    pub filename: String,
+    pub mime_type: String,
+    pub size: i64,
+    pub sha256: String,
+    pub created_at: NaiveDateTime,
+}
+
+impl Attachment {
+    pub fn from(
+        note: &Note,
+        user: &LoggedUser,
+        filename: String,
+        mime_type: String,
+        size: i64,
+        sha256: String,
+    ) -> Self {
+        Attachment {
+            id: Uuid::new_v4().to_string(),
+            note_id: note.id.clone(),
+            user_id: user.id.clone(),
+            filename,
+            mime_type,
+            size,
+            sha256,
+            created_at: Utc::now().naive_utc(),
         }
     }
 }
@@ -163,6 +711,8 @@ pub struct Group {
     pub created_by: String,
     pub name: String,
     pub color: String,
+    pub deleted_at: Option<NaiveDateTime>,
+    pub join_policy: String,
 }
 
 
@@ -170,6 +720,16 @@ pub struct Group {
 pub struct NewGroup {
     pub color: String,
     pub name: String,
+    #[serde(default)]
+    pub join_policy: Option<String>,
+}
+
+#[derive(Clone, Debug, AsChangeset, Deserialize)]
+#[table_name = "groups"]
+pub struct GroupChanges {
+    pub color: Option<String>,
+    pub name: Option<String>,
+    pub join_policy: Option<String>,
 }
 
 impl Group {
@@ -182,10 +742,337 @@ impl Group {
             created_by: user.id,
             name: group.name,
             color: group.color,
+            deleted_at: None,
+            join_policy: group.join_policy.unwrap_or_else(|| String::from("open")),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct TrashContents {
+    pub notes: Vec<Note>,
+    pub groups: Vec<Group>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct CalendarDay {
+    pub date: NaiveDate,
+    pub notes: Vec<Note>,
+}
+
+#[derive(Clone, Debug, Serialize, Insertable, Queryable, Identifiable)]
+pub struct CalendarToken {
+    pub id: String,
+    pub user_id: String,
+    #[serde(skip)]
+    pub token_hash: String,
+    pub created_at: NaiveDateTime,
+    pub revoked_at: Option<NaiveDateTime>,
+}
+
+impl CalendarToken {
+    pub fn from(user: &LoggedUser, token_hash: String) -> Self {
+        CalendarToken {
+            id: Uuid::new_v4().to_string(),
+            user_id: user.id.clone(),
+            token_hash,
+            created_at: Utc::now().naive_utc(),
+            revoked_at: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Associations, Insertable, Queryable, Identifiable)]
+#[belongs_to(Note)]
+pub struct Reminder {
+    pub id: String,
+    pub note_id: String,
+    pub user_id: String,
+    pub offset_minutes: Option<i32>,
+    pub remind_at: Option<NaiveDateTime>,
+    pub fire_at: Option<NaiveDateTime>,
+    pub fired_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+}
+
+/// Either `offset_minutes` before the note's `date_tag` or an absolute
+/// `remind_at` time.
+#[derive(Debug, Deserialize)]
+pub struct NewReminder {
+    pub offset_minutes: Option<i32>,
+    pub remind_at: Option<String>,
+}
+
+impl Reminder {
+    pub fn from(
+        note: &Note,
+        user: &LoggedUser,
+        offset_minutes: Option<i32>,
+        remind_at: Option<NaiveDateTime>,
+    ) -> Self {
+        let mut reminder = Reminder {
+            id: Uuid::new_v4().to_string(),
+            note_id: note.id.clone(),
+            user_id: user.id.clone(),
+            offset_minutes,
+            remind_at,
+            fire_at: None,
+            fired_at: None,
+            created_at: Utc::now().naive_utc(),
+        };
+        reminder.fire_at = reminder.schedule(note);
+        reminder
+    }
+
+    /// When the reminder is due for `note`. Offset reminders on notes
+    /// without a date never fire.
+    pub fn schedule(&self, note: &Note) -> Option<NaiveDateTime> {
+        match (self.remind_at, self.offset_minutes) {
+            (Some(at), _) => Some(at),
+            (None, Some(offset)) => note
+                .date_tag
+                .map(|date| date - chrono::Duration::minutes(i64::from(offset))),
+            (None, None) => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Insertable, Queryable, Identifiable)]
+pub struct Notification {
+    pub id: String,
+    pub user_id: String,
+    pub note_id: Option<String>,
+    pub reminder_id: Option<String>,
+    pub title: String,
+    pub body: String,
+    pub created_at: NaiveDateTime,
+    pub read_at: Option<NaiveDateTime>,
+}
+
+impl Notification {
+    pub fn from_reminder(reminder: &Reminder, note: &Note) -> Self {
+        let body = match note.date_tag {
+            Some(date) => format!("\"{}\" is due at {}", note.title, date.format("%Y-%m-%d %H:%M")),
+            None => format!("Reminder for \"{}\"", note.title),
+        };
+        Notification {
+            id: Uuid::new_v4().to_string(),
+            user_id: reminder.user_id.clone(),
+            note_id: Some(note.id.clone()),
+            reminder_id: Some(reminder.id.clone()),
+            title: note.title.clone(),
+            body,
+            created_at: Utc::now().naive_utc(),
+            read_at: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Insertable, Queryable, Identifiable, Associations)]
+#[belongs_to(Notification)]
+#[table_name = "notification_deliveries"]
+pub struct NotificationDelivery {
+    pub id: String,
+    pub notification_id: String,
+    pub channel: String,
+    pub attempts: i32,
+    pub next_attempt_at: NaiveDateTime,
+    pub delivered_at: Option<NaiveDateTime>,
+    pub last_error: Option<String>,
+}
+
+impl NotificationDelivery {
+    pub fn from(notification: &Notification, channel: &str) -> Self {
+        NotificationDelivery {
+            id: Uuid::new_v4().to_string(),
+            notification_id: notification.id.clone(),
+            channel: channel.to_string(),
+            attempts: 0,
+            next_attempt_at: notification.created_at,
+            delivered_at: None,
+            last_error: None,
+        }
+    }
+}
+
+/// Email waiting in the outbox, or already sent.
+#[derive(Clone, Debug, Insertable, Queryable, Identifiable)]
+pub struct OutgoingEmail {
+    pub id: String,
+    pub recipient: String,
+    pub subject: String,
+    pub body: String,
+    pub created_at: NaiveDateTime,
+    pub attempts: i32,
+    pub next_attempt_at: NaiveDateTime,
+    pub sent_at: Option<NaiveDateTime>,
+    pub last_error: Option<String>,
+}
+
+impl OutgoingEmail {
+    pub fn from(recipient: &str, subject: String, body: String) -> Self {
+        let now = Utc::now().naive_utc();
+        OutgoingEmail {
+            id: Uuid::new_v4().to_string(),
+            recipient: recipient.to_string(),
+            subject,
+            body,
+            created_at: now,
+            attempts: 0,
+            next_attempt_at: now,
+            sent_at: None,
+            last_error: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Associations, Insertable, Queryable, Identifiable)]
+#[belongs_to(Note)]
+pub struct NoteGrant {
+    pub id: String,
+    pub note_id: String,
+    pub user_id: String,
+    pub role: String,
+    pub granted_by: String,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewGrant {
+    pub email: String,
+    pub role: String,
+}
+
+impl NoteGrant {
+    pub fn from(note: &Note, grantee: &User, role: String, owner: &LoggedUser) -> Self {
+        NoteGrant {
+            id: Uuid::new_v4().to_string(),
+            note_id: note.id.clone(),
+            user_id: grantee.id.clone(),
+            role,
+            granted_by: owner.id.clone(),
+            created_at: Utc::now().naive_utc(),
+        }
+    }
+}
+
+/// A grant together with the user it was given to.
+#[derive(Clone, Debug, Serialize)]
+pub struct NoteAccess {
+    #[serde(flatten)]
+    pub grant: NoteGrant,
+    pub user: PublicUser,
+}
+
+fn is_set<S: serde::Serializer>(value: &Option<String>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_bool(value.is_some())
+}
+
+#[derive(Clone, Debug, Serialize, Associations, Insertable, Queryable, Identifiable)]
+#[belongs_to(Note)]
+pub struct NoteShare {
+    pub id: String,
+    pub note_id: String,
+    pub user_id: String,
+    #[serde(skip)]
+    pub token_hash: String,
+    pub permission: String,
+    #[serde(rename = "password_protected", serialize_with = "is_set")]
+    pub password_hash: Option<String>,
+    pub expires_at: Option<NaiveDateTime>,
+    pub views: i32,
+    pub created_at: NaiveDateTime,
+    pub revoked_at: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewShare {
+    pub permission: Option<String>,
+    pub expires_at: Option<String>,
+    pub password: Option<String>,
+}
+
+impl NoteShare {
+    pub fn from(
+        note: &Note,
+        user: &LoggedUser,
+        token_hash: String,
+        permission: String,
+        password_hash: Option<String>,
+        expires_at: Option<NaiveDateTime>,
+    ) -> Self {
+        NoteShare {
+            id: Uuid::new_v4().to_string(),
+            note_id: note.id.clone(),
+            user_id: user.id.clone(),
+            token_hash,
+            permission,
+            password_hash,
+            expires_at,
+            views: 0,
+            created_at: Utc::now().naive_utc(),
+            revoked_at: None,
+        }
+    }
+}
+
+/// A freshly created share link. The secret is only ever shown here.
+#[derive(Clone, Debug, Serialize)]
+pub struct CreatedShare {
+    #[serde(flatten)]
+    pub share: NoteShare,
+    pub secret: String,
+    pub url: String,
+}
+
+#[derive(Clone, Debug, Serialize, Associations, Insertable, Queryable, Identifiable)]
+#[belongs_to(Note)]
+pub struct NoteComment {
+    pub id: String,
+    pub note_id: String,
+    pub share_id: Option<String>,
+    pub author_name: String,
+    pub body: String,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewComment {
+    pub name: String,
+    pub body: String,
+}
+
+impl NoteComment {
+    pub fn from(comment: NewComment, share: &NoteShare) -> Self {
+        NoteComment {
+            id: Uuid::new_v4().to_string(),
+            note_id: share.note_id.clone(),
+            share_id: Some(share.id.clone()),
+            author_name: comment.name.trim().to_string(),
+            body: comment.body,
+            created_at: Utc::now().naive_utc(),
         }
     }
 }
 
+/// What a share link opens: the note and, for comment links, its comments.
+#[derive(Clone, Debug, Serialize)]
+pub struct SharedNote {
+    pub note: Note,
+    pub permission: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub comments: Option<Vec<NoteComment>>,
+}
+
+/// A freshly issued calendar token. The secret is only ever shown here.
+#[derive(Clone, Debug, Serialize)]
+pub struct CalendarFeed {
+    #[serde(flatten)]
+    pub token: CalendarToken,
+    pub secret: String,
+    pub url: String,
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize, Associations, Insertable, Queryable, Identifiable)]
 #[belongs_to(LoggedUser, foreign_key="user_id")]
 #[belongs_to(Group)]
@@ -194,14 +1081,239 @@ pub struct GroupLink {
     pub id: String,
     pub user_id: String,
     pub group_id: String,
+    pub role: String,
+    pub joined_at: NaiveDateTime,
 }
 
 impl GroupLink {
     pub fn from(group: &Group, user: &LoggedUser) -> Self {
+        GroupLink::with_role(group, user, GroupRole::Member)
+    }
+
+    pub fn with_role(group: &Group, user: &LoggedUser, role: GroupRole) -> Self {
         GroupLink {
             id: Uuid::new_v4().to_string(),
             group_id: group.id.clone(),
             user_id: user.id.clone(),
+            role: role.as_str().to_string(),
+            joined_at: Utc::now().naive_utc(),
+        }
+    }
+}
+
+/// A member of a group as shown to the other members.
+#[derive(Clone, Debug, Serialize)]
+pub struct GroupMember {
+    #[serde(flatten)]
+    pub user: PublicUser,
+    pub role: String,
+    pub joined_at: NaiveDateTime,
+}
+
+/// Roles within a group, from least to most privileged.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum GroupRole {
+    Viewer,
+    Member,
+    Admin,
+    Owner,
+}
+
+impl GroupRole {
+    pub fn parse(role: &str) -> Option<Self> {
+        match role {
+            "viewer" => Some(GroupRole::Viewer),
+            "member" => Some(GroupRole::Member),
+            "admin" => Some(GroupRole::Admin),
+            "owner" => Some(GroupRole::Owner),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            GroupRole::Viewer => "viewer",
+            GroupRole::Member => "member",
+            GroupRole::Admin => "admin",
+            GroupRole::Owner => "owner",
+        }
+    }
+
+    /// The next role up, never reaching owner.
+    pub fn promoted(self) -> Option<Self> {
+        match self {
+            GroupRole::Viewer => Some(GroupRole::Member),
+            GroupRole::Member => Some(GroupRole::Admin),
+            GroupRole::Admin | GroupRole::Owner => None,
+        }
+    }
+
+    pub fn demoted(self) -> Option<Self> {
+        match self {
+            GroupRole::Admin => Some(GroupRole::Member),
+            GroupRole::Member => Some(GroupRole::Viewer),
+            GroupRole::Viewer | GroupRole::Owner => None,
+        }
+    }
+}
+/// Invitation into a group, addressed to a registered user or to an email
+/// address. Pending invitations can be accepted, declined or revoked.
+#[derive(Clone, Debug, Serialize, Associations, Insertable, Queryable, Identifiable)]
+#[belongs_to(Group)]
+pub struct GroupInvitation {
+    pub id: String,
+    pub group_id: String,
+    pub email: Option<String>,
+    pub user_id: Option<String>,
+    pub role: String,
+    pub status: String,
+    pub invited_by: String,
+    pub created_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewGroupInvitation {
+    pub email: Option<String>,
+    pub user_id: Option<String>,
+    pub role: Option<String>,
+}
+
+impl GroupInvitation {
+    pub fn from(
+        group: &Group,
+        email: Option<String>,
+        user_id: Option<String>,
+        role: GroupRole,
+        inviter: &LoggedUser,
+    ) -> Self {
+        let now = Utc::now().naive_utc();
+        GroupInvitation {
+            id: Uuid::new_v4().to_string(),
+            group_id: group.id.clone(),
+            email,
+            user_id,
+            role: role.as_str().to_string(),
+            status: String::from("pending"),
+            invited_by: inviter.id.clone(),
+            created_at: now,
+            expires_at: now + chrono::Duration::days(7),
+        }
+    }
+
+    /// Invitations by email only count for accounts that confirmed the
+    /// address, `confirmed_email` is `None` otherwise.
+    pub fn is_addressed_to(&self, user: &LoggedUser, confirmed_email: Option<&String>) -> bool {
+        self.user_id.as_ref() == Some(&user.id)
+            || (confirmed_email.is_some() && self.email.as_ref() == confirmed_email)
+    }
+}
+
+/// An invitation together with the group it leads to.
+#[derive(Clone, Debug, Serialize)]
+pub struct ReceivedInvitation {
+    #[serde(flatten)]
+    pub invitation: GroupInvitation,
+    pub group: Group,
+}
+
+/// Request to join a group that requires approval.
+#[derive(Clone, Debug, Serialize, Associations, Insertable, Queryable, Identifiable)]
+#[belongs_to(Group)]
+pub struct GroupJoinRequest {
+    pub id: String,
+    pub group_id: String,
+    pub user_id: String,
+    pub status: String,
+    pub created_at: NaiveDateTime,
+    pub resolved_at: Option<NaiveDateTime>,
+    pub resolved_by: Option<String>,
+}
+
+impl GroupJoinRequest {
+    pub fn from(group: &Group, user: &LoggedUser) -> Self {
+        GroupJoinRequest {
+            id: Uuid::new_v4().to_string(),
+            group_id: group.id.clone(),
+            user_id: user.id.clone(),
+            status: String::from("pending"),
+            created_at: Utc::now().naive_utc(),
+            resolved_at: None,
+            resolved_by: None,
         }
     }
-}
\ No newline at end of file
+}
+
+/// A join request together with the user who sent it.
+#[derive(Clone, Debug, Serialize)]
+pub struct PendingMember {
+    #[serde(flatten)]
+    pub request: GroupJoinRequest,
+    pub user: PublicUser,
+}
+
+/// Short code that lets anyone who knows it join a group.
+#[derive(Clone, Debug, Serialize, Associations, Insertable, Queryable, Identifiable)]
+#[belongs_to(Group)]
+pub struct GroupJoinCode {
+    pub id: String,
+    pub group_id: String,
+    pub code: String,
+    pub created_by: String,
+    pub created_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+    pub max_uses: Option<i32>,
+    pub uses: i32,
+    pub revoked_at: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewJoinCode {
+    pub expires_at: Option<String>,
+    pub max_uses: Option<i32>,
+}
+
+impl GroupJoinCode {
+    pub fn from(
+        group: &Group,
+        code: String,
+        expires_at: NaiveDateTime,
+        max_uses: Option<i32>,
+        creator: &LoggedUser,
+    ) -> Self {
+        GroupJoinCode {
+            id: Uuid::new_v4().to_string(),
+            group_id: group.id.clone(),
+            code,
+            created_by: creator.id.clone(),
+            created_at: Utc::now().naive_utc(),
+            expires_at,
+            max_uses,
+            uses: 0,
+            revoked_at: None,
+        }
+    }
+
+    pub fn is_usable(&self, now: NaiveDateTime) -> bool {
+        self.revoked_at.is_none()
+            && self.expires_at > now
+            && self.max_uses.map(|limit| self.uses < limit).unwrap_or(true)
+    }
+}
+
+/// A token bucket of the rate limiter, as of `refilled_at`.
+#[derive(Clone, Debug, Insertable, Queryable)]
+pub struct RateLimitBucket {
+    pub id: String,
+    pub tokens: f64,
+    pub refilled_at: NaiveDateTime,
+}
+
+/// Recent failed credential checks for an account.
+#[derive(Clone, Debug, Insertable, Queryable)]
+pub struct LoginFailure {
+    pub id: String,
+    pub failures: i32,
+    pub last_failure_at: NaiveDateTime,
+    pub locked_until: Option<NaiveDateTime>,
+}
@@ -0,0 +1,211 @@
+use actix::{Actor, AsyncContext, Context};
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::prelude::*;
+use diesel::r2d2::ConnectionManager;
+use r2d2::Pool;
+
+use crate::errors::ServiceError;
+use crate::models::{LoggedUser, Note, Notification, NotificationDelivery, Reminder, User};
+use crate::policy::{allows, NoteAction};
+
+type SqlPool = Pool<ConnectionManager<SqliteConnection>>;
+
+/// Deliveries are given up on after this many failed attempts.
+const MAX_ATTEMPTS: i32 = 8;
+/// Upper bound of rows handled per scheduler tick.
+const BATCH_SIZE: i64 = 100;
+
+/// Somewhere notifications are sent besides the in-app inbox.
+pub trait Channel: Send {
+    fn name(&self) -> &'static str;
+
+    fn deliver(&self, user: &User, notification: &Notification) -> Result<(), String>;
+}
+
+/// Writes notifications to the server log.
+pub struct LogChannel;
+
+impl Channel for LogChannel {
+    fn name(&self) -> &'static str {
+        "log"
+    }
+
+    fn deliver(&self, user: &User, notification: &Notification) -> Result<(), String> {
+        println!(
+            "Notification for {}: {} - {}",
+            user.email, notification.title, notification.body
+        );
+        Ok(())
+    }
+}
+
+/// Builds the channels named in a comma separated list such as the
+/// `NOTIFY_CHANNELS` setting.
+pub fn channels_from_config(names: &str) -> Vec<Box<dyn Channel>> {
+    names
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(|name| -> Box<dyn Channel> {
+            match name {
+                "log" => Box::new(LogChannel),
+                other => panic!("Unknown notification channel '{}'", other),
+            }
+        })
+        .collect()
+}
+
+/// Recomputes when the pending reminders of a note fire, after its
+/// `date_tag` changed.
+pub fn reschedule(conn: &SqliteConnection, note: &Note) -> Result<(), ServiceError> {
+    use crate::schema::reminders::dsl::*;
+    let pending = Reminder::belonging_to(note)
+        .filter(fired_at.is_null())
+        .load::<Reminder>(conn)?;
+    for reminder in pending {
+        diesel::update(&reminder)
+            .set(fire_at.eq(reminder.schedule(note)))
+            .execute(conn)?;
+    }
+    Ok(())
+}
+
+/// Moves due reminders into the notification outbox. Marking the reminder
+/// fired, storing the notification and queueing it for every channel happen
+/// in one transaction, so each reminder produces exactly one notification.
+/// Reminders of users who can no longer view the note are dropped instead.
+pub fn fire_due(
+    conn: &SqliteConnection,
+    channels: &[Box<dyn Channel>],
+    now: NaiveDateTime,
+) -> Result<usize, ServiceError> {
+    use crate::schema::notes::dsl as notes;
+    use crate::schema::notification_deliveries::dsl::notification_deliveries;
+    use crate::schema::notifications::dsl::notifications;
+    use crate::schema::reminders::dsl as reminders;
+    use crate::schema::users::dsl as users;
+    let due = reminders::reminders
+        .inner_join(notes::notes)
+        .filter(reminders::fired_at.is_null())
+        .filter(reminders::fire_at.le(now))
+        .filter(notes::deleted_at.is_null())
+        .order(reminders::fire_at.asc())
+        .limit(BATCH_SIZE)
+        .load::<(Reminder, Note)>(conn)?;
+    let mut fired = 0;
+    for (reminder, note) in &due {
+        let user = users::users
+            .filter(users::id.eq(&reminder.user_id))
+            .first::<User>(conn)?;
+        if !allows(conn, &LoggedUser::from(user), note, NoteAction::View)? {
+            diesel::delete(reminder).execute(conn)?;
+            continue;
+        }
+        fired += conn.transaction::<_, ServiceError, _>(|| {
+            let claimed = diesel::update(reminder)
+                .filter(reminders::fired_at.is_null())
+                .set(reminders::fired_at.eq(now))
+                .execute(conn)?;
+            if claimed == 0 {
+                return Ok(0);
+            }
+            let notification = Notification::from_reminder(reminder, note);
+            diesel::insert_into(notifications)
+                .values(&notification)
+                .execute(conn)?;
+            for channel in channels {
+                diesel::insert_into(notification_deliveries)
+                    .values(&NotificationDelivery::from(&notification, channel.name()))
+                    .execute(conn)?;
+            }
+            Ok(claimed)
+        })?;
+    }
+    Ok(fired)
+}
+
+/// Hands queued notifications to their channels. A delivery is only marked
+/// done after the channel accepted it, so a crash in between sends it again
+/// (at-least-once). Failures are retried with exponential backoff.
+pub fn deliver_pending(
+    conn: &SqliteConnection,
+    channels: &[Box<dyn Channel>],
+    now: NaiveDateTime,
+) -> Result<usize, ServiceError> {
+    use crate::schema::notification_deliveries::dsl as deliveries;
+    use crate::schema::notifications::dsl::notifications;
+    use crate::schema::users::dsl as users;
+    let pending = deliveries::notification_deliveries
+        .inner_join(notifications)
+        .filter(deliveries::delivered_at.is_null())
+        .filter(deliveries::next_attempt_at.le(now))
+        .filter(deliveries::attempts.lt(MAX_ATTEMPTS))
+        .order(deliveries::next_attempt_at.asc())
+        .limit(BATCH_SIZE)
+        .load::<(NotificationDelivery, Notification)>(conn)?;
+    let mut delivered = 0;
+    for (delivery, notification) in pending {
+        // channels removed from the configuration keep their rows queued
+        let channel = match channels.iter().find(|channel| channel.name() == delivery.channel) {
+            Some(channel) => channel,
+            None => continue,
+        };
+        let user = users::users
+            .filter(users::id.eq(&notification.user_id))
+            .first::<User>(conn)?;
+        match channel.deliver(&user, &notification) {
+            Ok(()) => {
+                diesel::update(&delivery)
+                    .set(deliveries::delivered_at.eq(Utc::now().naive_utc()))
+                    .execute(conn)?;
+                delivered += 1;
+            }
+            Err(err) => {
+                let backoff = Duration::minutes(1 << delivery.attempts.min(10));
+                diesel::update(&delivery)
+                    .set((
+                        deliveries::attempts.eq(delivery.attempts + 1),
+                        deliveries::next_attempt_at.eq(now + backoff),
+                        deliveries::last_error.eq(err),
+                    ))
+                    .execute(conn)?;
+            }
+        }
+    }
+    Ok(delivered)
+}
+
+/// Background actor that fires due reminders and works through the
+/// delivery queue. Note dates carry no time zone and are treated as UTC.
+pub struct ReminderScheduler {
+    pub pool: SqlPool,
+    pub channels: Vec<Box<dyn Channel>>,
+    pub interval: std::time::Duration,
+}
+
+impl ReminderScheduler {
+    fn tick(&self) {
+        let conn = match self.pool.get() {
+            Ok(conn) => conn,
+            Err(err) => {
+                println!("Reminder run skipped, no database connection: {}", err);
+                return;
+            }
+        };
+        if let Err(err) = fire_due(&conn, &self.channels, Utc::now().naive_utc()) {
+            println!("Firing reminders failed: {}", err);
+        }
+        if let Err(err) = deliver_pending(&conn, &self.channels, Utc::now().naive_utc()) {
+            println!("Delivering notifications failed: {}", err);
+        }
+    }
+}
+
+impl Actor for ReminderScheduler {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.tick();
+        ctx.run_interval(self.interval, |scheduler, _| scheduler.tick());
+    }
+}
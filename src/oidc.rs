@@ -0,0 +1,570 @@
+use awc::Client;
+use futures::{future, Future};
+use jsonwebtoken::{decode, decode_header, Algorithm, Validation};
+use serde::de::DeserializeOwned;
+use sha2::{Digest, Sha256};
+use std::env;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::errors::ServiceError;
+use crate::tokens::random_bytes;
+
+/// Largest discovery, token or key set response we accept.
+const MAX_RESPONSE_BYTES: usize = 1 << 20;
+/// Clock skew allowed when checking the expiry of ID tokens.
+const LEEWAY_SECONDS: i64 = 60;
+/// How long discovery documents and key sets are reused. Keys are fetched
+/// again sooner when a token names one we don't know.
+const CACHE_SECONDS: u64 = 60 * 60;
+
+/// An OpenID Connect identity provider people can sign in with.
+#[derive(Clone, Debug)]
+pub struct Provider {
+    pub name: String,
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    pub redirect_uri: String,
+    pub scopes: String,
+    cache: Arc<Mutex<Cache>>,
+}
+
+impl Provider {
+    pub fn new(name: &str, issuer: &str, client_id: &str, redirect_uri: &str) -> Self {
+        Provider {
+            name: name.to_lowercase(),
+            issuer: issuer.to_string(),
+            client_id: client_id.to_string(),
+            client_secret: None,
+            redirect_uri: redirect_uri.to_string(),
+            scopes: "openid email profile".into(),
+            cache: Arc::default(),
+        }
+    }
+}
+
+/// What we fetched from a provider, and when.
+#[derive(Debug, Default)]
+struct Cache {
+    discovery: Option<(Instant, Discovery)>,
+    keys: Option<(Instant, KeySet)>,
+}
+
+fn fresh<T: Clone>(entry: &Option<(Instant, T)>) -> Option<T> {
+    entry
+        .as_ref()
+        .filter(|(fetched, _)| fetched.elapsed() < Duration::from_secs(CACHE_SECONDS))
+        .map(|(_, value)| value.clone())
+}
+
+/// Reads the providers named in `OIDC_PROVIDERS` (comma separated). Each
+/// one is configured with `OIDC_<NAME>_ISSUER`, `_CLIENT_ID`,
+/// `_REDIRECT_URI` and optionally `_CLIENT_SECRET` and `_SCOPES`.
+pub fn providers_from_env() -> Vec<Provider> {
+    let names = env::var("OIDC_PROVIDERS").unwrap_or_default();
+    names
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(|name| {
+            let setting = |key: &str| env::var(format!("OIDC_{}_{}", name.to_uppercase(), key));
+            let required = |key: &str| {
+                setting(key).unwrap_or_else(|_| {
+                    panic!("OIDC_{}_{} is not set", name.to_uppercase(), key)
+                })
+            };
+            let mut provider = Provider::new(
+                name,
+                &required("ISSUER"),
+                &required("CLIENT_ID"),
+                &required("REDIRECT_URI"),
+            );
+            provider.client_secret = setting("CLIENT_SECRET").ok();
+            if let Ok(scopes) = setting("SCOPES") {
+                provider.scopes = scopes;
+            }
+            provider
+        })
+        .collect()
+}
+
+/// The parts of a provider's discovery document we use.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Discovery {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub jwks_uri: String,
+}
+
+#[derive(Deserialize)]
+pub struct TokenResponse {
+    pub id_token: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct KeySet {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct Jwk {
+    kty: String,
+    kid: Option<String>,
+    n: Option<String>,
+    e: Option<String>,
+}
+
+/// What we read from a validated ID token.
+#[derive(Deserialize)]
+pub struct IdClaims {
+    pub sub: String,
+    pub nonce: Option<String>,
+    pub email: Option<String>,
+    pub email_verified: Option<bool>,
+    pub name: Option<String>,
+    pub preferred_username: Option<String>,
+}
+
+fn client() -> Client {
+    Client::build().timeout(Duration::from_secs(10)).finish()
+}
+
+/// Turns a JSON response into `T`. Transport errors and error statuses are
+/// logged and answered as internal errors, since the user can't fix them.
+fn read_json<T: DeserializeOwned + 'static>(
+    what: &'static str,
+    request: awc::SendClientRequest,
+) -> impl Future<Item = T, Error = ServiceError> {
+    request
+        .map_err(move |err| {
+            println!("Fetching the {} failed: {}", what, err);
+            ServiceError::InternalServerError
+        })
+        .and_then(move |mut response| {
+            let status = response.status();
+            response
+                .json::<T>()
+                .limit(MAX_RESPONSE_BYTES)
+                .then(move |body| match body {
+                    Ok(body) if status.is_success() => Ok(body),
+                    Ok(_) => {
+                        println!("Fetching the {} failed with status {}", what, status);
+                        Err(ServiceError::InternalServerError)
+                    }
+                    Err(err) => {
+                        println!("Reading the {} failed ({}): {}", what, status, err);
+                        Err(ServiceError::InternalServerError)
+                    }
+                })
+        })
+}
+
+/// Fetches the discovery document, or reuses a recent one, and checks it
+/// belongs to the issuer. The issuer has to match exactly, as it does in
+/// the ID tokens.
+pub fn discover(provider: &Provider) -> impl Future<Item = Discovery, Error = ServiceError> {
+    if let Some(discovery) = fresh(&provider.cache.lock().unwrap().discovery) {
+        return future::Either::A(future::ok(discovery));
+    }
+    let issuer = provider.issuer.clone();
+    let cache = provider.cache.clone();
+    let url = format!("{}/.well-known/openid-configuration", issuer.trim_end_matches('/'));
+    future::Either::B(
+        read_json::<Discovery>("discovery document", client().get(url).send()).and_then(
+            move |discovery| {
+                if discovery.issuer != issuer {
+                    println!("Discovery document of {} names issuer {}", issuer, discovery.issuer);
+                    return Err(ServiceError::InternalServerError);
+                }
+                cache.lock().unwrap().discovery = Some((Instant::now(), discovery.clone()));
+                Ok(discovery)
+            },
+        ),
+    )
+}
+
+/// A PKCE verifier and the challenge sent along with the authorization
+/// request.
+pub fn pkce_pair() -> (String, String) {
+    let verifier = base64::encode_config(&random_bytes(32), base64::URL_SAFE_NO_PAD);
+    let challenge = base64::encode_config(&Sha256::digest(verifier.as_bytes()), base64::URL_SAFE_NO_PAD);
+    (verifier, challenge)
+}
+
+/// Where to send the browser to sign in at the provider.
+pub fn authorization_url(
+    provider: &Provider,
+    discovery: &Discovery,
+    state: &str,
+    nonce: &str,
+    code_challenge: &str,
+) -> String {
+    let query = serde_urlencoded::to_string(&[
+        ("response_type", "code"),
+        ("client_id", &provider.client_id),
+        ("redirect_uri", &provider.redirect_uri),
+        ("scope", &provider.scopes),
+        ("state", state),
+        ("nonce", nonce),
+        ("code_challenge", code_challenge),
+        ("code_challenge_method", "S256"),
+    ])
+    .unwrap();
+    let separator = if discovery.authorization_endpoint.contains('?') { '&' } else { '?' };
+    format!("{}{}{}", discovery.authorization_endpoint, separator, query)
+}
+
+/// Trades the authorization code for the provider's tokens.
+pub fn exchange_code(
+    provider: &Provider,
+    discovery: &Discovery,
+    code: &str,
+    code_verifier: &str,
+) -> impl Future<Item = TokenResponse, Error = ServiceError> {
+    let mut form = vec![
+        ("grant_type", "authorization_code".to_string()),
+        ("code", code.to_string()),
+        ("redirect_uri", provider.redirect_uri.clone()),
+        ("client_id", provider.client_id.clone()),
+        ("code_verifier", code_verifier.to_string()),
+    ];
+    if let Some(secret) = &provider.client_secret {
+        form.push(("client_secret", secret.clone()));
+    }
+    read_json(
+        "token response",
+        client().post(&discovery.token_endpoint).send_form(&form),
+    )
+}
+
+fn der_length(len: usize) -> Vec<u8> {
+    if len < 0x80 {
+        return vec![len as u8];
+    }
+    let bytes: Vec<u8> = len.to_be_bytes().iter().cloned().skip_while(|b| *b == 0).collect();
+    let mut encoded = vec![0x80 | bytes.len() as u8];
+    encoded.extend(bytes);
+    encoded
+}
+
+fn der_integer(value: &[u8]) -> Vec<u8> {
+    let mut digits: Vec<u8> = value.iter().cloned().skip_while(|b| *b == 0).collect();
+    // keep the number positive
+    if digits.first().map(|b| b & 0x80 != 0).unwrap_or(true) {
+        digits.insert(0, 0);
+    }
+    let mut encoded = vec![0x02];
+    encoded.extend(der_length(digits.len()));
+    encoded.extend(digits);
+    encoded
+}
+
+/// The DER encoded PKCS#1 public key for a JWK's modulus and exponent, the
+/// form `jsonwebtoken` verifies RS256 signatures with.
+fn rsa_public_key_der(jwk: &Jwk) -> Option<Vec<u8>> {
+    let n = base64::decode_config(jwk.n.as_ref()?, base64::URL_SAFE_NO_PAD).ok()?;
+    let e = base64::decode_config(jwk.e.as_ref()?, base64::URL_SAFE_NO_PAD).ok()?;
+    let mut body = der_integer(&n);
+    body.extend(der_integer(&e));
+    let mut encoded = vec![0x30];
+    encoded.extend(der_length(body.len()));
+    encoded.extend(body);
+    Some(encoded)
+}
+
+/// The key a token's header names, or the only one if it names none.
+fn find_key(key_set: &KeySet, kid: &Option<String>) -> Option<Vec<u8>> {
+    key_set
+        .keys
+        .iter()
+        .filter(|key| key.kty == "RSA")
+        .find(|key| kid.is_none() || key.kid == *kid)
+        .and_then(rsa_public_key_der)
+}
+
+/// The public key to check a token signed with `kid` with. The provider's
+/// key set is fetched again when it is old or doesn't have the key, as
+/// happens after the provider rotated its keys.
+fn signing_key(
+    provider: &Provider,
+    discovery: &Discovery,
+    kid: Option<String>,
+) -> impl Future<Item = Vec<u8>, Error = ServiceError> {
+    let cached = fresh(&provider.cache.lock().unwrap().keys);
+    if let Some(key) = cached.and_then(|key_set| find_key(&key_set, &kid)) {
+        return future::Either::A(future::ok(key));
+    }
+    let cache = provider.cache.clone();
+    future::Either::B(
+        read_json::<KeySet>("key set", client().get(&discovery.jwks_uri).send()).and_then(
+            move |key_set| {
+                let key = find_key(&key_set, &kid);
+                cache.lock().unwrap().keys = Some((Instant::now(), key_set));
+                key.ok_or(ServiceError::Unauthorized)
+            },
+        ),
+    )
+}
+
+/// Checks an ID token against the provider's published keys: an RS256
+/// signature, our client as audience, the provider as issuer, not expired
+/// and carrying the nonce of the login.
+pub fn validate_id_token(
+    provider: &Provider,
+    discovery: &Discovery,
+    id_token: String,
+    nonce: String,
+) -> Box<dyn Future<Item = IdClaims, Error = ServiceError>> {
+    let header = match decode_header(&id_token) {
+        Ok(header) if header.alg == Algorithm::RS256 => header,
+        _ => return Box::new(future::err(ServiceError::Unauthorized)),
+    };
+    let mut validation = Validation::new(Algorithm::RS256);
+    validation.leeway = LEEWAY_SECONDS;
+    validation.iss = Some(provider.issuer.clone());
+    validation.set_audience(&provider.client_id);
+    Box::new(signing_key(provider, discovery, header.kid).and_then(move |key| {
+        let claims = decode::<IdClaims>(&id_token, &key, &validation)
+            .map_err(|_| ServiceError::Unauthorized)?
+            .claims;
+        if claims.nonce.as_ref() != Some(&nonce) {
+            return Err(ServiceError::Unauthorized);
+        }
+        Ok(claims)
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{web, App, HttpResponse, HttpServer};
+    use chrono::Utc;
+    use jsonwebtoken::{encode, Header};
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // a key pair made for these tests only
+    const PRIVATE_KEY: &[u8] = include_bytes!("../testdata/oidc/private_key.der");
+    const PUBLIC_KEY: &[u8] = include_bytes!("../testdata/oidc/public_key.der");
+    const JWKS: &str = include_str!("../testdata/oidc/jwks.json");
+    const KID: &str = "test-key";
+    const CLIENT_ID: &str = "conduit";
+
+    #[derive(Default)]
+    struct Hits {
+        discovery: AtomicUsize,
+        keys: AtomicUsize,
+    }
+
+    /// Serves discovery and keys on a free local port and answers with the
+    /// issuer URL.
+    fn mock_issuer(hits: Arc<Hits>) -> String {
+        let server = HttpServer::new(move || {
+            let hits = hits.clone();
+            App::new()
+                .data(hits)
+                .route(
+                    "/.well-known/openid-configuration",
+                    web::get().to(|req: actix_web::HttpRequest, hits: web::Data<Arc<Hits>>| {
+                        hits.discovery.fetch_add(1, Ordering::SeqCst);
+                        let issuer = format!("http://{}", req.connection_info().host());
+                        HttpResponse::Ok().json(serde_json::json!({
+                            "issuer": issuer,
+                            "authorization_endpoint": format!("{}/authorize", issuer),
+                            "token_endpoint": format!("{}/token", issuer),
+                            "jwks_uri": format!("{}/jwks", issuer),
+                        }))
+                    }),
+                )
+                .route(
+                    "/token",
+                    web::post().to(|form: web::Form<HashMap<String, String>>| {
+                        let field = |name: &str| form.get(name).map(String::as_str);
+                        let valid = field("grant_type") == Some("authorization_code")
+                            && field("code") == Some("code-1")
+                            && field("code_verifier") == Some("verifier-1")
+                            && field("client_id") == Some(CLIENT_ID);
+                        if !valid {
+                            return HttpResponse::BadRequest().json(serde_json::json!({
+                                "error": "invalid_grant",
+                            }));
+                        }
+                        HttpResponse::Ok().json(serde_json::json!({
+                            "access_token": "access-1",
+                            "token_type": "Bearer",
+                            "id_token": "id-token-1",
+                        }))
+                    }),
+                )
+                .route(
+                    "/jwks",
+                    web::get().to(|hits: web::Data<Arc<Hits>>| {
+                        hits.keys.fetch_add(1, Ordering::SeqCst);
+                        HttpResponse::Ok().content_type("application/json").body(JWKS)
+                    }),
+                )
+        })
+        .workers(1)
+        .disable_signals()
+        .bind("127.0.0.1:0")
+        .unwrap();
+        let issuer = format!("http://{}", server.addrs()[0]);
+        server.start();
+        issuer
+    }
+
+    fn provider(issuer: &str) -> Provider {
+        Provider::new("mock", issuer, CLIENT_ID, "http://localhost/callback")
+    }
+
+    fn id_token(issuer: &str, kid: Option<&str>, changes: serde_json::Value) -> String {
+        let now = Utc::now().timestamp();
+        let mut claims = serde_json::json!({
+            "iss": issuer,
+            "aud": CLIENT_ID,
+            "sub": "user-1",
+            "iat": now,
+            "exp": now + 300,
+            "nonce": "nonce-1",
+            "email": "ada@example.com",
+            "email_verified": true,
+        });
+        for (key, value) in changes.as_object().unwrap() {
+            claims[key] = value.clone();
+        }
+        let mut header = Header::new(Algorithm::RS256);
+        header.kid = kid.map(String::from);
+        encode(&header, &claims, PRIVATE_KEY).unwrap()
+    }
+
+    #[test]
+    fn der_length_uses_the_long_form_from_128_on() {
+        assert_eq!(der_length(0x45), vec![0x45]);
+        assert_eq!(der_length(0x7f), vec![0x7f]);
+        assert_eq!(der_length(0x80), vec![0x81, 0x80]);
+        assert_eq!(der_length(0x100), vec![0x82, 0x01, 0x00]);
+        assert_eq!(der_length(0x1234), vec![0x82, 0x12, 0x34]);
+    }
+
+    #[test]
+    fn der_integer_is_minimal_and_positive() {
+        assert_eq!(der_integer(&[0x00, 0x00, 0x01]), vec![0x02, 0x01, 0x01]);
+        assert_eq!(der_integer(&[0x80]), vec![0x02, 0x02, 0x00, 0x80]);
+        assert_eq!(der_integer(&[0x01, 0x00, 0x01]), vec![0x02, 0x03, 0x01, 0x00, 0x01]);
+        assert_eq!(der_integer(&[]), vec![0x02, 0x01, 0x00]);
+    }
+
+    #[test]
+    fn jwk_converts_to_the_pkcs1_public_key() {
+        let key_set: KeySet = serde_json::from_str(JWKS).unwrap();
+        assert_eq!(rsa_public_key_der(&key_set.keys[0]).unwrap(), PUBLIC_KEY);
+    }
+
+    #[test]
+    fn jwk_without_modulus_is_ignored() {
+        let jwk = Jwk {
+            kty: "RSA".into(),
+            kid: None,
+            n: None,
+            e: Some("AQAB".into()),
+        };
+        assert!(rsa_public_key_der(&jwk).is_none());
+    }
+
+    #[test]
+    fn pkce_challenge_is_the_hashed_verifier() {
+        let (verifier, challenge) = pkce_pair();
+        assert_eq!(verifier.len(), 43);
+        assert_eq!(
+            challenge,
+            base64::encode_config(&Sha256::digest(verifier.as_bytes()), base64::URL_SAFE_NO_PAD)
+        );
+    }
+
+    #[test]
+    fn discovery_is_fetched_once_and_checked() {
+        let mut sys = actix::System::new("oidc-test");
+        let hits = Arc::new(Hits::default());
+        let issuer = mock_issuer(hits.clone());
+        let provider = provider(&issuer);
+        let discovery = sys.block_on(discover(&provider)).unwrap();
+        assert_eq!(discovery.jwks_uri, format!("{}/jwks", issuer));
+        sys.block_on(discover(&provider)).unwrap();
+        assert_eq!(hits.discovery.load(Ordering::SeqCst), 1);
+
+        // same document, but issuers have to match exactly
+        let mismatched = Provider::new("mock", &format!("{}/", issuer), CLIENT_ID, "");
+        assert!(sys.block_on(discover(&mismatched)).is_err());
+    }
+
+    #[test]
+    fn codes_are_exchanged_with_the_verifier() {
+        let mut sys = actix::System::new("oidc-test");
+        let issuer = mock_issuer(Arc::default());
+        let provider = provider(&issuer);
+        let discovery = sys.block_on(discover(&provider)).unwrap();
+        let response = sys
+            .block_on(exchange_code(&provider, &discovery, "code-1", "verifier-1"))
+            .unwrap();
+        assert_eq!(response.id_token, "id-token-1");
+        let refused = sys.block_on(exchange_code(&provider, &discovery, "code-1", "other"));
+        assert!(refused.is_err());
+    }
+
+    #[test]
+    fn id_tokens_are_validated_against_the_key_set() {
+        let mut sys = actix::System::new("oidc-test");
+        let hits = Arc::new(Hits::default());
+        let issuer = mock_issuer(hits.clone());
+        let provider = provider(&issuer);
+        let discovery = sys.block_on(discover(&provider)).unwrap();
+        let mut validate = |token: String| {
+            sys.block_on(validate_id_token(&provider, &discovery, token, "nonce-1".into()))
+        };
+
+        let claims = validate(id_token(&issuer, Some(KID), serde_json::json!({}))).unwrap();
+        assert_eq!(claims.sub, "user-1");
+        assert_eq!(claims.email_verified, Some(true));
+        assert!(validate(id_token(&issuer, None, serde_json::json!({}))).is_ok());
+        // the key set was fetched once and reused
+        assert_eq!(hits.keys.load(Ordering::SeqCst), 1);
+
+        let rejected = [
+            serde_json::json!({ "nonce": "other" }),
+            serde_json::json!({ "aud": "someone-else" }),
+            serde_json::json!({ "iss": "http://issuer.example" }),
+            serde_json::json!({ "exp": Utc::now().timestamp() - 3600 }),
+        ];
+        for changes in rejected.iter() {
+            assert!(validate(id_token(&issuer, Some(KID), changes.clone())).is_err());
+        }
+        let mut tampered = id_token(&issuer, Some(KID), serde_json::json!({}));
+        let last = if tampered.ends_with('A') { "B" } else { "A" };
+        tampered.pop();
+        tampered.push_str(last);
+        assert!(validate(tampered).is_err());
+    }
+
+    #[test]
+    fn unknown_key_ids_refetch_the_key_set() {
+        let mut sys = actix::System::new("oidc-test");
+        let hits = Arc::new(Hits::default());
+        let issuer = mock_issuer(hits.clone());
+        let provider = provider(&issuer);
+        let discovery = sys.block_on(discover(&provider)).unwrap();
+        // keys from before a rotation
+        provider.cache.lock().unwrap().keys = Some((Instant::now(), KeySet { keys: vec![] }));
+
+        let token = id_token(&issuer, Some(KID), serde_json::json!({}));
+        let validated = sys.block_on(validate_id_token(&provider, &discovery, token, "nonce-1".into()));
+        assert!(validated.is_ok());
+        assert_eq!(hits.keys.load(Ordering::SeqCst), 1);
+
+        let token = id_token(&issuer, Some("retired-key"), serde_json::json!({}));
+        let validated = sys.block_on(validate_id_token(&provider, &discovery, token, "nonce-1".into()));
+        assert!(validated.is_err());
+        assert_eq!(hits.keys.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,242 @@
+use actix_web::http::Method;
+use diesel::prelude::*;
+
+use crate::errors::ServiceError;
+use crate::models::{GroupLink, GroupRole, LoggedUser, Note, NoteGrant};
+
+pub const READER: &str = "reader";
+pub const EDITOR: &str = "editor";
+
+/// What a user wants to do with a note.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NoteAction {
+    /// Read the note and its revisions, attachments and comments.
+    View,
+    /// Change the contents of the note.
+    Edit,
+    /// Move the note to the trash. The owner and admins of its group may
+    /// do this.
+    Delete,
+    /// Change the owner or group of the note, publish or pin it and control
+    /// who can access it. Only the owner may do this.
+    Manage,
+}
+
+/// What a personal access token may be used for.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Scope {
+    /// Read notes and everything attached to them, tags, the calendar and
+    /// notifications.
+    NotesRead,
+    /// Create, change and delete those.
+    NotesWrite,
+    /// See groups, their notes and members.
+    GroupsRead,
+    /// Join and leave groups and answer invitations.
+    GroupsWrite,
+    /// Manage groups: settings, members, invitations, codes and requests.
+    GroupsAdmin,
+}
+
+impl Scope {
+    pub const ALL: [Scope; 5] = [
+        Scope::NotesRead,
+        Scope::NotesWrite,
+        Scope::GroupsRead,
+        Scope::GroupsWrite,
+        Scope::GroupsAdmin,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Scope::NotesRead => "notes:read",
+            Scope::NotesWrite => "notes:write",
+            Scope::GroupsRead => "groups:read",
+            Scope::GroupsWrite => "groups:write",
+            Scope::GroupsAdmin => "groups:admin",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Scope> {
+        Scope::ALL.iter().cloned().find(|scope| scope.as_str() == name)
+    }
+}
+
+/// The scope a personal access token needs for the routes this is attached
+/// to as route data. Routes without it can't be used with such tokens.
+pub struct TokenAccess {
+    read: Scope,
+    write: Scope,
+}
+
+impl TokenAccess {
+    /// `read` for GET requests, `write` for everything else.
+    pub fn new(read: Scope, write: Scope) -> Self {
+        TokenAccess { read, write }
+    }
+
+    pub fn only(scope: Scope) -> Self {
+        TokenAccess::new(scope, scope)
+    }
+
+    pub fn required(&self, method: &Method) -> Scope {
+        match *method {
+            Method::GET | Method::HEAD => self.read,
+            _ => self.write,
+        }
+    }
+}
+
+/// The role of `user` in a group that is not in the trash, if they are a
+/// member.
+pub fn group_role(
+    conn: &SqliteConnection,
+    user: &LoggedUser,
+    gid: &str,
+) -> Result<Option<GroupRole>, ServiceError> {
+    use crate::schema::groups::dsl::{deleted_at, groups, id as g_id};
+    let live_group = groups
+        .filter(g_id.eq(gid))
+        .filter(deleted_at.is_null())
+        .count()
+        .get_result::<i64>(conn)?;
+    if live_group == 0 {
+        return Ok(None);
+    }
+    member_role(conn, user, gid)
+}
+
+/// The role of `user` in a group, whether or not it is in the trash.
+pub fn member_role(
+    conn: &SqliteConnection,
+    user: &LoggedUser,
+    gid: &str,
+) -> Result<Option<GroupRole>, ServiceError> {
+    use crate::schema::group_links::dsl::{group_id, role};
+    let role_name = GroupLink::belonging_to(user)
+        .filter(group_id.eq(gid))
+        .select(role)
+        .first::<String>(conn)
+        .optional()?;
+    Ok(role_name.as_ref().and_then(|name| GroupRole::parse(name)))
+}
+
+/// Fails with `Forbidden` unless `user` holds at least `min` in the group.
+pub fn require_group_role(
+    conn: &SqliteConnection,
+    user: &LoggedUser,
+    gid: &str,
+    min: GroupRole,
+) -> Result<GroupRole, ServiceError> {
+    match group_role(conn, user, gid)? {
+        Some(current) if current >= min => Ok(current),
+        _ => Err(ServiceError::Forbidden),
+    }
+}
+
+fn grant_role(
+    conn: &SqliteConnection,
+    user: &LoggedUser,
+    note: &Note,
+) -> Result<Option<String>, ServiceError> {
+    use crate::schema::note_grants::dsl::*;
+    let role_name = NoteGrant::belonging_to(note)
+        .filter(user_id.eq(&user.id))
+        .select(role)
+        .first::<String>(conn)
+        .optional()?;
+    Ok(role_name)
+}
+
+fn note_group_role(
+    conn: &SqliteConnection,
+    user: &LoggedUser,
+    note: &Note,
+) -> Result<Option<GroupRole>, ServiceError> {
+    match &note.group_id {
+        Some(gid) => group_role(conn, user, gid),
+        None => Ok(None),
+    }
+}
+
+/// Whether `user` may perform `action` on `note`. Owners may do anything.
+/// Editors may view and edit; group admins may delete; readers, group
+/// members and, for public notes, everyone else may view.
+pub fn allows(
+    conn: &SqliteConnection,
+    user: &LoggedUser,
+    note: &Note,
+    action: NoteAction,
+) -> Result<bool, ServiceError> {
+    if note.user_id == user.id {
+        return Ok(true);
+    }
+    match action {
+        NoteAction::Manage => Ok(false),
+        NoteAction::Delete => Ok(note_group_role(conn, user, note)? >= Some(GroupRole::Admin)),
+        NoteAction::Edit => {
+            let role = grant_role(conn, user, note)?;
+            Ok(role.as_ref().map(String::as_str) == Some(EDITOR))
+        }
+        NoteAction::View => Ok(note.public == 1
+            || grant_role(conn, user, note)?.is_some()
+            || note_group_role(conn, user, note)?.is_some()),
+    }
+}
+
+/// Loads a note that is not in the trash and checks that `user` may perform
+/// `action` on it.
+pub fn authorize(
+    conn: &SqliteConnection,
+    user: &LoggedUser,
+    note_id: &str,
+    action: NoteAction,
+) -> Result<Note, ServiceError> {
+    use crate::schema::notes::dsl::*;
+    let note = notes
+        .filter(id.eq(note_id))
+        .filter(deleted_at.is_null())
+        .first::<Note>(conn)
+        .optional()?
+        .ok_or_else(|| ServiceError::BadRequest(String::from("Invalid note identifiers!")))?;
+    if !allows(conn, user, &note, action)? {
+        return Err(ServiceError::Forbidden);
+    }
+    Ok(note)
+}
+
+/// Loads a note the user is allowed to see, or fails with `Forbidden`.
+pub fn visible_note(
+    conn: &SqliteConnection,
+    user: &LoggedUser,
+    note_id: &str,
+) -> Result<Note, ServiceError> {
+    authorize(conn, user, note_id, NoteAction::View)
+}
+
+/// Loads a note the user is allowed to change, or fails with `Forbidden`.
+pub fn editable_note(
+    conn: &SqliteConnection,
+    user: &LoggedUser,
+    note_id: &str,
+) -> Result<Note, ServiceError> {
+    authorize(conn, user, note_id, NoteAction::Edit)
+}
+
+/// Loads a note the user may move to the trash, or fails with `Forbidden`.
+pub fn deletable_note(
+    conn: &SqliteConnection,
+    user: &LoggedUser,
+    note_id: &str,
+) -> Result<Note, ServiceError> {
+    authorize(conn, user, note_id, NoteAction::Delete)
+}
+
+/// Loads a note the user owns, or fails with `Forbidden`.
+pub fn owned_note(
+    conn: &SqliteConnection,
+    user: &LoggedUser,
+    note_id: &str,
+) -> Result<Note, ServiceError> {
+    authorize(conn, user, note_id, NoteAction::Manage)
+}
@@ -0,0 +1,542 @@
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::{error::BlockingError, web, Error, HttpMessage, HttpRequest};
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::prelude::*;
+use diesel::r2d2::ConnectionManager;
+use futures::future::{ok, Either, FutureResult};
+use futures::{Future, Poll};
+use r2d2::Pool;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::env;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+
+use crate::errors::ServiceError;
+use crate::models::{LoginFailure, RateLimitBucket};
+use crate::routes::auth::client_ip;
+
+type SqlPool = Pool<ConnectionManager<SqliteConnection>>;
+
+/// Failed attempts on an account before it gets locked.
+const FAILURES_BEFORE_LOCKOUT: i32 = 5;
+/// The first lockout; every further failure doubles it.
+const FIRST_LOCKOUT_SECONDS: i64 = 30;
+const MAX_LOCKOUT_SECONDS: i64 = 60 * 60;
+/// Failures are forgotten after a day without any.
+const FAILURE_MEMORY_HOURS: i64 = 24;
+/// Size at which the memory store starts dropping idle buckets.
+const MAX_MEMORY_BUCKETS: usize = 10_000;
+
+/// A token bucket: `capacity` requests in a burst and one more every
+/// `every`.
+#[derive(Clone, Copy, Debug)]
+pub struct Bucket {
+    pub capacity: u32,
+    pub every: std::time::Duration,
+}
+
+impl Bucket {
+    pub const fn new(capacity: u32, every: std::time::Duration) -> Self {
+        Bucket { capacity, every }
+    }
+
+    /// Refills `state` up to `now` and takes a token out. When the bucket is
+    /// empty it also answers how many seconds until the next token.
+    fn take(&self, key: &str, state: Option<RateLimitBucket>, now: NaiveDateTime) -> (RateLimitBucket, Option<u64>) {
+        let capacity = f64::from(self.capacity);
+        let every = self.every.as_secs_f64();
+        let mut state = state.unwrap_or_else(|| RateLimitBucket {
+            id: key.to_string(),
+            tokens: capacity,
+            refilled_at: now,
+        });
+        let elapsed = (now - state.refilled_at).num_milliseconds().max(0) as f64 / 1000.0;
+        state.tokens = (state.tokens + elapsed / every).min(capacity);
+        state.refilled_at = now;
+        if state.tokens >= 1.0 {
+            state.tokens -= 1.0;
+            (state, None)
+        } else {
+            let wait = ((1.0 - state.tokens) * every).ceil() as u64;
+            (state, Some(wait.max(1)))
+        }
+    }
+}
+
+/// The limits of a group of routes. Routes sharing a `name` share their
+/// buckets.
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    pub name: &'static str,
+    pub per_ip: Bucket,
+    pub per_account: Bucket,
+}
+
+fn seconds_until(until: NaiveDateTime, now: NaiveDateTime) -> u64 {
+    let millis = (until - now).num_milliseconds().max(0) as u64;
+    ((millis + 999) / 1000).max(1)
+}
+
+/// Counts one more failure on top of `state`. From the
+/// `FAILURES_BEFORE_LOCKOUT`th on, each failure locks the account, twice as
+/// long as the one before.
+fn add_failure(key: &str, state: Option<LoginFailure>, now: NaiveDateTime) -> LoginFailure {
+    let mut state = state
+        .filter(|state| now - state.last_failure_at < Duration::hours(FAILURE_MEMORY_HOURS))
+        .unwrap_or_else(|| LoginFailure {
+            id: key.to_string(),
+            failures: 0,
+            last_failure_at: now,
+            locked_until: None,
+        });
+    state.failures += 1;
+    state.last_failure_at = now;
+    if state.failures >= FAILURES_BEFORE_LOCKOUT {
+        let doublings = (state.failures - FAILURES_BEFORE_LOCKOUT).min(16) as u32;
+        let seconds = (FIRST_LOCKOUT_SECONDS << doublings).min(MAX_LOCKOUT_SECONDS);
+        state.locked_until = Some(now + Duration::seconds(seconds));
+    }
+    state
+}
+
+/// Where buckets and failure counts are kept.
+pub trait LimitStore: Send + Sync {
+    /// Takes a token from the bucket at `key`. Answers the seconds to wait
+    /// when it is empty.
+    fn take(&self, key: &str, bucket: &Bucket) -> Result<Option<u64>, ServiceError>;
+
+    fn failures(&self, key: &str) -> Result<Option<LoginFailure>, ServiceError>;
+
+    fn record_failure(&self, key: &str) -> Result<LoginFailure, ServiceError>;
+
+    fn clear_failures(&self, key: &str) -> Result<(), ServiceError>;
+}
+
+pub type Limiter = Arc<dyn LimitStore>;
+
+/// Keeps everything in this process. Limits start over on a restart and
+/// aren't shared between instances.
+#[derive(Default)]
+pub struct MemoryStore {
+    buckets: Mutex<HashMap<String, RateLimitBucket>>,
+    failures: Mutex<HashMap<String, LoginFailure>>,
+}
+
+impl LimitStore for MemoryStore {
+    fn take(&self, key: &str, bucket: &Bucket) -> Result<Option<u64>, ServiceError> {
+        let now = Utc::now().naive_utc();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_MEMORY_BUCKETS {
+            buckets.retain(|_, state| now - state.refilled_at < Duration::hours(1));
+        }
+        let (state, wait) = bucket.take(key, buckets.remove(key), now);
+        buckets.insert(key.to_string(), state);
+        Ok(wait)
+    }
+
+    fn failures(&self, key: &str) -> Result<Option<LoginFailure>, ServiceError> {
+        Ok(self.failures.lock().unwrap().get(key).cloned())
+    }
+
+    fn record_failure(&self, key: &str) -> Result<LoginFailure, ServiceError> {
+        let mut failures = self.failures.lock().unwrap();
+        let state = add_failure(key, failures.remove(key), Utc::now().naive_utc());
+        failures.insert(key.to_string(), state.clone());
+        Ok(state)
+    }
+
+    fn clear_failures(&self, key: &str) -> Result<(), ServiceError> {
+        self.failures.lock().unwrap().remove(key);
+        Ok(())
+    }
+}
+
+/// Keeps everything in the database, so limits hold across restarts and
+/// instances.
+pub struct SqliteStore {
+    pub pool: SqlPool,
+}
+
+impl LimitStore for SqliteStore {
+    fn take(&self, key: &str, bucket: &Bucket) -> Result<Option<u64>, ServiceError> {
+        use crate::schema::rate_limit_buckets::dsl::*;
+        let conn = self.pool.get().unwrap();
+        conn.transaction(|| {
+            let current = rate_limit_buckets
+                .filter(id.eq(key))
+                .first::<RateLimitBucket>(&conn)
+                .optional()?;
+            let (state, wait) = bucket.take(key, current, Utc::now().naive_utc());
+            diesel::replace_into(rate_limit_buckets)
+                .values(&state)
+                .execute(&conn)?;
+            Ok(wait)
+        })
+    }
+
+    fn failures(&self, key: &str) -> Result<Option<LoginFailure>, ServiceError> {
+        use crate::schema::login_failures::dsl::*;
+        let conn = self.pool.get().unwrap();
+        let state = login_failures
+            .filter(id.eq(key))
+            .first::<LoginFailure>(&conn)
+            .optional()?;
+        Ok(state)
+    }
+
+    fn record_failure(&self, key: &str) -> Result<LoginFailure, ServiceError> {
+        use crate::schema::login_failures::dsl::*;
+        let conn = self.pool.get().unwrap();
+        conn.transaction(|| {
+            let current = login_failures
+                .filter(id.eq(key))
+                .first::<LoginFailure>(&conn)
+                .optional()?;
+            let state = add_failure(key, current, Utc::now().naive_utc());
+            diesel::replace_into(login_failures)
+                .values(&state)
+                .execute(&conn)?;
+            Ok(state)
+        })
+    }
+
+    fn clear_failures(&self, key: &str) -> Result<(), ServiceError> {
+        use crate::schema::login_failures::dsl::*;
+        let conn = self.pool.get().unwrap();
+        diesel::delete(login_failures.filter(id.eq(key))).execute(&conn)?;
+        Ok(())
+    }
+}
+
+/// Picks the store named by `RATE_LIMIT_STORE`: `memory` (the default) or
+/// `sqlite`.
+pub fn limiter_from_env(pool: SqlPool) -> Limiter {
+    match env::var("RATE_LIMIT_STORE").unwrap_or_else(|_| "memory".into()).as_str() {
+        "memory" => Arc::new(MemoryStore::default()),
+        "sqlite" => Arc::new(SqliteStore { pool }),
+        other => panic!("Unknown rate limit store '{}'", other),
+    }
+}
+
+/// Rate limits the wrapped routes per client address and hands the
+/// per-account limits on to the handlers through `AccountGuard`.
+pub struct RateLimit {
+    limits: Limits,
+}
+
+impl RateLimit {
+    pub fn new(limits: Limits) -> Self {
+        RateLimit { limits }
+    }
+}
+
+impl<S, B> Transform<S> for RateLimit
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RateLimitMiddleware<S>;
+    type Future = FutureResult<Self::Transform, Self::InitError>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RateLimitMiddleware {
+            service: Rc::new(RefCell::new(service)),
+            limits: self.limits,
+        })
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    service: Rc<RefCell<S>>,
+    limits: Limits,
+}
+
+impl<S, B> Service for RateLimitMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.service.borrow_mut().poll_ready()
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let store = match req.app_data::<Limiter>() {
+            Some(store) => store.get_ref().clone(),
+            None => return Box::new(self.service.borrow_mut().call(req)),
+        };
+        let limits = self.limits;
+        req.extensions_mut().insert(AccountGuard {
+            limited: Some((store.clone(), limits)),
+        });
+        let client = client_ip(req.peer_addr(), req.headers()).unwrap_or_default();
+        let key = format!("{}:ip:{}", limits.name, client);
+        let service = self.service.clone();
+        Box::new(
+            web::block(move || store.take(&key, &limits.per_ip)).then(move |res| match res {
+                Ok(None) => Either::A(service.borrow_mut().call(req)),
+                Ok(Some(wait)) => Either::B(ok(req.error_response(ServiceError::TooManyRequests(wait)))),
+                Err(BlockingError::Error(service_error)) => Either::B(ok(req.error_response(service_error))),
+                Err(BlockingError::Canceled) => {
+                    Either::B(ok(req.error_response(ServiceError::InternalServerError)))
+                }
+            }),
+        )
+    }
+}
+
+/// The per-account limits of the route group a request came through.
+/// Outside of a `RateLimit` every check passes.
+#[derive(Clone, Default)]
+pub struct AccountGuard {
+    limited: Option<(Limiter, Limits)>,
+}
+
+impl AccountGuard {
+    pub fn of(req: &HttpRequest) -> Self {
+        req.extensions().get::<AccountGuard>().cloned().unwrap_or_default()
+    }
+
+    fn bucket_key(limits: &Limits, account: &str) -> String {
+        format!("{}:account:{}", limits.name, account.trim().to_lowercase())
+    }
+
+    /// Failures count against the account whichever route they came
+    /// through.
+    fn lockout_key(account: &str) -> String {
+        format!("lockout:{}", account.trim().to_lowercase())
+    }
+
+    /// Refuses a request for `account` while it is locked out or its bucket
+    /// is empty.
+    pub fn check(&self, account: &str) -> Result<(), ServiceError> {
+        let store = match &self.limited {
+            Some((store, _)) => store,
+            None => return Ok(()),
+        };
+        let now = Utc::now().naive_utc();
+        if let Some(until) = store
+            .failures(&Self::lockout_key(account))?
+            .and_then(|failures| failures.locked_until)
+        {
+            if until > now {
+                return Err(ServiceError::TooManyRequests(seconds_until(until, now)));
+            }
+        }
+        self.throttle(account)
+    }
+
+    /// Refuses a request for `account` while its bucket is empty, locked
+    /// out or not.
+    pub fn throttle(&self, account: &str) -> Result<(), ServiceError> {
+        let (store, limits) = match &self.limited {
+            Some(limited) => limited,
+            None => return Ok(()),
+        };
+        match store.take(&Self::bucket_key(limits, account), &limits.per_account)? {
+            Some(wait) => Err(ServiceError::TooManyRequests(wait)),
+            None => Ok(()),
+        }
+    }
+
+    /// Runs a credential check for `account`. Rejected credentials count
+    /// towards a lockout, accepted ones reset the count.
+    pub fn attempt<T>(
+        &self,
+        account: &str,
+        verify: impl FnOnce() -> Result<T, ServiceError>,
+    ) -> Result<T, ServiceError> {
+        let verified = self.count_failures(account, verify)?;
+        self.succeeded(account)?;
+        Ok(verified)
+    }
+
+    /// Like `attempt`, but accepted credentials leave the count alone: for a
+    /// check that a second one may still follow, or for guesses that no
+    /// success should wipe out. `succeeded` resets it.
+    pub fn count_failures<T>(
+        &self,
+        account: &str,
+        verify: impl FnOnce() -> Result<T, ServiceError>,
+    ) -> Result<T, ServiceError> {
+        self.check(account)?;
+        let store = match &self.limited {
+            Some((store, _)) => store,
+            None => return verify(),
+        };
+        match verify() {
+            Err(ServiceError::Unauthorized) => {
+                store.record_failure(&Self::lockout_key(account))?;
+                Err(ServiceError::Unauthorized)
+            }
+            verified => verified,
+        }
+    }
+
+    /// Resets the failures of `account` once all of its checks passed.
+    pub fn succeeded(&self, account: &str) -> Result<(), ServiceError> {
+        match &self.limited {
+            Some((store, _)) => store.clear_failures(&Self::lockout_key(account)),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(seconds: i64) -> NaiveDateTime {
+        NaiveDateTime::from_timestamp(1_500_000_000 + seconds, 0)
+    }
+
+    const BUCKET: Bucket = Bucket::new(3, std::time::Duration::from_secs(10));
+
+    #[test]
+    fn bucket_allows_a_burst_then_waits() {
+        let mut state = None;
+        for _ in 0..3 {
+            let (next, wait) = BUCKET.take("key", state, at(0));
+            assert_eq!(wait, None);
+            state = Some(next);
+        }
+        let (next, wait) = BUCKET.take("key", state, at(0));
+        assert_eq!(wait, Some(10));
+        let (_, wait) = BUCKET.take("key", Some(next), at(4));
+        assert_eq!(wait, Some(6));
+    }
+
+    #[test]
+    fn bucket_refills_over_time_up_to_capacity() {
+        let (state, _) = BUCKET.take("key", None, at(0));
+        let (state, _) = BUCKET.take("key", Some(state), at(0));
+        let (state, _) = BUCKET.take("key", Some(state), at(0));
+        let (state, wait) = BUCKET.take("key", Some(state), at(10));
+        assert_eq!(wait, None);
+        assert!(state.tokens < 1.0);
+        // a long break only fills the bucket, not beyond
+        let (state, _) = BUCKET.take("key", Some(state), at(1000));
+        assert!((state.tokens - 2.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn bucket_ignores_clocks_going_backwards() {
+        let (state, _) = BUCKET.take("key", None, at(100));
+        let (state, wait) = BUCKET.take("key", Some(state), at(50));
+        assert_eq!(wait, None);
+        assert!((state.tokens - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn failures_lock_out_after_the_threshold() {
+        let mut state = None;
+        for n in 1..FAILURES_BEFORE_LOCKOUT {
+            let next = add_failure("key", state, at(0));
+            assert_eq!(next.failures, n);
+            assert_eq!(next.locked_until, None);
+            state = Some(next);
+        }
+        let locked = add_failure("key", state, at(0));
+        assert_eq!(locked.locked_until, Some(at(FIRST_LOCKOUT_SECONDS)));
+    }
+
+    #[test]
+    fn lockouts_double_up_to_the_maximum() {
+        let mut state = None;
+        for _ in 0..FAILURES_BEFORE_LOCKOUT {
+            state = Some(add_failure("key", state, at(0)));
+        }
+        let next = add_failure("key", state, at(0));
+        assert_eq!(next.locked_until, Some(at(2 * FIRST_LOCKOUT_SECONDS)));
+        let mut state = Some(next);
+        for _ in 0..40 {
+            state = Some(add_failure("key", state, at(0)));
+        }
+        assert_eq!(state.unwrap().locked_until, Some(at(MAX_LOCKOUT_SECONDS)));
+    }
+
+    #[test]
+    fn failures_are_forgotten_after_a_quiet_day() {
+        let mut state = None;
+        for _ in 0..FAILURES_BEFORE_LOCKOUT {
+            state = Some(add_failure("key", state, at(0)));
+        }
+        let later = add_failure("key", state, at(FAILURE_MEMORY_HOURS * 3600));
+        assert_eq!(later.failures, 1);
+        assert_eq!(later.locked_until, None);
+    }
+
+    fn guard() -> AccountGuard {
+        let per_account = Bucket::new(100, std::time::Duration::from_secs(1));
+        AccountGuard {
+            limited: Some((
+                Arc::new(MemoryStore::default()),
+                Limits { name: "test", per_ip: per_account, per_account },
+            )),
+        }
+    }
+
+    fn locked(result: Result<(), ServiceError>) -> bool {
+        matches!(result, Err(ServiceError::TooManyRequests(_)))
+    }
+
+    fn reject() -> Result<(), ServiceError> {
+        Err(ServiceError::Unauthorized)
+    }
+
+    #[test]
+    fn only_a_full_success_resets_the_failures() {
+        let guard = guard();
+        for _ in 0..FAILURES_BEFORE_LOCKOUT - 1 {
+            assert!(guard.attempt("a@x.io", reject).is_err());
+        }
+        // a first factor that passes leaves the count alone
+        assert!(guard.count_failures("a@x.io", || Ok(())).is_ok());
+        assert!(guard.count_failures("A@x.io ", reject).is_err());
+        assert!(locked(guard.check("a@x.io")));
+        assert!(guard.succeeded("a@x.io").is_ok());
+        assert!(guard.check("a@x.io").is_ok());
+    }
+
+    #[test]
+    fn a_successful_attempt_resets_the_failures() {
+        let guard = guard();
+        for _ in 0..FAILURES_BEFORE_LOCKOUT - 1 {
+            assert!(guard.attempt("a@x.io", reject).is_err());
+        }
+        assert!(guard.attempt("a@x.io", || Ok(())).is_ok());
+        assert!(guard.attempt("a@x.io", reject).is_err());
+        assert!(guard.check("a@x.io").is_ok());
+    }
+
+    #[test]
+    fn other_errors_do_not_count() {
+        let guard = guard();
+        for _ in 0..FAILURES_BEFORE_LOCKOUT + 1 {
+            let failed = guard.attempt("a@x.io", || -> Result<(), ServiceError> {
+                Err(ServiceError::BadRequest(String::from("no")))
+            });
+            assert!(failed.is_err());
+        }
+        assert!(guard.check("a@x.io").is_ok());
+    }
+
+    #[test]
+    fn waits_round_up_to_whole_seconds() {
+        let now = at(0);
+        assert_eq!(seconds_until(now + Duration::milliseconds(1500), now), 2);
+        assert_eq!(seconds_until(now, now), 1);
+    }
+}
@@ -0,0 +1,255 @@
+use actix::prelude::*;
+use actix_web_actors::ws;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use crate::models::Note;
+
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Change inside a group, pushed to every connected member as JSON.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum GroupEvent {
+    NoteCreated { group_id: String, note: Note },
+    NoteUpdated { group_id: String, note: Note },
+    NoteDeleted { group_id: String, note_id: String },
+    MemberJoined { group_id: String, user_id: String, name: String },
+    MemberLeft { group_id: String, user_id: String },
+    /// The group went to the trash or is gone for good. Sockets stop
+    /// listening to it.
+    GroupDeleted { group_id: String },
+    /// The group came back from the trash. The sockets of `members` listen
+    /// to it again.
+    GroupRestored {
+        group_id: String,
+        #[serde(skip)]
+        members: Vec<String>,
+    },
+}
+
+impl GroupEvent {
+    fn group_id(&self) -> &str {
+        match self {
+            GroupEvent::NoteCreated { group_id, .. }
+            | GroupEvent::NoteUpdated { group_id, .. }
+            | GroupEvent::NoteDeleted { group_id, .. }
+            | GroupEvent::MemberJoined { group_id, .. }
+            | GroupEvent::MemberLeft { group_id, .. }
+            | GroupEvent::GroupDeleted { group_id }
+            | GroupEvent::GroupRestored { group_id, .. } => group_id,
+        }
+    }
+}
+
+#[derive(Message)]
+pub struct Push(pub String);
+
+#[derive(Message)]
+#[rtype(usize)]
+pub struct Connect {
+    pub user_id: String,
+    pub group_ids: Vec<String>,
+    pub addr: Recipient<Push>,
+}
+
+#[derive(Message)]
+pub struct Disconnect(pub usize);
+
+#[derive(Message)]
+pub struct Publish(pub GroupEvent);
+
+struct Subscriber {
+    user_id: String,
+    group_ids: HashSet<String>,
+    addr: Recipient<Push>,
+}
+
+/// Keeps track of open sockets and the groups each one listens to.
+#[derive(Default)]
+pub struct Hub {
+    next_id: usize,
+    subscribers: HashMap<usize, Subscriber>,
+}
+
+impl Actor for Hub {
+    type Context = Context<Self>;
+}
+
+impl Handler<Connect> for Hub {
+    type Result = usize;
+
+    fn handle(&mut self, msg: Connect, _: &mut Context<Self>) -> usize {
+        self.next_id += 1;
+        self.subscribers.insert(
+            self.next_id,
+            Subscriber {
+                user_id: msg.user_id,
+                group_ids: msg.group_ids.into_iter().collect(),
+                addr: msg.addr,
+            },
+        );
+        self.next_id
+    }
+}
+
+impl Handler<Disconnect> for Hub {
+    type Result = ();
+
+    fn handle(&mut self, msg: Disconnect, _: &mut Context<Self>) {
+        self.subscribers.remove(&msg.0);
+    }
+}
+
+impl Handler<Publish> for Hub {
+    type Result = ();
+
+    fn handle(&mut self, msg: Publish, _: &mut Context<Self>) {
+        let event = msg.0;
+        let group_id = event.group_id().to_string();
+        // open sockets of a new member start listening right away
+        if let GroupEvent::MemberJoined { user_id, .. } = &event {
+            for subscriber in self.subscribers.values_mut() {
+                if &subscriber.user_id == user_id {
+                    subscriber.group_ids.insert(group_id.clone());
+                }
+            }
+        }
+        if let GroupEvent::GroupRestored { members, .. } = &event {
+            for subscriber in self.subscribers.values_mut() {
+                if members.contains(&subscriber.user_id) {
+                    subscriber.group_ids.insert(group_id.clone());
+                }
+            }
+        }
+        let payload = serde_json::to_string(&event).unwrap();
+        for subscriber in self.subscribers.values() {
+            if subscriber.group_ids.contains(&group_id) {
+                let _ = subscriber.addr.do_send(Push(payload.clone()));
+            }
+        }
+        // a leaving member still gets their own member_left
+        if let GroupEvent::MemberLeft { user_id, .. } = &event {
+            for subscriber in self.subscribers.values_mut() {
+                if &subscriber.user_id == user_id {
+                    subscriber.group_ids.remove(&group_id);
+                }
+            }
+        }
+        if let GroupEvent::GroupDeleted { .. } = &event {
+            for subscriber in self.subscribers.values_mut() {
+                subscriber.group_ids.remove(&group_id);
+            }
+        }
+    }
+}
+
+/// Publishes the events for a note that was saved. `previous_group` is the
+/// group the note was in before, so moving a note between groups shows up as
+/// a deletion in one and a creation in the other.
+pub fn note_saved(hub: &Addr<Hub>, previous_group: Option<String>, note: &Note) {
+    if let Some(previous) = &previous_group {
+        if note.group_id.as_ref() != Some(previous) {
+            hub.do_send(Publish(GroupEvent::NoteDeleted {
+                group_id: previous.clone(),
+                note_id: note.id.clone(),
+            }));
+        }
+    }
+    if let Some(group_id) = &note.group_id {
+        let event = if previous_group.as_ref() == Some(group_id) {
+            GroupEvent::NoteUpdated { group_id: group_id.clone(), note: note.clone() }
+        } else {
+            GroupEvent::NoteCreated { group_id: group_id.clone(), note: note.clone() }
+        };
+        hub.do_send(Publish(event));
+    }
+}
+
+pub fn note_deleted(hub: &Addr<Hub>, note: &Note) {
+    if let Some(group_id) = &note.group_id {
+        hub.do_send(Publish(GroupEvent::NoteDeleted {
+            group_id: group_id.clone(),
+            note_id: note.id.clone(),
+        }));
+    }
+}
+
+/// One client connection. Only pushes events; anything the client sends
+/// apart from pings and close frames is ignored.
+pub struct WsSession {
+    id: usize,
+    user_id: String,
+    group_ids: Vec<String>,
+    heartbeat: Instant,
+    hub: Addr<Hub>,
+}
+
+impl WsSession {
+    pub fn new(user_id: String, group_ids: Vec<String>, hub: Addr<Hub>) -> Self {
+        WsSession {
+            id: 0,
+            user_id,
+            group_ids,
+            heartbeat: Instant::now(),
+            hub,
+        }
+    }
+}
+
+impl Actor for WsSession {
+    type Context = ws::WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(HEARTBEAT_INTERVAL, |session, ctx| {
+            if Instant::now().duration_since(session.heartbeat) > CLIENT_TIMEOUT {
+                ctx.stop();
+                return;
+            }
+            ctx.ping("");
+        });
+        self.hub
+            .send(Connect {
+                user_id: self.user_id.clone(),
+                group_ids: std::mem::take(&mut self.group_ids),
+                addr: ctx.address().recipient(),
+            })
+            .into_actor(self)
+            .then(|res, session, ctx| {
+                match res {
+                    Ok(id) => session.id = id,
+                    Err(_) => ctx.stop(),
+                }
+                fut::ok(())
+            })
+            .wait(ctx);
+    }
+
+    fn stopping(&mut self, _: &mut Self::Context) -> Running {
+        self.hub.do_send(Disconnect(self.id));
+        Running::Stop
+    }
+}
+
+impl Handler<Push> for WsSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: Push, ctx: &mut Self::Context) {
+        ctx.text(msg.0);
+    }
+}
+
+impl StreamHandler<ws::Message, ws::ProtocolError> for WsSession {
+    fn handle(&mut self, msg: ws::Message, ctx: &mut Self::Context) {
+        match msg {
+            ws::Message::Ping(msg) => {
+                self.heartbeat = Instant::now();
+                ctx.pong(&msg);
+            }
+            ws::Message::Pong(_) => self.heartbeat = Instant::now(),
+            ws::Message::Close(_) => ctx.stop(),
+            _ => {}
+        }
+    }
+}
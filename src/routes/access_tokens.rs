@@ -0,0 +1,130 @@
+use actix_web::{error::BlockingError, web, HttpResponse};
+use chrono::{NaiveDateTime, Utc};
+use diesel::prelude::*;
+use diesel::r2d2::ConnectionManager;
+use futures::Future;
+use r2d2::Pool;
+use uuid::Uuid;
+
+use crate::errors::ServiceError;
+use crate::models::{CreatedAccessToken, LoggedUser, NewAccessToken, PersonalAccessToken};
+use crate::policy::Scope;
+use crate::routes::auth::PERSONAL_TOKEN_PREFIX;
+use crate::tokens;
+
+type SqlPool = Pool<ConnectionManager<SqliteConnection>>;
+
+/// Lists the user's tokens. Tokens that ended with a password change or a
+/// sign out everywhere stay listed with their `revoked_reason` until the user
+/// removes them.
+pub fn list(
+    user: LoggedUser,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    use crate::schema::personal_access_tokens::dsl::*;
+    web::block(move || -> Result<Vec<PersonalAccessToken>, ServiceError> {
+        let conn = pool.get().unwrap();
+        let token_list = personal_access_tokens
+            .filter(user_id.eq(&user.id))
+            .filter(revoked_at.is_null().or(revoked_reason.is_not_null()))
+            .order(created_at.desc())
+            .load::<PersonalAccessToken>(&conn)?;
+        Ok(token_list)
+    })
+    .then(|res| match res {
+        Ok(t) => Ok(HttpResponse::Ok().json(t)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
+
+/// Creates a token. The response holds the secret, which can't be looked
+/// up again later.
+pub fn insert(
+    user: LoggedUser,
+    new_token: web::Json<NewAccessToken>,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    use crate::schema::personal_access_tokens::dsl::*;
+    web::block(move || -> Result<CreatedAccessToken, ServiceError> {
+        let conn = pool.get().unwrap();
+        let new_token = new_token.into_inner();
+        let token_name = new_token.name.trim().to_string();
+        if token_name.is_empty() {
+            return Err(ServiceError::BadRequest(String::from("Name can't be empty!")));
+        }
+        let mut granted = Vec::new();
+        for requested in &new_token.scopes {
+            let scope = Scope::parse(requested)
+                .ok_or_else(|| ServiceError::BadRequest(format!("Unknown scope '{}'", requested)))?;
+            if !granted.contains(&scope) {
+                granted.push(scope);
+            }
+        }
+        if granted.is_empty() {
+            return Err(ServiceError::BadRequest(String::from("A token needs at least one scope!")));
+        }
+        let expiry = match new_token.expires_at {
+            Some(value) => {
+                let expiry = NaiveDateTime::parse_from_str(&value, "%Y-%m-%d %H:%M:%S")
+                    .map_err(|_| ServiceError::BadRequest(format!(
+                        "Invalid date '{}', expected YYYY-MM-DD HH:MM:SS",
+                        value
+                    )))?;
+                if expiry <= Utc::now().naive_utc() {
+                    return Err(ServiceError::BadRequest(
+                        String::from("Expiry must be in the future!")
+                    ));
+                }
+                Some(expiry)
+            }
+            None => None,
+        };
+        let secret = format!("{}{}", PERSONAL_TOKEN_PREFIX, tokens::generate());
+        let token = PersonalAccessToken::from(&user, token_name, &granted, expiry, tokens::hash(&secret));
+        diesel::insert_into(personal_access_tokens)
+            .values(&token)
+            .execute(&conn)?;
+        Ok(CreatedAccessToken { token, secret })
+    })
+    .then(|res| match res {
+        Ok(t) => Ok(HttpResponse::Ok().json(t)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
+
+/// Revokes a token, or removes one that was revoked along with the sessions
+/// from the list.
+pub fn revoke(
+    user: LoggedUser,
+    token_id: web::Path<Uuid>,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    use crate::schema::personal_access_tokens::dsl::*;
+    web::block(move || -> Result<(), ServiceError> {
+        let conn = pool.get().unwrap();
+        let token = personal_access_tokens
+            .filter(id.eq(token_id.into_inner().to_string()))
+            .filter(user_id.eq(&user.id))
+            .first::<PersonalAccessToken>(&conn)
+            .optional()?
+            .ok_or(ServiceError::Forbidden)?;
+        let revoked = token.revoked_at.unwrap_or_else(|| Utc::now().naive_utc());
+        diesel::update(&token)
+            .set((revoked_at.eq(revoked), revoked_reason.eq(None::<String>)))
+            .execute(&conn)?;
+        Ok(())
+    })
+    .then(|res| match res {
+        Ok(_) => Ok(HttpResponse::Ok().finish()),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
@@ -0,0 +1,408 @@
+use actix_multipart::{Field, Multipart};
+use actix_web::{error::BlockingError, http::header, web, HttpRequest, HttpResponse};
+use diesel::prelude::*;
+use diesel::r2d2::ConnectionManager;
+use futures::future::{err, Either};
+use futures::{Future, Stream};
+use r2d2::Pool;
+use uuid::Uuid;
+
+use crate::errors::ServiceError;
+use crate::models::{Attachment, LoggedUser};
+use crate::policy::{editable_note, visible_note};
+use crate::storage::Storage;
+use crate::trash::drop_unused_blobs;
+
+type SqlPool = Pool<ConnectionManager<SqliteConnection>>;
+
+/// Largest accepted upload, all of its files together, matching the payload
+/// limit set up in `main`.
+const MAX_UPLOAD_SIZE: usize = 1 << 25;
+/// Most multipart fields read from one upload, files or not.
+const MAX_UPLOAD_FIELDS: usize = 20;
+
+/// Types browsers may show in place. They can't run script on our origin,
+/// everything else is always downloaded.
+const INLINE_TYPES: [&str; 9] = [
+    "image/png",
+    "image/jpeg",
+    "image/gif",
+    "image/webp",
+    "application/pdf",
+    "text/plain",
+    "audio/mpeg",
+    "audio/ogg",
+    "video/mp4",
+];
+
+struct Upload {
+    filename: String,
+    mime_type: String,
+    data: web::BytesMut,
+}
+
+/// Buffers one multipart field of at most `limit` bytes and answers its size
+/// too. Fields without a filename are form values rather than files and are
+/// dropped.
+fn read_field(field: Field, limit: usize) -> impl Future<Item = (Option<Upload>, usize), Error = ServiceError> {
+    let filename = field
+        .content_disposition()
+        .and_then(|disposition| disposition.get_filename().map(String::from));
+    let mime_type = field.content_type().to_string();
+    field
+        .map_err(|err| ServiceError::BadRequest(err.to_string()))
+        .fold(web::BytesMut::new(), move |mut data, chunk| {
+            if data.len() + chunk.len() > limit {
+                return Err(ServiceError::BadRequest(
+                    String::from("Attachments are too large!")
+                ));
+            }
+            data.extend_from_slice(&chunk);
+            Ok(data)
+        })
+        .map(move |data| {
+            let size = data.len();
+            (filename.map(|filename| Upload { filename, mime_type, data }), size)
+        })
+}
+
+/// Reads the files of an upload one field after the other, stopping as soon
+/// as there are too many fields or bytes.
+fn read_uploads(multipart: Multipart) -> impl Future<Item = Vec<Upload>, Error = ServiceError> {
+    multipart
+        .map_err(|err| ServiceError::BadRequest(err.to_string()))
+        .fold((Vec::new(), 0, 0), |(mut uploads, fields, total), field| {
+            if fields == MAX_UPLOAD_FIELDS {
+                return Either::A(err(ServiceError::BadRequest(format!(
+                    "At most {} files can be uploaded at once!",
+                    MAX_UPLOAD_FIELDS
+                ))));
+            }
+            Either::B(read_field(field, MAX_UPLOAD_SIZE - total).map(move |(upload, size)| {
+                uploads.extend(upload);
+                (uploads, fields + 1, total + size)
+            }))
+        })
+        .map(|(uploads, _, _)| uploads)
+}
+
+/// Parses a single `bytes=` range against a blob of `size` bytes into an
+/// inclusive `(start, end)` pair. Returns `Ok(None)` when the header should be
+/// ignored and `Err(())` when the range cannot be satisfied.
+fn parse_range(value: &str, size: u64) -> Result<Option<(u64, u64)>, ()> {
+    let value = value.trim();
+    if !value.starts_with("bytes=") {
+        return Ok(None);
+    }
+    let spec = &value["bytes=".len()..];
+    if spec.contains(',') {
+        // multipart/byteranges responses are not supported, send everything
+        return Ok(None);
+    }
+    let mut bounds = spec.splitn(2, '-');
+    let first = bounds.next().unwrap_or("").trim();
+    let last = bounds.next().unwrap_or("").trim();
+    let (start, end) = match (first.is_empty(), last.is_empty()) {
+        (false, _) => {
+            let start = first.parse::<u64>().map_err(|_| ())?;
+            let end = match last.parse::<u64>() {
+                Ok(end) => end.min(size.saturating_sub(1)),
+                Err(_) if last.is_empty() => size.saturating_sub(1),
+                Err(_) => return Ok(None),
+            };
+            (start, end)
+        }
+        (true, false) => {
+            let suffix = last.parse::<u64>().map_err(|_| ())?;
+            if suffix == 0 {
+                return Err(());
+            }
+            (size.saturating_sub(suffix), size.saturating_sub(1))
+        }
+        (true, true) => return Ok(None),
+    };
+    if size == 0 || start >= size || start > end {
+        return Err(());
+    }
+    Ok(Some((start, end)))
+}
+
+pub fn list(
+    user: LoggedUser,
+    uuid: web::Path<Uuid>,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    use crate::schema::attachments::dsl::*;
+    web::block(move || -> Result<Vec<Attachment>, ServiceError> {
+        let conn = pool.get().unwrap();
+        let note = visible_note(&conn, &user, &uuid.into_inner().to_string())?;
+        let attachment_list = Attachment::belonging_to(&note)
+            .order(created_at.asc())
+            .load::<Attachment>(&conn)?;
+        Ok(attachment_list)
+    })
+    .then(|res| match res {
+        Ok(t) => Ok(HttpResponse::Ok().json(t)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
+
+pub fn upload(
+    user: LoggedUser,
+    uuid: web::Path<Uuid>,
+    multipart: Multipart,
+    pool: web::Data<SqlPool>,
+    storage: web::Data<Storage>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    use crate::schema::attachments::dsl::*;
+    let target = uuid.into_inner().to_string();
+    let check_pool = pool.clone();
+    let check_user = user.clone();
+    web::block(move || editable_note(&check_pool.get().unwrap(), &check_user, &target))
+        .map_err(|err| match err {
+            BlockingError::Error(service_error) => service_error,
+            BlockingError::Canceled => ServiceError::InternalServerError,
+        })
+        .and_then(move |note| {
+            read_uploads(multipart).map(|uploads| (note, uploads))
+        })
+        .and_then(move |(note, uploads)| {
+            web::block(move || -> Result<Vec<Attachment>, ServiceError> {
+                if uploads.is_empty() {
+                    return Err(ServiceError::BadRequest(
+                        String::from("No files found in the request!")
+                    ));
+                }
+                let conn = pool.get().unwrap();
+                let mut stored = Vec::with_capacity(uploads.len());
+                for upload in uploads {
+                    let digest = storage.put(&upload.data).map_err(|err| {
+                        println!("Storing attachment {} failed: {}", upload.filename, err);
+                        ServiceError::InternalServerError
+                    })?;
+                    let attachment = Attachment::from(
+                        &note,
+                        &user,
+                        upload.filename,
+                        upload.mime_type,
+                        upload.data.len() as i64,
+                        digest,
+                    );
+                    diesel::insert_into(attachments)
+                        .values(&attachment)
+                        .execute(&conn)?;
+                    stored.push(attachment);
+                }
+                Ok(stored)
+            })
+            .then(|res| match res {
+                Ok(t) => Ok(HttpResponse::Ok().json(t)),
+                Err(err) => match err {
+                    BlockingError::Error(service_error) => Err(service_error),
+                    BlockingError::Canceled => Err(ServiceError::InternalServerError),
+                },
+            })
+        })
+}
+
+/// `Content-Disposition` for serving `attachment`. The filename comes from
+/// the uploader, so quotes, backslashes and control characters are dropped.
+fn content_disposition(attachment: &Attachment) -> String {
+    let essence = attachment.mime_type.split(';').next().unwrap_or("").trim().to_lowercase();
+    let kind = if INLINE_TYPES.contains(&essence.as_str()) { "inline" } else { "attachment" };
+    let filename: String = attachment
+        .filename
+        .chars()
+        .filter(|c| !c.is_control() && *c != '"' && *c != '\\')
+        .collect();
+    format!("{}; filename=\"{}\"", kind, filename)
+}
+
+/// What `download` found in the database and storage, turned into a
+/// response once back on the event loop.
+enum Download {
+    Unsatisfiable(u64),
+    Content {
+        attachment: Attachment,
+        range: Option<(u64, u64)>,
+        data: Vec<u8>,
+    },
+}
+
+impl Download {
+    fn into_response(self) -> HttpResponse {
+        match self {
+            Download::Unsatisfiable(total) => HttpResponse::RangeNotSatisfiable()
+                .header(header::CONTENT_RANGE, format!("bytes */{}", total))
+                .finish(),
+            Download::Content { attachment, range, data } => {
+                let mut response = match range {
+                    Some((start, end)) => {
+                        let mut partial = HttpResponse::PartialContent();
+                        partial.header(
+                            header::CONTENT_RANGE,
+                            format!("bytes {}-{}/{}", start, end, attachment.size),
+                        );
+                        partial
+                    }
+                    None => HttpResponse::Ok(),
+                };
+                response
+                    .content_type(attachment.mime_type.as_str())
+                    .header(header::ACCEPT_RANGES, "bytes")
+                    .header(header::X_CONTENT_TYPE_OPTIONS, "nosniff")
+                    .header(header::CONTENT_DISPOSITION, content_disposition(&attachment))
+                    .body(data)
+            }
+        }
+    }
+}
+
+pub fn download(
+    req: HttpRequest,
+    user: LoggedUser,
+    path: web::Path<(Uuid, Uuid)>,
+    pool: web::Data<SqlPool>,
+    storage: web::Data<Storage>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    use crate::schema::attachments::dsl::*;
+    let range_header = req
+        .headers()
+        .get(header::RANGE)
+        .and_then(|value| value.to_str().ok())
+        .map(String::from);
+    web::block(move || -> Result<Download, ServiceError> {
+        let conn = pool.get().unwrap();
+        let (note_uuid, attachment_uuid) = path.into_inner();
+        let note = visible_note(&conn, &user, &note_uuid.to_string())?;
+        let attachment = Attachment::belonging_to(&note)
+            .filter(id.eq(attachment_uuid.to_string()))
+            .first::<Attachment>(&conn)?;
+        let total = attachment.size as u64;
+        let range = match &range_header {
+            Some(value) => match parse_range(value, total) {
+                Ok(range) => range,
+                Err(_) => return Ok(Download::Unsatisfiable(total)),
+            },
+            None => None,
+        };
+        let (start, end) = range.unwrap_or((0, total.saturating_sub(1)));
+        let data = storage
+            .read_range(&attachment.sha256, start, end + 1 - start)
+            .map_err(|err| {
+                println!("Reading attachment {} failed: {}", attachment.id, err);
+                ServiceError::InternalServerError
+            })?;
+        Ok(Download::Content { attachment, range, data })
+    })
+    .then(|res| match res {
+        Ok(t) => Ok(t.into_response()),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
+
+pub fn delete(
+    user: LoggedUser,
+    path: web::Path<(Uuid, Uuid)>,
+    pool: web::Data<SqlPool>,
+    storage: web::Data<Storage>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    use crate::schema::attachments::dsl::*;
+    web::block(move || -> Result<Attachment, ServiceError> {
+        let conn = pool.get().unwrap();
+        let (note_uuid, attachment_uuid) = path.into_inner();
+        let note = editable_note(&conn, &user, &note_uuid.to_string())?;
+        let attachment = Attachment::belonging_to(&note)
+            .filter(id.eq(attachment_uuid.to_string()))
+            .first::<Attachment>(&conn)?;
+        diesel::delete(&attachment).execute(&conn)?;
+        drop_unused_blobs(&conn, &storage, std::slice::from_ref(&attachment.sha256));
+        Ok(attachment)
+    })
+    .then(|res| match res {
+        Ok(t) => Ok(HttpResponse::Ok().json(t)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{content_disposition, parse_range};
+    use crate::models::Attachment;
+
+    fn attachment(filename: &str, mime_type: &str) -> Attachment {
+        Attachment {
+            id: String::new(),
+            note_id: String::new(),
+            user_id: String::new(),
+            filename: filename.to_string(),
+            mime_type: mime_type.to_string(),
+            size: 0,
+            sha256: String::new(),
+            created_at: chrono::Utc::now().naive_utc(),
+        }
+    }
+
+    #[test]
+    fn shows_only_safe_types_inline() {
+        assert_eq!(content_disposition(&attachment("a.png", "image/png")), "inline; filename=\"a.png\"");
+        assert_eq!(
+            content_disposition(&attachment("a.txt", "Text/Plain; charset=utf-8")),
+            "inline; filename=\"a.txt\""
+        );
+        assert_eq!(content_disposition(&attachment("a.html", "text/html")), "attachment; filename=\"a.html\"");
+        assert_eq!(content_disposition(&attachment("a.svg", "image/svg+xml")), "attachment; filename=\"a.svg\"");
+    }
+
+    #[test]
+    fn strips_header_breaking_characters_from_filenames() {
+        assert_eq!(
+            content_disposition(&attachment("a\"b\\c\r\nSet-Cookie: x\u{7f}.bin", "application/zip")),
+            "attachment; filename=\"abcSet-Cookie: x.bin\""
+        );
+    }
+
+    #[test]
+    fn parses_closed_and_open_ranges() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Ok(Some((0, 99))));
+        assert_eq!(parse_range(" bytes=10-10 ", 1000), Ok(Some((10, 10))));
+        assert_eq!(parse_range("bytes=900-", 1000), Ok(Some((900, 999))));
+    }
+
+    #[test]
+    fn clamps_the_end_to_the_size() {
+        assert_eq!(parse_range("bytes=900-5000", 1000), Ok(Some((900, 999))));
+    }
+
+    #[test]
+    fn parses_suffix_ranges() {
+        assert_eq!(parse_range("bytes=-100", 1000), Ok(Some((900, 999))));
+        assert_eq!(parse_range("bytes=-5000", 1000), Ok(Some((0, 999))));
+        assert_eq!(parse_range("bytes=-0", 1000), Err(()));
+    }
+
+    #[test]
+    fn refuses_unsatisfiable_ranges() {
+        assert_eq!(parse_range("bytes=1000-", 1000), Err(()));
+        assert_eq!(parse_range("bytes=50-10", 1000), Err(()));
+        assert_eq!(parse_range("bytes=0-", 0), Err(()));
+        assert_eq!(parse_range("bytes=x-10", 1000), Err(()));
+    }
+
+    #[test]
+    fn ignores_what_it_does_not_support() {
+        assert_eq!(parse_range("items=0-10", 1000), Ok(None));
+        assert_eq!(parse_range("bytes=0-10,20-30", 1000), Ok(None));
+        assert_eq!(parse_range("bytes=-", 1000), Ok(None));
+        assert_eq!(parse_range("bytes=0-x", 1000), Ok(None));
+    }
+}
@@ -1,21 +1,30 @@
-// use crate::email_service::send_mail;
+use crate::email_service::{self, frontend_link, Template};
 use crate::errors::ServiceError;
-use crate::models::{Invitation, LoggedUser, NewUser, User};
+use crate::models::{
+    new_security_stamp, Invitation, LoggedUser, MfaChallenge, NewUser, PasswordReset,
+    PersonalAccessToken, RefreshToken, Session, User,
+};
+use crate::policy::{Scope, TokenAccess};
+use crate::ratelimit::AccountGuard;
+use crate::routes::mfa;
+use crate::tokens;
 
 use actix_identity::Identity;
 use actix_web::{
-    dev::Payload, error::BlockingError, web, Error, FromRequest, HttpRequest, HttpResponse,
+    dev::Payload, error::BlockingError, http::header, web, Error, FromRequest, HttpRequest,
+    HttpResponse,
 };
 use argonautica::{Hasher, Verifier};
 use diesel::prelude::*;
 use diesel::r2d2::ConnectionManager;
-use futures::Future;
+use futures::{future, Future};
+use jsonwebtoken::{decode, encode, Header, Validation};
 use r2d2::Pool;
+use std::env;
+use std::net::{IpAddr, SocketAddr};
+use chrono::{Duration, Utc};
 use uuid::Uuid;
 
-// when mailing is added
-// use std::env;
-
 type SqlPool = Pool<ConnectionManager<SqliteConnection>>;
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -24,17 +33,329 @@ pub struct AuthData {
     pub password: String,
 }
 
-impl FromRequest for LoggedUser {
+/// What the auth cookie and the access tokens point at.
+#[derive(Deserialize, Serialize)]
+struct SessionIdentity {
+    session_id: String,
+}
+
+/// The session a request was made in, with its user. For requests made
+/// with a personal access token `id` is the token's.
+pub struct CurrentSession {
+    pub id: String,
+    pub user: LoggedUser,
+}
+
+/// How stale `last_seen_at` may get before a request updates it.
+const LAST_SEEN_RESOLUTION_MINUTES: i64 = 5;
+/// Lifetime of a browser session, the same as the auth cookie.
+pub const COOKIE_SESSION_HOURS: i64 = 24;
+/// Lifetime of a token session, renewed on every refresh.
+const TOKEN_SESSION_DAYS: i64 = 30;
+
+/// Loads a session that is neither revoked nor expired. The stamp has to
+/// match the user's current security stamp, so resetting the password ends
+/// every session.
+fn live_session(conn: &SqliteConnection, session_id: &str) -> Result<(Session, User), ServiceError> {
+    use crate::schema::sessions::dsl::*;
+    use crate::schema::users::dsl::{id as u_id, users};
+    let session = sessions
+        .filter(id.eq(session_id))
+        .first::<Session>(conn)
+        .optional()?
+        .ok_or(ServiceError::Unauthorized)?;
+    if !session.is_live(Utc::now().naive_utc()) {
+        return Err(ServiceError::Unauthorized);
+    }
+    let user = users
+        .filter(u_id.eq(&session.user_id))
+        .first::<User>(conn)
+        .optional()?
+        .ok_or(ServiceError::Unauthorized)?;
+    if user.security_stamp != session.security_stamp {
+        return Err(ServiceError::Unauthorized);
+    }
+    Ok((session, user))
+}
+
+fn session_user(conn: &SqliteConnection, identity: &SessionIdentity) -> Result<CurrentSession, ServiceError> {
+    use crate::schema::sessions::dsl::*;
+    let (session, user) = live_session(conn, &identity.session_id)?;
+    let now = Utc::now().naive_utc();
+    if now - session.last_seen_at > Duration::minutes(LAST_SEEN_RESOLUTION_MINUTES) {
+        diesel::update(&session).set(last_seen_at.eq(now)).execute(conn)?;
+    }
+    Ok(CurrentSession {
+        id: session.id,
+        user: LoggedUser::from(user),
+    })
+}
+
+/// Where a login comes from, recorded with its session.
+pub struct Device {
+    user_agent: Option<String>,
+    ip_address: Option<String>,
+}
+
+impl Device {
+    pub fn of(req: &HttpRequest) -> Self {
+        let user_agent = req
+            .headers()
+            .get(header::USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .map(String::from);
+        let ip_address = client_ip(req.peer_addr(), req.headers());
+        Device { user_agent, ip_address }
+    }
+}
+
+lazy_static::lazy_static! {
+    /// Proxies, from `TRUSTED_PROXIES` (comma separated), whose
+    /// `X-Forwarded-For` header names the client.
+    static ref TRUSTED_PROXIES: Vec<IpAddr> = env::var("TRUSTED_PROXIES")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|proxy| !proxy.is_empty())
+        .map(|proxy| proxy.parse().expect("TRUSTED_PROXIES must list IP addresses"))
+        .collect();
+}
+
+/// The client's address. Anyone can send `X-Forwarded-For`, so it only
+/// counts when a trusted proxy connected; its last untrusted entry is the
+/// client then.
+pub fn client_ip(peer: Option<SocketAddr>, headers: &header::HeaderMap) -> Option<String> {
+    peer.map(|peer| forwarded_client(peer.ip(), headers, &TRUSTED_PROXIES).to_string())
+}
+
+fn forwarded_client(peer: IpAddr, headers: &header::HeaderMap, trusted: &[IpAddr]) -> IpAddr {
+    if !trusted.contains(&peer) {
+        return peer;
+    }
+    let forwarded: Vec<&str> = headers
+        .get_all("x-forwarded-for")
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .collect();
+    // entries are appended, so the ones on the right come from our proxies
+    forwarded
+        .into_iter()
+        .rev()
+        .map(|entry| entry.parse::<IpAddr>().ok())
+        .find(|ip| ip.map(|ip| !trusted.contains(&ip)).unwrap_or(true))
+        .and_then(|ip| ip)
+        .unwrap_or(peer)
+}
+
+/// Records a new session of `kind` for `user`.
+pub fn start_session(
+    conn: &SqliteConnection,
+    user: &User,
+    kind: &str,
+    device: Device,
+) -> Result<Session, ServiceError> {
+    use crate::schema::sessions::dsl::sessions;
+    let lifetime = match kind {
+        "token" => Duration::days(TOKEN_SESSION_DAYS),
+        _ => Duration::hours(COOKIE_SESSION_HOURS),
+    };
+    let session = Session::from(user, kind, device.user_agent, device.ip_address, lifetime);
+    diesel::insert_into(sessions).values(&session).execute(conn)?;
+    Ok(session)
+}
+
+/// Revokes a session together with its refresh tokens.
+pub fn end_session(conn: &SqliteConnection, session_id: &str) -> Result<(), ServiceError> {
+    use crate::schema::refresh_tokens::dsl as refresh_tokens;
+    use crate::schema::sessions::dsl::*;
+    let now = Utc::now().naive_utc();
+    diesel::update(sessions.filter(id.eq(session_id)).filter(revoked_at.is_null()))
+        .set(revoked_at.eq(now))
+        .execute(conn)?;
+    diesel::update(
+        refresh_tokens::refresh_tokens
+            .filter(refresh_tokens::family_id.eq(session_id))
+            .filter(refresh_tokens::revoked_at.is_null()),
+    )
+    .set(refresh_tokens::revoked_at.eq(now))
+    .execute(conn)?;
+    Ok(())
+}
+
+/// Revokes every session of a user except `keep`, and the personal access
+/// tokens too, since a stolen session could have made them. `reason` is
+/// recorded on the tokens. Returns how many sessions and tokens ended.
+pub fn end_other_sessions(
+    conn: &SqliteConnection,
+    user: &str,
+    keep: Option<&str>,
+    reason: &str,
+) -> Result<(usize, usize), ServiceError> {
+    use crate::schema::personal_access_tokens::dsl as access_tokens;
+    use crate::schema::sessions::dsl::*;
+    let others = sessions
+        .filter(user_id.eq(user))
+        .filter(revoked_at.is_null())
+        .filter(id.ne(keep.unwrap_or("")))
+        .select(id)
+        .load::<String>(conn)?;
+    for session_id in &others {
+        end_session(conn, session_id)?;
+    }
+    let revoked_tokens = diesel::update(
+        access_tokens::personal_access_tokens
+            .filter(access_tokens::user_id.eq(user))
+            .filter(access_tokens::revoked_at.is_null())
+            .filter(access_tokens::id.ne(keep.unwrap_or(""))),
+    )
+    .set((
+        access_tokens::revoked_at.eq(Utc::now().naive_utc()),
+        access_tokens::revoked_reason.eq(reason),
+    ))
+    .execute(conn)?;
+    Ok((others.len(), revoked_tokens))
+}
+
+/// Points the auth cookie at `session`.
+pub fn remember(id: &Identity, session: &Session) {
+    let identity = SessionIdentity {
+        session_id: session.id.clone(),
+    };
+    id.remember(serde_json::to_string(&identity).unwrap());
+}
+
+/// Lifetime of the access tokens handed out by `issue_token`.
+const ACCESS_TOKEN_MINUTES: i64 = 15;
+
+/// Claims of an access token. `sid` names the session, so revoking it
+/// also ends the token.
+#[derive(Deserialize, Serialize)]
+struct AccessClaims {
+    sub: String,
+    sid: String,
+    iat: i64,
+    exp: i64,
+}
+
+fn access_token(session: &Session) -> Result<String, ServiceError> {
+    let now = Utc::now();
+    let claims = AccessClaims {
+        sub: session.user_id.clone(),
+        sid: session.id.clone(),
+        iat: now.timestamp(),
+        exp: (now + Duration::minutes(ACCESS_TOKEN_MINUTES)).timestamp(),
+    };
+    encode(&Header::default(), &claims, SECRET_KEY.as_bytes())
+        .map_err(|_| ServiceError::InternalServerError)
+}
+
+/// Personal access tokens start with this, which tells them apart from
+/// access tokens.
+pub const PERSONAL_TOKEN_PREFIX: &str = "dcp_";
+
+/// What a request proves its user with.
+enum Credential {
+    Session(SessionIdentity),
+    PersonalToken(String),
+}
+
+/// Reads the credential from an `Authorization: Bearer` header. A request
+/// that sends one is judged by it alone, never by the cookie.
+fn bearer_credential(req: &HttpRequest) -> Option<Option<Credential>> {
+    let value = req.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+    if !value.starts_with("Bearer ") {
+        return None;
+    }
+    let token = &value[7..];
+    if token.starts_with(PERSONAL_TOKEN_PREFIX) {
+        return Some(Some(Credential::PersonalToken(token.to_string())));
+    }
+    let session = decode::<AccessClaims>(token, SECRET_KEY.as_bytes(), &Validation::default())
+        .ok()
+        .map(|data| {
+            Credential::Session(SessionIdentity {
+                session_id: data.claims.sid,
+            })
+        });
+    Some(session)
+}
+
+/// Checks a personal access token and that it carries `required`, the scope
+/// of the route it is used on. `None` means the route takes no such tokens.
+fn token_user(
+    conn: &SqliteConnection,
+    secret: &str,
+    required: Option<Scope>,
+) -> Result<CurrentSession, ServiceError> {
+    use crate::schema::personal_access_tokens::dsl::*;
+    use crate::schema::users::dsl::{id as u_id, users};
+    let now = Utc::now().naive_utc();
+    let token = personal_access_tokens
+        .filter(token_hash.eq(tokens::hash(secret)))
+        .first::<PersonalAccessToken>(conn)
+        .optional()?
+        .filter(|token| token.is_usable(now))
+        .ok_or(ServiceError::Unauthorized)?;
+    match required {
+        Some(scope) if token.has_scope(scope) => (),
+        _ => return Err(ServiceError::Forbidden),
+    }
+    let user = users.filter(u_id.eq(&token.user_id)).first::<User>(conn)?;
+    diesel::update(&token).set(last_used_at.eq(now)).execute(conn)?;
+    Ok(CurrentSession {
+        id: token.id,
+        user: LoggedUser::from(user),
+    })
+}
+
+impl FromRequest for CurrentSession {
     type Error = Error;
-    type Future = Result<LoggedUser, Error>;
+    type Future = Box<dyn Future<Item = CurrentSession, Error = Error>>;
     type Config = ();
 
     fn from_request(req: &HttpRequest, pl: &mut Payload) -> Self::Future {
-        if let Some(identity) = Identity::from_request(req, pl)?.identity() {
-            let user: LoggedUser = serde_json::from_str(&identity)?;
-            return Ok(user);
+        let credential = match bearer_credential(req) {
+            Some(credential) => credential,
+            None => {
+                let identity = match Identity::from_request(req, pl) {
+                    Ok(identity) => identity.identity(),
+                    Err(err) => return Box::new(future::err(err)),
+                };
+                identity
+                    .and_then(|identity| serde_json::from_str::<SessionIdentity>(&identity).ok())
+                    .map(Credential::Session)
+            }
+        };
+        let required = req
+            .get_app_data::<TokenAccess>()
+            .map(|access| access.required(req.method()));
+        match (credential, req.get_app_data::<SqlPool>()) {
+            (Some(credential), Some(pool)) => Box::new(
+                web::block(move || {
+                    let conn = pool.get().unwrap();
+                    match credential {
+                        Credential::Session(session) => session_user(&conn, &session),
+                        Credential::PersonalToken(secret) => token_user(&conn, &secret, required),
+                    }
+                })
+                .map_err(|err| match err {
+                    BlockingError::Error(service_error) => service_error.into(),
+                    BlockingError::Canceled => ServiceError::InternalServerError.into(),
+                }),
+            ),
+            _ => Box::new(future::err(ServiceError::Unauthorized.into())),
         }
-        Err(ServiceError::Unauthorized.into())
+    }
+}
+
+impl FromRequest for LoggedUser {
+    type Error = Error;
+    type Future = Box<dyn Future<Item = LoggedUser, Error = Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, pl: &mut Payload) -> Self::Future {
+        Box::new(CurrentSession::from_request(req, pl).map(|session| session.user))
     }
 }
 
@@ -43,13 +364,12 @@ lazy_static::lazy_static! {
 }
 
 pub fn hash_password(password: &str) -> Result<String, ServiceError> {
-    print!("{}", password);
     Hasher::default()
         .with_password(password)
         .with_secret_key(SECRET_KEY.as_str())
         .hash()
         .map_err(|err| {
-            dbg!(err);
+            println!("Hashing a password failed: {:?}", err);
             ServiceError::InternalServerError
         })
 }
@@ -60,39 +380,44 @@ pub fn verify(hash: &str, password: &str) -> Result<bool, ServiceError> {
         .with_password(password)
         .with_secret_key(SECRET_KEY.as_str())
         .verify()
-        .map_err(|err| {
-            dbg!(err);
-            ServiceError::Unauthorized
-        })
+        .map_err(|_| ServiceError::Unauthorized)
 }
 
 pub fn register(
+    req: HttpRequest,
     new_user: web::Json<NewUser>,
     pool: web::Data<SqlPool>,
 ) -> impl Future<Item = HttpResponse, Error = ServiceError> {
     use crate::schema::invitations::dsl::*;
+    use crate::schema::users::dsl::email as u_email;
     use crate::schema::users::dsl::*;
-    web::block(move || -> Result<Option<Invitation>, ServiceError> {
+    let guard = AccountGuard::of(&req);
+    web::block(move || -> Result<(), ServiceError> {
+        guard.check(&new_user.email)?;
         let conn = pool.get().unwrap();
+        let taken = users.filter(u_email.eq(&new_user.email)).count().get_result::<i64>(&conn)?;
+        if taken > 0 {
+            return Err(ServiceError::BadRequest(String::from("This email is already in use!")));
+        }
         let user = User::from(new_user.into_inner());
         let invitation = Invitation::from_user(&user);
-        diesel::insert_into(users).values(&user).execute(&conn)?;
-        diesel::insert_into(invitations)
-            .values(&invitation)
-            .execute(&conn)?;
-
-        // when mailing is added
-        // let frotend_target = env::var("REGISTRATION_CONFIRMATION_URL").expect("BIND_ADDRESS is not set");
-        // let frontend_address = env::var("FRONTEND_ADDRESS").expect("FRONTEND_ADDRESS is not set");
-        // send_mail(
-        //     user.email.clone(),
-        //     String::from("Confirm registration for InnoReserve")
-        //     frontend_address + &frotend_target + &invitation.id,
-        // );
-        Ok(Some(invitation))
+        let confirmation_path = env::var("REGISTRATION_CONFIRMATION_URL")
+            .unwrap_or_else(|_| "/register/".into());
+        conn.transaction(|| {
+            diesel::insert_into(users).values(&user).execute(&conn)?;
+            diesel::insert_into(invitations)
+                .values(&invitation)
+                .execute(&conn)?;
+            email_service::queue(&conn, &user.email, Template::ConfirmRegistration {
+                name: &user.name,
+                link: frontend_link(&(confirmation_path + &invitation.id)),
+            })
+        })
     })
     .then(|res| match res {
-        Ok(t) => Ok(HttpResponse::Ok().json(t)),
+        // the confirmation link only goes out by email, and the account can't
+        // sign in before it is used
+        Ok(_) => Ok(HttpResponse::Accepted().finish()),
         Err(err) => match err {
             BlockingError::Error(service_error) => Err(service_error),
             BlockingError::Canceled => Err(ServiceError::InternalServerError),
@@ -101,6 +426,7 @@ pub fn register(
 }
 
 pub fn confirm_registration(
+    req: HttpRequest,
     uuid: web::Path<Uuid>,
     data: web::Json<AuthData>,
     pool: web::Data<SqlPool>,
@@ -109,42 +435,45 @@ pub fn confirm_registration(
     use crate::schema::invitations::dsl::*;
     use crate::schema::users::dsl::email as u_email;
     use crate::schema::users::dsl::*;
+    let guard = AccountGuard::of(&req);
     web::block(move || -> Result<LoggedUser, ServiceError> {
         let conn = pool.get().unwrap();
-        let mut list_users = users.filter(u_email.eq(&data.email)).load::<User>(&conn)?;
-        let mut list_inv = invitations
-            .filter(inv_id.eq(&uuid.into_inner().to_string()))
-            .load::<Invitation>(&conn)?;
-        //does user exist
-        if let Some(user) = list_users.pop() {
-            //does user have an invitaion
-            if let Some(inv) = list_inv.pop() {
-                //verfy user credentials
-                if let Ok(matching) = verify(&user.password, &data.password) {
-                    //verification successful and invitation still valid
-                    if matching
-                        && (inv.expires_at > chrono::Local::now().naive_local()
-                            && inv.resolved == 0)
-                    {
-                        println!("We are in!");
-                        diesel::update(&user).set(active.eq(1)).execute(&conn)?;
-                        diesel::update(&inv).set(resolved.eq(1)).execute(&conn)?;
-                        return Ok(LoggedUser::from(user));
+        guard.attempt(&data.email, || {
+            let mut list_users = users.filter(u_email.eq(&data.email)).load::<User>(&conn)?;
+            let mut list_inv = invitations
+                .filter(inv_id.eq(&uuid.into_inner().to_string()))
+                .load::<Invitation>(&conn)?;
+            //does user exist
+            if let Some(user) = list_users.pop() {
+                //does user have an invitaion
+                if let Some(inv) = list_inv.pop() {
+                    //verfy user credentials
+                    if let Ok(matching) = verify(&user.password, &data.password) {
+                        //verification successful and invitation still valid
+                        if matching
+                            && (inv.expires_at > chrono::Local::now().naive_local()
+                                && inv.resolved == 0)
+                        {
+                            println!("We are in!");
+                            diesel::update(&user).set(active.eq(1)).execute(&conn)?;
+                            diesel::update(&inv).set(resolved.eq(1)).execute(&conn)?;
+                            return Ok(LoggedUser::from(user));
+                        } else {
+                            println!(
+                                "Passwords don't match or the invitation expired! matching: {:?}",
+                                matching
+                            );
+                        }
                     } else {
-                        println!(
-                            "Passwords don't match or the invitation expired! matching: {:?}",
-                            matching
-                        );
+                        println!("Invitation not found");
                     }
                 } else {
-                    println!("Invitation not found");
+                    println!("User not found");
                 }
-            } else {
-                println!("User not found");
             }
-        }
-        println!("Unauthorized...");
-        Err(ServiceError::Unauthorized)
+            println!("Unauthorized...");
+            Err(ServiceError::Unauthorized)
+        })
     })
     .then(|res| match res {
         Ok(user) => Ok(HttpResponse::Ok().json(user)),
@@ -155,33 +484,94 @@ pub fn confirm_registration(
     })
 }
 
+/// Looks up the user behind a set of credentials. Accounts that never
+/// confirmed their email address can't sign in.
+fn authenticate(conn: &SqliteConnection, auth_data: &AuthData) -> Result<User, ServiceError> {
+    use crate::schema::users::dsl::{email, users};
+    let mut items = users
+        .filter(email.eq(&auth_data.email))
+        .load::<User>(conn)?;
+    if let Some(user) = items.pop() {
+        if let Ok(matching) = verify(&user.password, &auth_data.password) {
+            if matching && user.active != 1 {
+                return Err(ServiceError::BadRequest(String::from(
+                    "Please confirm your email address first!",
+                )));
+            }
+            if matching {
+                return Ok(user);
+            }
+        }
+    }
+    Err(ServiceError::Unauthorized)
+}
+
+/// Answer to a correct password when the user has two-factor
+/// authentication enabled. `mfa_token` goes to `complete_mfa` with a code.
+#[derive(Serialize)]
+pub struct MfaPending {
+    mfa_required: bool,
+    mfa_token: String,
+    expires_in: i64,
+}
+
+/// A login that either finished or waits for the second factor.
+pub enum Login<T> {
+    Complete(T),
+    Pending(MfaPending),
+}
+
+/// Wrong codes allowed per login before it has to start over.
+const MAX_MFA_ATTEMPTS: i32 = 5;
+
+/// Starts the second step of a login of `kind` if `user` needs one.
+pub fn mfa_challenge(
+    conn: &SqliteConnection,
+    user: &User,
+    kind: &str,
+) -> Result<Option<MfaPending>, ServiceError> {
+    use crate::schema::mfa_challenges::dsl::mfa_challenges;
+    if mfa::enabled_credential(conn, &user.id)?.is_none() {
+        return Ok(None);
+    }
+    let secret = tokens::generate();
+    let challenge = MfaChallenge::from(user, kind, tokens::hash(&secret));
+    diesel::insert_into(mfa_challenges)
+        .values(&challenge)
+        .execute(conn)?;
+    Ok(Some(MfaPending {
+        mfa_required: true,
+        mfa_token: secret,
+        expires_in: (challenge.expires_at - Utc::now().naive_utc()).num_seconds(),
+    }))
+}
+
 pub fn login(
+    req: HttpRequest,
     auth_data: web::Json<AuthData>,
     id: Identity,
     pool: web::Data<SqlPool>,
 ) -> impl Future<Item = HttpResponse, Error = ServiceError> {
-    use crate::schema::users::dsl::{email, users};
-    web::block(move || -> Result<LoggedUser, ServiceError> {
+    let device = Device::of(&req);
+    let guard = AccountGuard::of(&req);
+    web::block(move || -> Result<Login<(User, Session)>, ServiceError> {
         let conn = pool.get().unwrap();
-        let mut items = users
-            .filter(email.eq(&auth_data.email))
-            .load::<User>(&conn)?;
-        if let Some(user) = items.pop() {
-            if let Ok(matching) = verify(&user.password, &auth_data.password) {
-                if matching {
-                    return Ok(LoggedUser::from(user));
-                }
-            }
+        // with a second factor the failures only reset after it
+        let user = guard.count_failures(&auth_data.email, || authenticate(&conn, &auth_data))?;
+        if let Some(pending) = mfa_challenge(&conn, &user, "cookie")? {
+            return Ok(Login::Pending(pending));
         }
-        Err(ServiceError::Unauthorized)
+        guard.succeeded(&auth_data.email)?;
+        let session = start_session(&conn, &user, "cookie", device)?;
+        Ok(Login::Complete((user, session)))
     })
     .then(
-        move |res: Result<LoggedUser, BlockingError<ServiceError>>| match res {
-            Ok(user) => {
-                let user_string = serde_json::to_string(&user).unwrap();
-                id.remember(user_string);
-                Ok(HttpResponse::Ok().json(user))
+        move |res: Result<Login<(User, Session)>, BlockingError<ServiceError>>| match res {
+            Ok(Login::Complete((user, session))) => {
+                remember(&id, &session);
+                Ok(HttpResponse::Ok().json(LoggedUser::from(user)))
             }
+            Ok(Login::Pending(pending)) => Ok(HttpResponse::Accepted().json(pending)),
             Err(err) => match err {
                 BlockingError::Error(service_error) => Err(service_error),
                 BlockingError::Canceled => Err(ServiceError::InternalServerError),
@@ -190,11 +580,413 @@ pub fn login(
     )
 }
 
-pub fn logout(id: Identity) -> HttpResponse {
-    id.forget();
-    HttpResponse::Ok().finish()
+#[derive(Serialize)]
+pub struct TokenPair {
+    access_token: String,
+    token_type: &'static str,
+    expires_in: i64,
+    refresh_token: String,
+}
+
+/// Creates an access token and a refresh token for a token session.
+fn issue_tokens(conn: &SqliteConnection, user: &User, session: &Session) -> Result<TokenPair, ServiceError> {
+    use crate::schema::refresh_tokens::dsl::refresh_tokens;
+    let secret = tokens::generate();
+    diesel::insert_into(refresh_tokens)
+        .values(&RefreshToken::from(user, session.id.clone(), tokens::hash(&secret)))
+        .execute(conn)?;
+    Ok(TokenPair {
+        access_token: access_token(session)?,
+        token_type: "Bearer",
+        expires_in: ACCESS_TOKEN_MINUTES * 60,
+        refresh_token: secret,
+    })
+}
+
+/// Login for clients without cookies. Starts a token session and answers
+/// with a bearer access token and a refresh token.
+pub fn issue_token(
+    req: HttpRequest,
+    auth_data: web::Json<AuthData>,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    let device = Device::of(&req);
+    let guard = AccountGuard::of(&req);
+    web::block(move || -> Result<Login<TokenPair>, ServiceError> {
+        let conn = pool.get().unwrap();
+        // with a second factor the failures only reset after it
+        let user = guard.count_failures(&auth_data.email, || authenticate(&conn, &auth_data))?;
+        if let Some(pending) = mfa_challenge(&conn, &user, "token")? {
+            return Ok(Login::Pending(pending));
+        }
+        guard.succeeded(&auth_data.email)?;
+        let session = start_session(&conn, &user, "token", device)?;
+        issue_tokens(&conn, &user, &session).map(Login::Complete)
+    })
+    .then(|res| match res {
+        Ok(Login::Complete(t)) => Ok(HttpResponse::Ok().json(t)),
+        Ok(Login::Pending(pending)) => Ok(HttpResponse::Accepted().json(pending)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
+
+#[derive(Deserialize)]
+pub struct MfaResponse {
+    mfa_token: String,
+    code: Option<String>,
+    recovery_code: Option<String>,
+}
+
+/// How a login ends after its second step, depending on how it started.
+enum MfaLogin {
+    Cookie(User, Session),
+    Tokens(TokenPair),
+}
+
+/// Second step of a login with two-factor authentication. Takes a code from
+/// the authenticator app or a recovery code and finishes the login the
+/// first step started, with a cookie or with tokens.
+pub fn complete_mfa(
+    req: HttpRequest,
+    data: web::Json<MfaResponse>,
+    id: Identity,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    let device = Device::of(&req);
+    let guard = AccountGuard::of(&req);
+    web::block(move || -> Result<MfaLogin, ServiceError> {
+        use crate::schema::mfa_challenges::dsl::*;
+        use crate::schema::users::dsl::{email, id as u_id, users};
+        let conn = pool.get().unwrap();
+        let now = Utc::now().naive_utc();
+        // wrong codes count against the account like wrong passwords
+        let owner = mfa_challenges
+            .filter(token_hash.eq(tokens::hash(&data.mfa_token)))
+            .select(user_id)
+            .first::<String>(&conn)
+            .optional()?
+            .ok_or(ServiceError::Unauthorized)?;
+        let account = users.filter(u_id.eq(&owner)).select(email).first::<String>(&conn)?;
+        // wrong codes have to be counted, so failures are only turned into
+        // errors after the transaction
+        let (user, session) = guard.attempt(&account, || {
+            conn.transaction::<_, ServiceError, _>(|| {
+                let challenge = match mfa_challenges
+                    .filter(token_hash.eq(tokens::hash(&data.mfa_token)))
+                    .filter(resolved.eq(0))
+                    .filter(expires_at.gt(now))
+                    .first::<MfaChallenge>(&conn)
+                    .optional()?
+                {
+                    Some(challenge) => challenge,
+                    None => return Ok(None),
+                };
+                if challenge.attempts >= MAX_MFA_ATTEMPTS {
+                    return Ok(None);
+                }
+                let matches = mfa::check_second_factor(
+                    &conn,
+                    &challenge.user_id,
+                    data.code.as_ref().map(String::as_str),
+                    data.recovery_code.as_ref().map(String::as_str),
+                )?;
+                if !matches {
+                    diesel::update(&challenge)
+                        .set(attempts.eq(attempts + 1))
+                        .execute(&conn)?;
+                    return Ok(None);
+                }
+                let claimed = diesel::update(&challenge)
+                    .filter(resolved.eq(0))
+                    .set(resolved.eq(1))
+                    .execute(&conn)?;
+                if claimed == 0 {
+                    return Ok(None);
+                }
+                let user = users.filter(u_id.eq(&challenge.user_id)).first::<User>(&conn)?;
+                let session = start_session(&conn, &user, &challenge.kind, device)?;
+                Ok(Some((user, session)))
+            })?
+            .ok_or(ServiceError::Unauthorized)
+        })?;
+        match session.kind.as_str() {
+            "token" => issue_tokens(&conn, &user, &session).map(MfaLogin::Tokens),
+            _ => Ok(MfaLogin::Cookie(user, session)),
+        }
+    })
+    .then(move |res| match res {
+        Ok(MfaLogin::Cookie(user, session)) => {
+            remember(&id, &session);
+            Ok(HttpResponse::Ok().json(LoggedUser::from(user)))
+        }
+        Ok(MfaLogin::Tokens(t)) => Ok(HttpResponse::Ok().json(t)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
+
+#[derive(Deserialize)]
+pub struct RefreshRequest {
+    refresh_token: String,
+}
+
+/// Trades a refresh token for a new pair and renews the session. The old
+/// token stops working; presenting it again means it leaked, so the whole
+/// session is revoked.
+pub fn refresh_token(
+    data: web::Json<RefreshRequest>,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    use crate::schema::refresh_tokens::dsl::*;
+    use crate::schema::sessions::dsl::{expires_at as session_expires_at, last_seen_at};
+    web::block(move || -> Result<TokenPair, ServiceError> {
+        let conn = pool.get().unwrap();
+        let now = Utc::now().naive_utc();
+        // the revocation on reuse has to be committed, so failures are only
+        // turned into errors after the transaction
+        let pair = conn.transaction::<_, ServiceError, _>(|| {
+            let token = match refresh_tokens
+                .filter(token_hash.eq(tokens::hash(&data.refresh_token)))
+                .first::<RefreshToken>(&conn)
+                .optional()?
+            {
+                Some(token) => token,
+                None => return Ok(None),
+            };
+            if token.revoked_at.is_some() {
+                end_session(&conn, &token.family_id)?;
+                return Ok(None);
+            }
+            let claimed = diesel::update(&token)
+                .filter(revoked_at.is_null())
+                .set(revoked_at.eq(now))
+                .execute(&conn)?;
+            if claimed == 0 || token.expires_at <= now {
+                return Ok(None);
+            }
+            let (session, user) = match live_session(&conn, &token.family_id) {
+                Ok(live) => live,
+                Err(_) => return Ok(None),
+            };
+            if user.security_stamp != token.security_stamp {
+                end_session(&conn, &session.id)?;
+                return Ok(None);
+            }
+            diesel::update(&session)
+                .set((
+                    last_seen_at.eq(now),
+                    session_expires_at.eq(now + Duration::days(TOKEN_SESSION_DAYS)),
+                ))
+                .execute(&conn)?;
+            issue_tokens(&conn, &user, &session).map(Some)
+        })?;
+        pair.ok_or(ServiceError::Unauthorized)
+    })
+    .then(|res| match res {
+        Ok(t) => Ok(HttpResponse::Ok().json(t)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
+
+/// Logout for token clients. Ends the session of the given refresh token;
+/// unknown tokens are ignored.
+pub fn revoke_token(
+    data: web::Json<RefreshRequest>,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    use crate::schema::refresh_tokens::dsl::*;
+    web::block(move || -> Result<(), ServiceError> {
+        let conn = pool.get().unwrap();
+        let family = refresh_tokens
+            .filter(token_hash.eq(tokens::hash(&data.refresh_token)))
+            .select(family_id)
+            .first::<String>(&conn)
+            .optional()?;
+        match family {
+            Some(family) => end_session(&conn, &family),
+            None => Ok(()),
+        }
+    })
+    .then(|res| match res {
+        Ok(_) => Ok(HttpResponse::Ok().finish()),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
+
+/// Ends the current session, if there is one, and drops the cookie.
+pub fn logout(
+    id: Identity,
+    session: Option<CurrentSession>,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    web::block(move || -> Result<(), ServiceError> {
+        match session {
+            Some(session) => end_session(&pool.get().unwrap(), &session.id),
+            None => Ok(()),
+        }
+    })
+    .then(move |res| match res {
+        Ok(_) => {
+            id.forget();
+            Ok(HttpResponse::Ok().finish())
+        }
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
 }
 
 pub fn get_me(logged_user: LoggedUser) -> HttpResponse {
     HttpResponse::Ok().json(logged_user)
 }
+
+#[derive(Deserialize)]
+pub struct ForgottenPassword {
+    email: String,
+}
+
+/// Mails a password reset link. Answers the same whether or not the email
+/// belongs to an account, and only so often for the same address.
+pub fn forgot_password(
+    req: HttpRequest,
+    data: web::Json<ForgottenPassword>,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    use crate::schema::password_resets::dsl::*;
+    use crate::schema::users::dsl::{email, users};
+    let guard = AccountGuard::of(&req);
+    web::block(move || -> Result<(), ServiceError> {
+        // a locked account may still reset its password
+        guard.throttle(&data.email)?;
+        let conn = pool.get().unwrap();
+        let user = match users.filter(email.eq(&data.email)).first::<User>(&conn).optional()? {
+            Some(user) => user,
+            None => return Ok(()),
+        };
+        let secret = tokens::generate();
+        let reset_path = env::var("PASSWORD_RESET_URL").unwrap_or_else(|_| "/password/reset/".into());
+        conn.transaction(|| {
+            // only the newest link works
+            diesel::update(password_resets.filter(user_id.eq(&user.id)).filter(resolved.eq(0)))
+                .set(resolved.eq(1))
+                .execute(&conn)?;
+            diesel::insert_into(password_resets)
+                .values(&PasswordReset::from(&user, tokens::hash(&secret)))
+                .execute(&conn)?;
+            email_service::queue(&conn, &user.email, Template::PasswordReset {
+                name: &user.name,
+                link: frontend_link(&(reset_path + &secret)),
+            })
+        })
+    })
+    .then(|res| match res {
+        Ok(_) => Ok(HttpResponse::Ok().finish()),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
+
+#[derive(Deserialize)]
+pub struct NewPassword {
+    password: String,
+}
+
+/// Sets a new password with a token from `forgot_password`. Every existing
+/// session and personal access token of the user ends.
+pub fn reset_password(
+    secret: web::Path<String>,
+    data: web::Json<NewPassword>,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    use crate::schema::password_resets::dsl::*;
+    use crate::schema::users::dsl::{id as u_id, password, security_stamp, users};
+    web::block(move || -> Result<(), ServiceError> {
+        let conn = pool.get().unwrap();
+        if data.password.is_empty() {
+            return Err(ServiceError::BadRequest(String::from("Password can't be empty!")));
+        }
+        let hash = hash_password(&data.password)?;
+        conn.transaction(|| {
+            let reset = password_resets
+                .filter(token_hash.eq(tokens::hash(&secret)))
+                .filter(resolved.eq(0))
+                .filter(expires_at.gt(Utc::now().naive_utc()))
+                .first::<PasswordReset>(&conn)
+                .optional()?
+                .ok_or(ServiceError::Unauthorized)?;
+            let claimed = diesel::update(&reset)
+                .filter(resolved.eq(0))
+                .set(resolved.eq(1))
+                .execute(&conn)?;
+            if claimed == 0 {
+                return Err(ServiceError::Unauthorized);
+            }
+            diesel::update(users.filter(u_id.eq(&reset.user_id)))
+                .set((password.eq(&hash), security_stamp.eq(new_security_stamp())))
+                .execute(&conn)?;
+            end_other_sessions(&conn, &reset.user_id, None, "password_changed")?;
+            Ok(())
+        })
+    })
+    .then(|res| match res {
+        Ok(_) => Ok(HttpResponse::Ok().finish()),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn forwarded(value: &str) -> header::HeaderMap {
+        let mut headers = header::HeaderMap::new();
+        headers.insert(
+            header::HeaderName::from_static("x-forwarded-for"),
+            header::HeaderValue::from_str(value).unwrap(),
+        );
+        headers
+    }
+
+    #[test]
+    fn ignores_forwarded_header_from_untrusted_peer() {
+        let peer: IpAddr = "203.0.113.7".parse().unwrap();
+        let trusted: Vec<IpAddr> = vec!["10.0.0.1".parse().unwrap()];
+        let client = forwarded_client(peer, &forwarded("198.51.100.1"), &trusted);
+        assert_eq!(client, peer);
+    }
+
+    #[test]
+    fn takes_last_untrusted_entry_behind_proxy() {
+        let peer: IpAddr = "10.0.0.1".parse().unwrap();
+        let trusted: Vec<IpAddr> = vec![peer, "10.0.0.2".parse().unwrap()];
+        let headers = forwarded("1.2.3.4, 198.51.100.1, 10.0.0.2");
+        let client = forwarded_client(peer, &headers, &trusted);
+        assert_eq!(client, "198.51.100.1".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn falls_back_to_peer_without_usable_entry() {
+        let peer: IpAddr = "10.0.0.1".parse().unwrap();
+        let trusted = vec![peer];
+        let empty = header::HeaderMap::new();
+        assert_eq!(forwarded_client(peer, &empty, &trusted), peer);
+        assert_eq!(forwarded_client(peer, &forwarded("garbage"), &trusted), peer);
+    }
+}
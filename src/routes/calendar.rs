@@ -0,0 +1,225 @@
+use actix_web::{error::BlockingError, http::header, web, HttpResponse};
+use chrono::{NaiveDateTime, Utc};
+use diesel::prelude::*;
+use diesel::r2d2::ConnectionManager;
+use futures::Future;
+use r2d2::Pool;
+
+use crate::errors::ServiceError;
+use crate::ical;
+use crate::models::{CalendarDay, CalendarFeed, CalendarToken, LoggedUser, Note};
+use crate::routes::pagination::parse_bound;
+use crate::tokens;
+
+type SqlPool = Pool<ConnectionManager<SqliteConnection>>;
+
+#[derive(Deserialize)]
+pub struct CalendarRange {
+    from: Option<String>,
+    to: Option<String>,
+}
+
+impl CalendarRange {
+    fn bounds(&self) -> Result<(Option<NaiveDateTime>, Option<NaiveDateTime>), ServiceError> {
+        let from = match &self.from {
+            Some(value) => Some(parse_bound(value, false)?),
+            None => None,
+        };
+        let to = match &self.to {
+            Some(value) => Some(parse_bound(value, true)?),
+            None => None,
+        };
+        Ok((from, to))
+    }
+}
+
+/// Dated notes a user can see in their calendar: their own plus everything
+/// posted to live groups they belong to, ordered by date.
+fn calendar_notes(
+    conn: &SqliteConnection,
+    owner_id: &str,
+    from: Option<NaiveDateTime>,
+    to: Option<NaiveDateTime>,
+) -> Result<Vec<Note>, ServiceError> {
+    use crate::schema::group_links::dsl as links;
+    use crate::schema::groups::dsl as groups;
+    use crate::schema::notes::dsl as notes;
+    let group_ids = links::group_links
+        .filter(links::user_id.eq(owner_id))
+        .select(links::group_id)
+        .load::<String>(conn)?;
+    let live_ids = groups::groups
+        .filter(groups::id.eq_any(group_ids))
+        .filter(groups::deleted_at.is_null())
+        .select(groups::id)
+        .load::<String>(conn)?;
+    let mut query = notes::notes
+        .filter(notes::user_id.eq(owner_id).or(notes::group_id.eq_any(live_ids)))
+        .filter(notes::deleted_at.is_null())
+        .filter(notes::date_tag.is_not_null())
+        .into_boxed();
+    if let Some(from) = from {
+        query = query.filter(notes::date_tag.ge(from));
+    }
+    if let Some(to) = to {
+        query = query.filter(notes::date_tag.lt(to));
+    }
+    let note_list = query
+        .order_by(notes::date_tag.asc())
+        .then_order_by(notes::title.asc())
+        .load::<Note>(conn)?;
+    Ok(note_list)
+}
+
+fn ics_response(body: String, disposition: &str) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/calendar; charset=utf-8")
+        .header(header::CONTENT_DISPOSITION, disposition)
+        .body(body)
+}
+
+pub fn get_calendar(
+    user: LoggedUser,
+    range: web::Query<CalendarRange>,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    web::block(move || -> Result<Vec<CalendarDay>, ServiceError> {
+        if range.from.is_none() || range.to.is_none() {
+            return Err(ServiceError::BadRequest(
+                String::from("Both from and to dates are required!")
+            ));
+        }
+        let conn = pool.get().unwrap();
+        let (from, to) = range.bounds()?;
+        let mut days: Vec<CalendarDay> = vec![];
+        for note in calendar_notes(&conn, &user.id, from, to)? {
+            let date = note.date_tag.unwrap().date();
+            match days.last_mut() {
+                Some(day) if day.date == date => day.notes.push(note),
+                _ => days.push(CalendarDay { date, notes: vec![note] }),
+            }
+        }
+        Ok(days)
+    })
+    .then(|res| match res {
+        Ok(t) => Ok(HttpResponse::Ok().json(t)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
+
+pub fn export(
+    user: LoggedUser,
+    range: web::Query<CalendarRange>,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    web::block(move || -> Result<String, ServiceError> {
+        let conn = pool.get().unwrap();
+        let (from, to) = range.bounds()?;
+        let note_list = calendar_notes(&conn, &user.id, from, to)?;
+        Ok(ical::render(&format!("{}'s notes", user.name), &note_list))
+    })
+    .then(|res| match res {
+        Ok(t) => Ok(ics_response(t, "attachment; filename=\"notes.ics\"")),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
+
+/// Subscription feed for calendar apps, authenticated by the secret token in
+/// the path instead of a session.
+pub fn feed(
+    secret: web::Path<String>,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    use crate::schema::calendar_tokens::dsl::*;
+    use crate::schema::users::dsl::{id as u_id, name, users};
+    web::block(move || -> Result<String, ServiceError> {
+        let conn = pool.get().unwrap();
+        let secret = secret.into_inner();
+        let secret = secret.trim_end_matches(".ics");
+        let token = calendar_tokens
+            .filter(token_hash.eq(tokens::hash(secret)))
+            .filter(revoked_at.is_null())
+            .first::<CalendarToken>(&conn)
+            .optional()?
+            .ok_or(ServiceError::Unauthorized)?;
+        let user_name = users
+            .filter(u_id.eq(&token.user_id))
+            .select(name)
+            .first::<String>(&conn)?;
+        let note_list = calendar_notes(&conn, &token.user_id, None, None)?;
+        Ok(ical::render(&format!("{}'s notes", user_name), &note_list))
+    })
+    .then(|res| match res {
+        Ok(t) => Ok(ics_response(t, "inline; filename=\"notes.ics\"")),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
+
+fn revoke_active(conn: &SqliteConnection, user: &LoggedUser) -> Result<usize, ServiceError> {
+    use crate::schema::calendar_tokens::dsl::*;
+    let revoked = diesel::update(
+        calendar_tokens
+            .filter(user_id.eq(&user.id))
+            .filter(revoked_at.is_null()),
+    )
+    .set(revoked_at.eq(Utc::now().naive_utc()))
+    .execute(conn)?;
+    Ok(revoked)
+}
+
+/// Issues a new feed token, revoking any previous one.
+pub fn create_token(
+    user: LoggedUser,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    use crate::schema::calendar_tokens::dsl::*;
+    web::block(move || -> Result<CalendarFeed, ServiceError> {
+        let conn = pool.get().unwrap();
+        let secret = tokens::generate();
+        let token = CalendarToken::from(&user, tokens::hash(&secret));
+        conn.transaction(|| {
+            revoke_active(&conn, &user)?;
+            diesel::insert_into(calendar_tokens)
+                .values(&token)
+                .execute(&conn)?;
+            Ok(CalendarFeed {
+                url: format!("/api/calendar/feed/{}.ics", secret),
+                token,
+                secret,
+            })
+        })
+    })
+    .then(|res| match res {
+        Ok(t) => Ok(HttpResponse::Ok().json(t)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
+
+pub fn revoke_token(
+    user: LoggedUser,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    web::block(move || -> Result<usize, ServiceError> {
+        let conn = pool.get().unwrap();
+        revoke_active(&conn, &user)
+    })
+    .then(|res| match res {
+        Ok(_) => Ok(HttpResponse::Ok().finish()),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
@@ -0,0 +1,130 @@
+use actix_web::{error::BlockingError, web, HttpResponse};
+use diesel::prelude::*;
+use diesel::r2d2::ConnectionManager;
+use futures::Future;
+use r2d2::Pool;
+use uuid::Uuid;
+
+use crate::errors::ServiceError;
+use crate::models::{LoggedUser, NewGrant, NoteAccess, NoteGrant, PublicUser, User};
+use crate::policy::{owned_note, EDITOR, READER};
+
+type SqlPool = Pool<ConnectionManager<SqliteConnection>>;
+
+pub fn list(
+    user: LoggedUser,
+    uuid: web::Path<Uuid>,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    use crate::schema::note_grants::dsl::*;
+    use crate::schema::users::dsl::users;
+    web::block(move || -> Result<Vec<NoteAccess>, ServiceError> {
+        let conn = pool.get().unwrap();
+        let note = owned_note(&conn, &user, &uuid.into_inner().to_string())?;
+        let access_list = NoteGrant::belonging_to(&note)
+            .inner_join(users)
+            .order(created_at.asc())
+            .load::<(NoteGrant, User)>(&conn)?
+            .into_iter()
+            .map(|(grant, grantee)| NoteAccess {
+                grant,
+                user: PublicUser::from(grantee),
+            })
+            .collect();
+        Ok(access_list)
+    })
+    .then(|res| match res {
+        Ok(t) => Ok(HttpResponse::Ok().json(t)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
+
+/// Grants a user access to a note, or changes the role of an existing grant.
+pub fn grant(
+    user: LoggedUser,
+    uuid: web::Path<Uuid>,
+    new_grant: web::Json<NewGrant>,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    use crate::schema::note_grants::dsl::*;
+    use crate::schema::users::dsl::{email, users};
+    web::block(move || -> Result<NoteAccess, ServiceError> {
+        let conn = pool.get().unwrap();
+        let note = owned_note(&conn, &user, &uuid.into_inner().to_string())?;
+        let new_grant = new_grant.into_inner();
+        if new_grant.role != READER && new_grant.role != EDITOR {
+            return Err(ServiceError::BadRequest(format!(
+                "Unknown role '{}', expected reader or editor",
+                new_grant.role
+            )));
+        }
+        let grantee = users
+            .filter(email.eq(&new_grant.email))
+            .first::<User>(&conn)
+            .optional()?
+            .ok_or_else(|| ServiceError::BadRequest(String::from("No user with that email!")))?;
+        if grantee.id == note.user_id {
+            return Err(ServiceError::BadRequest(
+                String::from("The owner already has full access!")
+            ));
+        }
+        let existing = NoteGrant::belonging_to(&note)
+            .filter(user_id.eq(&grantee.id))
+            .first::<NoteGrant>(&conn)
+            .optional()?;
+        let saved = match existing {
+            Some(existing) => {
+                diesel::update(&existing)
+                    .set(role.eq(&new_grant.role))
+                    .execute(&conn)?;
+                NoteGrant { role: new_grant.role, ..existing }
+            }
+            None => {
+                let created = NoteGrant::from(&note, &grantee, new_grant.role, &user);
+                diesel::insert_into(note_grants)
+                    .values(&created)
+                    .execute(&conn)?;
+                created
+            }
+        };
+        Ok(NoteAccess {
+            grant: saved,
+            user: PublicUser::from(grantee),
+        })
+    })
+    .then(|res| match res {
+        Ok(t) => Ok(HttpResponse::Ok().json(t)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
+
+pub fn revoke(
+    user: LoggedUser,
+    path: web::Path<(Uuid, Uuid)>,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    use crate::schema::note_grants::dsl::*;
+    web::block(move || -> Result<NoteGrant, ServiceError> {
+        let conn = pool.get().unwrap();
+        let (note_uuid, grant_uuid) = path.into_inner();
+        let note = owned_note(&conn, &user, &note_uuid.to_string())?;
+        let grant = NoteGrant::belonging_to(&note)
+            .filter(id.eq(grant_uuid.to_string()))
+            .first::<NoteGrant>(&conn)?;
+        diesel::delete(&grant).execute(&conn)?;
+        Ok(grant)
+    })
+    .then(|res| match res {
+        Ok(t) => Ok(HttpResponse::Ok().json(t)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
@@ -1,4 +1,6 @@
+use actix::Addr;
 use actix_web::{error::BlockingError, web, HttpResponse};
+use chrono::Utc;
 use diesel::prelude::*;
 use diesel::r2d2::ConnectionManager;
 use futures::Future;
@@ -6,28 +8,82 @@ use r2d2::Pool;
 use uuid::Uuid;
 
 use crate::errors::ServiceError;
-use crate::models::{LoggedUser, Group, NewGroup, GroupLink, Note, GroupedNotes};
+use crate::models::{LoggedUser, Group, NewGroup, GroupChanges, GroupJoinRequest, GroupLink, GroupMember, GroupRole, Note, GroupedNotes, GroupNotesPage, Page, PublicUser, User};
+use crate::policy::{self, require_group_role};
+use crate::realtime::{GroupEvent, Hub, Publish};
+use crate::routes::pagination::ListQuery;
 
 type SqlPool = Pool<ConnectionManager<SqliteConnection>>;
 
+const JOIN_POLICIES: [&str; 3] = ["open", "approval", "invite_only"];
+
+fn check_join_policy(join_policy: &Option<String>) -> Result<(), ServiceError> {
+    match join_policy {
+        Some(value) if !JOIN_POLICIES.contains(&value.as_str()) => Err(ServiceError::BadRequest(format!(
+            "Unknown join policy '{}', expected open, approval or invite_only",
+            value
+        ))),
+        _ => Ok(()),
+    }
+}
+
+pub fn live_group(conn: &SqliteConnection, target: &str) -> Result<Group, ServiceError> {
+    use crate::schema::groups::dsl::*;
+    let group = groups
+        .filter(id.eq(target))
+        .filter(deleted_at.is_null())
+        .first::<Group>(conn)?;
+    Ok(group)
+}
+
+/// Adds `member` to a live group with the given role.
+pub fn add_member(
+    conn: &SqliteConnection,
+    group: &Group,
+    member: &LoggedUser,
+    member_role: GroupRole,
+) -> Result<GroupLink, ServiceError> {
+    use crate::schema::group_links::dsl::*;
+    let existing = group_links
+        .filter(group_id.eq(&group.id).and(user_id.eq(&member.id)))
+        .count()
+        .get_result::<i64>(conn)?;
+    if existing > 0 {
+        return Err(ServiceError::BadRequest(String::from("Already a member of this group!")));
+    }
+    let link = GroupLink::with_role(group, member, member_role);
+    diesel::insert_into(group_links).values(&link).execute(conn)?;
+    Ok(link)
+}
+
 
 pub fn group_notes(
-    _: LoggedUser,
+    user: LoggedUser,
     uuid: web::Path<Uuid>,
+    list: web::Query<ListQuery>,
     pool: web::Data<SqlPool>,
 ) -> impl Future<Item = HttpResponse, Error = ServiceError> {
     use crate::schema::groups::dsl::*;
     use crate::schema::groups::dsl::id as g_id;
     use crate::schema::notes::dsl::*;
     use crate::schema::notes::dsl::group_id as n_g_id;
-    web::block(move || -> Result<GroupedNotes, ServiceError> {
+    use crate::schema::groups::dsl::deleted_at as g_deleted_at;
+    use crate::schema::notes::dsl::deleted_at as n_deleted_at;
+    web::block(move || -> Result<GroupNotesPage, ServiceError> {
         let conn = pool.get().unwrap();
         let uuid = uuid.into_inner().to_string();
-        let group = groups.filter(g_id.eq(&uuid)).first::<Group>(&conn)?;
-        let note_list = notes.filter(n_g_id.eq(&uuid)).load::<Note>(&conn)?;
-        Ok(GroupedNotes {
+        require_group_role(&conn, &user, &uuid, GroupRole::Viewer)?;
+        let group = groups
+            .filter(g_id.eq(&uuid))
+            .filter(g_deleted_at.is_null())
+            .first::<Group>(&conn)?;
+        let query = notes
+            .filter(n_g_id.eq(&uuid))
+            .filter(n_deleted_at.is_null())
+            .into_boxed();
+        Ok(GroupNotesPage {
             group, 
-            notes: note_list
+            page: list.notes_page(&conn, Some(&user), query)?,
         })
     }) 
     .then(
@@ -43,6 +99,7 @@ pub fn group_notes(
 
 pub fn users_groups_notes(
     user: LoggedUser,
+    list: web::Query<ListQuery>,
     pool: web::Data<SqlPool>,
 ) -> impl Future<Item = HttpResponse, Error = ServiceError> {
     use crate::schema::groups::dsl::*;
@@ -50,14 +107,32 @@ pub fn users_groups_notes(
     use crate::schema::group_links::dsl::group_id as l_g_id;
     use crate::schema::notes::dsl::*;
     use crate::schema::notes::dsl::group_id as n_g_id;
-    web::block(move || -> Result<Vec<GroupedNotes>, ServiceError> {
+    use crate::schema::groups::dsl::deleted_at as g_deleted_at;
+    use crate::schema::notes::dsl::deleted_at as n_deleted_at;
+    web::block(move || -> Result<Page<GroupedNotes>, ServiceError> {
         let conn = pool.get().unwrap();
         let mut out: Vec<GroupedNotes> = vec![];
         let group_ids = GroupLink::belonging_to(&user)
             .select(l_g_id).load::<String>(&conn)?;
-        let group_list = groups.filter(g_id.eq_any(&group_ids)).load::<Group>(&conn)?;
-        let note_list = notes.filter(n_g_id.eq_any(&group_ids)).load::<Note>(&conn)?;
-        let grouped_notes: Vec<Vec<Note>> = note_list.grouped_by(&group_list);
+        let live_ids = groups
+            .filter(g_id.eq_any(&group_ids))
+            .filter(g_deleted_at.is_null())
+            .select(g_id)
+            .load::<String>(&conn)?;
+        let query = notes
+            .filter(n_g_id.eq_any(live_ids))
+            .filter(n_deleted_at.is_null())
+            .into_boxed();
+        let page = list.notes_page(&conn, Some(&user), query)?;
+        // only the groups that have notes on this page are returned
+        let page_group_ids: Vec<&String> = page.items
+            .iter()
+            .filter_map(|note| note.group_id.as_ref())
+            .collect();
+        let group_list = groups
+            .filter(g_id.eq_any(page_group_ids))
+            .load::<Group>(&conn)?;
+        let grouped_notes: Vec<Vec<Note>> = page.items.grouped_by(&group_list);
         let zipped: Vec<(Group, Vec<Note>)> = group_list
             .into_iter()
             .zip(grouped_notes)
@@ -68,8 +143,10 @@ pub fn users_groups_notes(
                 notes: grp.1,
             });
         } 
-        Ok(out)
-        
+        Ok(Page {
+            items: out,
+            next_cursor: page.next_cursor,
+        })
     }) 
     .then(
         |res| match res {
@@ -85,17 +162,21 @@ pub fn users_groups_notes(
 
 pub fn get_user_groups(
     user: LoggedUser,
+    list: web::Query<ListQuery>,
     pool: web::Data<SqlPool>,
 ) -> impl Future<Item = HttpResponse, Error = ServiceError> {
     use crate::schema::groups::dsl::*;
     use crate::schema::groups::dsl::id as g_id;
     use crate::schema::group_links::dsl::*;
-    web::block(move || -> Result<Vec<Group>, ServiceError> {
+    web::block(move || -> Result<Page<Group>, ServiceError> {
         let conn = pool.get().unwrap();
         let group_ids = GroupLink::belonging_to(&user)
             .select(group_id).load::<String>(&conn)?;
-        let group_list = groups.filter(g_id.eq_any(&group_ids)).load::<Group>(&conn)?;
-        Ok(group_list)
+        let query = groups
+            .filter(g_id.eq_any(group_ids))
+            .filter(deleted_at.is_null())
+            .into_boxed();
+        list.groups_page(&conn, query)
     })
     .then(
         |res| match res {
@@ -115,11 +196,57 @@ pub fn insert(
     pool: web::Data<SqlPool>,
 ) -> impl Future<Item = HttpResponse, Error = ServiceError> {
     use crate::schema::groups::dsl::*;
+    use crate::schema::group_links::dsl::group_links;
     web::block(move || -> Result<Group, ServiceError> {
         let conn = pool.get().unwrap();
-        let group = Group::from(new_group.into_inner(), user);
-        diesel::insert_into(groups).values(&group).execute(&conn)?;
-        Ok(group)
+        let new_group = new_group.into_inner();
+        check_join_policy(&new_group.join_policy)?;
+        let group = Group::from(new_group, user.clone());
+        conn.transaction(|| {
+            diesel::insert_into(groups).values(&group).execute(&conn)?;
+            diesel::insert_into(group_links)
+                .values(&GroupLink::with_role(&group, &user, GroupRole::Owner))
+                .execute(&conn)?;
+            Ok(group)
+        })
+    })
+    .then(
+        |res| match res {
+            Ok(t) => Ok(HttpResponse::Ok().json(t)),
+            Err(err) => match err {
+                BlockingError::Error(service_error) => Err(service_error),
+                BlockingError::Canceled => Err(ServiceError::InternalServerError),
+            }
+        }
+    )
+}
+
+/// Everyone in a group with their role, longest standing members first.
+/// Only visible to members.
+pub fn members(
+    user: LoggedUser,
+    uuid: web::Path<Uuid>,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    use crate::schema::group_links::dsl::*;
+    use crate::schema::users::dsl::users;
+    web::block(move || -> Result<Vec<GroupMember>, ServiceError> {
+        let conn = pool.get().unwrap();
+        let group = live_group(&conn, &uuid.into_inner().to_string())
+            .map_err(|_| ServiceError::Forbidden)?;
+        require_group_role(&conn, &user, &group.id, GroupRole::Viewer)?;
+        let member_list = GroupLink::belonging_to(&group)
+            .inner_join(users)
+            .order(joined_at.asc())
+            .load::<(GroupLink, User)>(&conn)?
+            .into_iter()
+            .map(|(link, member)| GroupMember {
+                user: PublicUser::from(member),
+                role: link.role,
+                joined_at: link.joined_at,
+            })
+            .collect();
+        Ok(member_list)
     })
     .then(
         |res| match res {
@@ -137,27 +264,69 @@ pub struct GroupTarget {
     id: String,
 }
 
+enum JoinOutcome {
+    Joined(Group),
+    Requested(GroupJoinRequest),
+}
+
+/// Joins an open group right away. Groups that need approval get a pending
+/// join request instead, and invite-only groups can only be joined through
+/// an invitation.
 pub fn join(
     target: web::Json<GroupTarget>,
     user: LoggedUser,
     pool: web::Data<SqlPool>,
+    hub: web::Data<Addr<Hub>>,
 ) -> impl Future<Item = HttpResponse, Error = ServiceError> {
     use crate::schema::groups::dsl::*;
-    use crate::schema::groups::dsl::id as g_id;
-    use crate::schema::group_links::dsl::*;
-    web::block(move || -> Result<Group, ServiceError> {
+    use crate::schema::group_join_requests::dsl as requests;
+    let member = user.clone();
+    web::block(move || -> Result<JoinOutcome, ServiceError> {
         let conn = pool.get().unwrap();
         let target = target.into_inner();
-        let group = groups.filter(g_id.eq(&target.id)).first::<Group>(&conn)?;
-        let group_link = GroupLink::from(&group, &user);
-        diesel::insert_into(group_links)
-            .values(&group_link)
-            .execute(&conn)?;
-        Ok(group)
+        let group = groups
+            .filter(id.eq(&target.id))
+            .filter(deleted_at.is_null())
+            .first::<Group>(&conn)?;
+        match group.join_policy.as_str() {
+            "open" => {
+                add_member(&conn, &group, &user, GroupRole::Member)?;
+                Ok(JoinOutcome::Joined(group))
+            }
+            "approval" => {
+                if policy::group_role(&conn, &user, &group.id)?.is_some() {
+                    return Err(ServiceError::BadRequest(
+                        String::from("Already a member of this group!")
+                    ));
+                }
+                let pending = GroupJoinRequest::belonging_to(&group)
+                    .filter(requests::user_id.eq(&user.id))
+                    .filter(requests::status.eq("pending"))
+                    .first::<GroupJoinRequest>(&conn)
+                    .optional()?;
+                if let Some(pending) = pending {
+                    return Ok(JoinOutcome::Requested(pending));
+                }
+                let request = GroupJoinRequest::from(&group, &user);
+                diesel::insert_into(requests::group_join_requests)
+                    .values(&request)
+                    .execute(&conn)?;
+                Ok(JoinOutcome::Requested(request))
+            }
+            _ => Err(ServiceError::Forbidden),
+        }
     })
     .then(
-        |res| match res {
-            Ok(t) => Ok(HttpResponse::Ok().json(t)),
+        move |res| match res {
+            Ok(JoinOutcome::Joined(t)) => {
+                hub.do_send(Publish(GroupEvent::MemberJoined {
+                    group_id: t.id.clone(),
+                    user_id: member.id,
+                    name: member.name,
+                }));
+                Ok(HttpResponse::Ok().json(t))
+            }
+            Ok(JoinOutcome::Requested(t)) => Ok(HttpResponse::Accepted().json(t)),
             Err(err) => match err {
                 BlockingError::Error(service_error) => Err(service_error),
                 BlockingError::Canceled => Err(ServiceError::InternalServerError),
@@ -170,22 +339,44 @@ pub fn leave(
     target: web::Json<GroupTarget>,
     user: LoggedUser,
     pool: web::Data<SqlPool>,
+    hub: web::Data<Addr<Hub>>,
 ) -> impl Future<Item = HttpResponse, Error = ServiceError> {
-    use crate::schema::groups::dsl::*;
-    use crate::schema::groups::dsl::id as g_id;
     use crate::schema::group_links::dsl::*;
-    web::block(move || -> Result<Group, ServiceError> {
+    let member_id = user.id.clone();
+    web::block(move || -> Result<(Group, usize), ServiceError> {
         let conn = pool.get().unwrap();
         let target = target.into_inner();
-        let group = groups.filter(g_id.eq(&target.id)).first::<Group>(&conn)?;
-        diesel::delete(
+        let group = live_group(&conn, &target.id)?;
+        let current = group_links
+            .filter(group_id.eq(&target.id).and(user_id.eq(&user.id)))
+            .select(role)
+            .first::<String>(&conn)
+            .optional()?;
+        match current.as_ref().and_then(|current| GroupRole::parse(current)) {
+            None => return Err(ServiceError::Forbidden),
+            Some(GroupRole::Owner) => {
+                return Err(ServiceError::BadRequest(
+                    String::from("The owner can't leave the group, delete it instead!")
+                ))
+            }
+            Some(_) => {}
+        }
+        let removed = diesel::delete(
             group_links.filter(group_id.eq(&target.id).and(user_id.eq(&user.id))))
             .execute(&conn)?;
-        Ok(group)
+        Ok((group, removed))
     })
     .then(
-        |res| match res {
-            Ok(t) => Ok(HttpResponse::Ok().json(t)),
+        move |res| match res {
+            Ok((t, removed)) => {
+                if removed > 0 {
+                    hub.do_send(Publish(GroupEvent::MemberLeft {
+                        group_id: t.id.clone(),
+                        user_id: member_id,
+                    }));
+                }
+                Ok(HttpResponse::Ok().json(t))
+            }
             Err(err) => match err {
                 BlockingError::Error(service_error) => Err(service_error),
                 BlockingError::Canceled => Err(ServiceError::InternalServerError),
@@ -194,27 +385,63 @@ pub fn leave(
     )
 }
 
+/// Moves a group to the trash. Its members' sockets stop listening to it.
 pub fn delete(
     user: LoggedUser,
     uuid: web::Path<Uuid>,
     pool: web::Data<SqlPool>,
+    hub: web::Data<Addr<Hub>>,
 ) -> impl Future<Item = HttpResponse, Error = ServiceError> {
     use crate::schema::groups::dsl::*;
     use crate::schema::groups::dsl::id as g_id;
-    use crate::schema::group_links::dsl::*;
     web::block(move || -> Result<Group, ServiceError> {
         let conn = pool.get().unwrap();
         let target = uuid.into_inner().to_string();
-        let group = groups.filter(g_id.eq(&target)).first::<Group>(&conn)?;
-        if &group.created_by != &user.id {
-            return Err(ServiceError::Forbidden);
-        }
-        diesel::delete(
-            group_links.filter(group_id.eq(&target)))
+        let group = groups
+            .filter(g_id.eq(&target))
+            .filter(deleted_at.is_null())
+            .first::<Group>(&conn)?;
+        require_group_role(&conn, &user, &group.id, GroupRole::Owner)?;
+        diesel::update(&group)
+            .set(deleted_at.eq(Utc::now().naive_utc()))
             .execute(&conn)?;
-        diesel::delete(
-            groups.filter(g_id.eq(&target)))
+        let trashed = groups.filter(g_id.eq(&target)).first::<Group>(&conn)?;
+        Ok(trashed)
+    })
+    .then(
+        move |res| match res {
+            Ok(t) => {
+                hub.do_send(Publish(GroupEvent::GroupDeleted { group_id: t.id.clone() }));
+                Ok(HttpResponse::Ok().json(t))
+            }
+            Err(err) => match err {
+                BlockingError::Error(service_error) => Err(service_error),
+                BlockingError::Canceled => Err(ServiceError::InternalServerError),
+            }
+        }
+    )
+}
+
+pub fn update(
+    user: LoggedUser,
+    uuid: web::Path<Uuid>,
+    changes: web::Json<GroupChanges>,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    use crate::schema::groups::dsl::*;
+    web::block(move || -> Result<Group, ServiceError> {
+        let conn = pool.get().unwrap();
+        let target = uuid.into_inner().to_string();
+        require_group_role(&conn, &user, &target, GroupRole::Admin)?;
+        let changes = changes.into_inner();
+        check_join_policy(&changes.join_policy)?;
+        if changes.name.is_none() && changes.color.is_none() && changes.join_policy.is_none() {
+            return Err(ServiceError::BadRequest(String::from("Nothing to change!")));
+        }
+        diesel::update(groups.filter(id.eq(&target)))
+            .set(&changes)
             .execute(&conn)?;
+        let group = groups.filter(id.eq(&target)).first::<Group>(&conn)?;
         Ok(group)
     })
     .then(
@@ -226,4 +453,118 @@ pub fn delete(
             }
         }
     )
-}
\ No newline at end of file
+}
+
+/// Loads the membership of `member_id` and checks that `user` outranks it.
+fn managed_member(
+    conn: &SqliteConnection,
+    user: &LoggedUser,
+    target_group: &str,
+    member_id: &str,
+) -> Result<(GroupRole, GroupLink, GroupRole), ServiceError> {
+    use crate::schema::group_links::dsl::*;
+    let actor_role = require_group_role(conn, user, target_group, GroupRole::Admin)?;
+    let link = group_links
+        .filter(group_id.eq(target_group).and(user_id.eq(member_id)))
+        .first::<GroupLink>(conn)
+        .optional()?
+        .ok_or_else(|| ServiceError::BadRequest(String::from("Not a member of this group!")))?;
+    let member_role = GroupRole::parse(&link.role).unwrap_or(GroupRole::Viewer);
+    if member_role >= actor_role {
+        return Err(ServiceError::Forbidden);
+    }
+    Ok((actor_role, link, member_role))
+}
+
+pub fn remove_member(
+    user: LoggedUser,
+    path: web::Path<(Uuid, Uuid)>,
+    pool: web::Data<SqlPool>,
+    hub: web::Data<Addr<Hub>>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    web::block(move || -> Result<GroupLink, ServiceError> {
+        let conn = pool.get().unwrap();
+        let (group_uuid, member_uuid) = path.into_inner();
+        let (_, link, _) = managed_member(
+            &conn, &user, &group_uuid.to_string(), &member_uuid.to_string())?;
+        diesel::delete(&link).execute(&conn)?;
+        Ok(link)
+    })
+    .then(
+        move |res| match res {
+            Ok(t) => {
+                hub.do_send(Publish(GroupEvent::MemberLeft {
+                    group_id: t.group_id.clone(),
+                    user_id: t.user_id.clone(),
+                }));
+                Ok(HttpResponse::Ok().json(t))
+            }
+            Err(err) => match err {
+                BlockingError::Error(service_error) => Err(service_error),
+                BlockingError::Canceled => Err(ServiceError::InternalServerError),
+            }
+        }
+    )
+}
+
+/// Moves a member one role up or down. Nobody can hand out a role as high as
+/// their own, so admins manage viewers and members and only the owner
+/// manages admins.
+fn change_role(
+    pool: &SqlPool,
+    user: &LoggedUser,
+    path: (Uuid, Uuid),
+    step: fn(GroupRole) -> Option<GroupRole>,
+) -> Result<GroupLink, ServiceError> {
+    use crate::schema::group_links::dsl::*;
+    let conn = pool.get().unwrap();
+    let (group_uuid, member_uuid) = path;
+    let (actor_role, link, member_role) = managed_member(
+        &conn, user, &group_uuid.to_string(), &member_uuid.to_string())?;
+    let new_role = step(member_role).ok_or_else(|| ServiceError::BadRequest(format!(
+        "Can't change the role of a group {}",
+        member_role.as_str()
+    )))?;
+    if new_role >= actor_role {
+        return Err(ServiceError::Forbidden);
+    }
+    diesel::update(&link)
+        .set(role.eq(new_role.as_str()))
+        .execute(&conn)?;
+    let updated = group_links.filter(id.eq(&link.id)).first::<GroupLink>(&conn)?;
+    Ok(updated)
+}
+
+pub fn promote(
+    user: LoggedUser,
+    path: web::Path<(Uuid, Uuid)>,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    web::block(move || change_role(&pool, &user, path.into_inner(), GroupRole::promoted))
+    .then(
+        |res| match res {
+            Ok(t) => Ok(HttpResponse::Ok().json(t)),
+            Err(err) => match err {
+                BlockingError::Error(service_error) => Err(service_error),
+                BlockingError::Canceled => Err(ServiceError::InternalServerError),
+            }
+        }
+    )
+}
+
+pub fn demote(
+    user: LoggedUser,
+    path: web::Path<(Uuid, Uuid)>,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    web::block(move || change_role(&pool, &user, path.into_inner(), GroupRole::demoted))
+    .then(
+        |res| match res {
+            Ok(t) => Ok(HttpResponse::Ok().json(t)),
+            Err(err) => match err {
+                BlockingError::Error(service_error) => Err(service_error),
+                BlockingError::Canceled => Err(ServiceError::InternalServerError),
+            }
+        }
+    )
+}
@@ -0,0 +1,413 @@
+use actix::Addr;
+use actix_web::{error::BlockingError, web, HttpResponse};
+use chrono::Utc;
+use diesel::prelude::*;
+use diesel::r2d2::ConnectionManager;
+use futures::Future;
+use r2d2::Pool;
+use uuid::Uuid;
+
+use crate::email_service::{self, frontend_link, Template};
+use crate::errors::ServiceError;
+use crate::models::{
+    Group, GroupInvitation, GroupJoinRequest, GroupRole, LoggedUser, NewGroupInvitation,
+    PendingMember, PublicUser, ReceivedInvitation, User,
+};
+use crate::policy::{group_role, require_group_role};
+use crate::realtime::{GroupEvent, Hub, Publish};
+use crate::routes::groups::{add_member, live_group};
+
+type SqlPool = Pool<ConnectionManager<SqliteConnection>>;
+
+/// The email address of `user` if the account confirmed it. Only then may
+/// it pick up invitations sent to that address.
+fn confirmed_email(conn: &SqliteConnection, user: &LoggedUser) -> Result<Option<String>, ServiceError> {
+    use crate::schema::users::dsl::*;
+    let confirmed = users.filter(id.eq(&user.id)).select(active).first::<i32>(conn)? == 1;
+    Ok(if confirmed { Some(user.email.clone()) } else { None })
+}
+
+/// Loads a pending, unexpired invitation addressed to `user`. Anything else
+/// fails the same way so invitation ids can't be probed.
+fn received_invitation(
+    conn: &SqliteConnection,
+    user: &LoggedUser,
+    invitation_id: &str,
+) -> Result<(GroupInvitation, Group), ServiceError> {
+    use crate::schema::group_invitations::dsl::*;
+    let invalid = || ServiceError::BadRequest(String::from("This invitation is no longer valid!"));
+    let invitation = group_invitations
+        .filter(id.eq(invitation_id))
+        .filter(status.eq("pending"))
+        .filter(expires_at.gt(Utc::now().naive_utc()))
+        .first::<GroupInvitation>(conn)
+        .optional()?
+        .ok_or_else(invalid)?;
+    if !invitation.is_addressed_to(user, confirmed_email(conn, user)?.as_ref()) {
+        return Err(invalid());
+    }
+    let group = live_group(conn, &invitation.group_id).map_err(|_| invalid())?;
+    Ok((invitation, group))
+}
+
+/// Invitations of a group, newest first. Admins only.
+pub fn list(
+    user: LoggedUser,
+    uuid: web::Path<Uuid>,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    use crate::schema::group_invitations::dsl::*;
+    web::block(move || -> Result<Vec<GroupInvitation>, ServiceError> {
+        let conn = pool.get().unwrap();
+        let group = live_group(&conn, &uuid.into_inner().to_string())?;
+        require_group_role(&conn, &user, &group.id, GroupRole::Admin)?;
+        let invitation_list = GroupInvitation::belonging_to(&group)
+            .order(created_at.desc())
+            .load::<GroupInvitation>(&conn)?;
+        Ok(invitation_list)
+    })
+    .then(|res| match res {
+        Ok(t) => Ok(HttpResponse::Ok().json(t)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
+
+/// Invites a user, by id or email, into a group. Admins can't invite anyone
+/// into a role as high as their own.
+pub fn invite(
+    user: LoggedUser,
+    uuid: web::Path<Uuid>,
+    new_invitation: web::Json<NewGroupInvitation>,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    use crate::schema::group_invitations::dsl::group_invitations;
+    use crate::schema::group_links::dsl as links;
+    use crate::schema::users::dsl as users;
+    web::block(move || -> Result<GroupInvitation, ServiceError> {
+        let conn = pool.get().unwrap();
+        let group = live_group(&conn, &uuid.into_inner().to_string())?;
+        let actor_role = require_group_role(&conn, &user, &group.id, GroupRole::Admin)?;
+        let new_invitation = new_invitation.into_inner();
+        let invite_role = match &new_invitation.role {
+            Some(name) => GroupRole::parse(name).ok_or_else(|| ServiceError::BadRequest(format!(
+                "Unknown role '{}', expected viewer, member or admin",
+                name
+            )))?,
+            None => GroupRole::Member,
+        };
+        if invite_role >= actor_role {
+            return Err(ServiceError::Forbidden);
+        }
+        let email = new_invitation.email.map(|value| value.trim().to_string());
+        let invitee = match (&email, &new_invitation.user_id) {
+            (Some(value), None) if !value.is_empty() => users::users
+                .filter(users::email.eq(value))
+                .first::<User>(&conn)
+                .optional()?,
+            (None, Some(value)) => Some(
+                users::users
+                    .filter(users::id.eq(value))
+                    .first::<User>(&conn)
+                    .optional()?
+                    .ok_or_else(|| ServiceError::BadRequest(String::from("No user with that id!")))?,
+            ),
+            _ => {
+                return Err(ServiceError::BadRequest(
+                    String::from("Invitations need either an email or a user id!")
+                ))
+            }
+        };
+        if let Some(invitee) = &invitee {
+            let existing = links::group_links
+                .filter(links::group_id.eq(&group.id).and(links::user_id.eq(&invitee.id)))
+                .count()
+                .get_result::<i64>(&conn)?;
+            if existing > 0 {
+                return Err(ServiceError::BadRequest(String::from("Already a member of this group!")));
+            }
+        }
+        let invitation = GroupInvitation::from(
+            &group,
+            email,
+            new_invitation.user_id,
+            invite_role,
+            &user,
+        );
+        let recipient = invitee
+            .map(|invitee| invitee.email)
+            .or_else(|| invitation.email.clone());
+        conn.transaction(|| {
+            diesel::insert_into(group_invitations)
+                .values(&invitation)
+                .execute(&conn)?;
+            if let Some(recipient) = &recipient {
+                email_service::queue(&conn, recipient, Template::GroupInvitation {
+                    group: &group.name,
+                    inviter: &user.name,
+                    link: frontend_link(&format!("/invitations/{}", invitation.id)),
+                })?;
+            }
+            Ok(invitation)
+        })
+    })
+    .then(|res| match res {
+        Ok(t) => Ok(HttpResponse::Ok().json(t)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
+
+pub fn revoke(
+    user: LoggedUser,
+    path: web::Path<(Uuid, Uuid)>,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    use crate::schema::group_invitations::dsl::*;
+    web::block(move || -> Result<GroupInvitation, ServiceError> {
+        let conn = pool.get().unwrap();
+        let (group_uuid, invitation_uuid) = path.into_inner();
+        let group = live_group(&conn, &group_uuid.to_string())?;
+        require_group_role(&conn, &user, &group.id, GroupRole::Admin)?;
+        let invitation = GroupInvitation::belonging_to(&group)
+            .filter(id.eq(invitation_uuid.to_string()))
+            .first::<GroupInvitation>(&conn)?;
+        diesel::update(&invitation)
+            .filter(status.eq("pending"))
+            .set(status.eq("revoked"))
+            .execute(&conn)?;
+        let revoked = group_invitations
+            .filter(id.eq(&invitation.id))
+            .first::<GroupInvitation>(&conn)?;
+        Ok(revoked)
+    })
+    .then(|res| match res {
+        Ok(t) => Ok(HttpResponse::Ok().json(t)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
+
+/// Pending invitations addressed to the logged in user.
+pub fn received(
+    user: LoggedUser,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    use crate::schema::group_invitations::dsl::*;
+    use crate::schema::groups::dsl::{deleted_at, groups};
+    web::block(move || -> Result<Vec<ReceivedInvitation>, ServiceError> {
+        let conn = pool.get().unwrap();
+        let mut query = group_invitations
+            .inner_join(groups)
+            .filter(status.eq("pending"))
+            .filter(expires_at.gt(Utc::now().naive_utc()))
+            .filter(deleted_at.is_null())
+            .into_boxed();
+        query = match confirmed_email(&conn, &user)? {
+            Some(address) => query.filter(user_id.eq(&user.id).or(email.eq(address))),
+            None => query.filter(user_id.eq(&user.id)),
+        };
+        let invitation_list = query
+            .order(created_at.desc())
+            .load::<(GroupInvitation, Group)>(&conn)?
+            .into_iter()
+            .map(|(invitation, group)| ReceivedInvitation { invitation, group })
+            .collect();
+        Ok(invitation_list)
+    })
+    .then(|res| match res {
+        Ok(t) => Ok(HttpResponse::Ok().json(t)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
+
+pub fn accept(
+    user: LoggedUser,
+    uuid: web::Path<Uuid>,
+    pool: web::Data<SqlPool>,
+    hub: web::Data<Addr<Hub>>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    use crate::schema::group_invitations::dsl::*;
+    let member = user.clone();
+    web::block(move || -> Result<Group, ServiceError> {
+        let conn = pool.get().unwrap();
+        conn.transaction(|| {
+            let (invitation, group) = received_invitation(&conn, &user, &uuid.into_inner().to_string())?;
+            let invite_role = GroupRole::parse(&invitation.role).unwrap_or(GroupRole::Member);
+            diesel::update(&invitation)
+                .set(status.eq("accepted"))
+                .execute(&conn)?;
+            add_member(&conn, &group, &user, invite_role)?;
+            Ok(group)
+        })
+    })
+    .then(move |res| match res {
+        Ok(t) => {
+            hub.do_send(Publish(GroupEvent::MemberJoined {
+                group_id: t.id.clone(),
+                user_id: member.id,
+                name: member.name,
+            }));
+            Ok(HttpResponse::Ok().json(t))
+        }
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
+
+pub fn decline(
+    user: LoggedUser,
+    uuid: web::Path<Uuid>,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    use crate::schema::group_invitations::dsl::*;
+    web::block(move || -> Result<GroupInvitation, ServiceError> {
+        let conn = pool.get().unwrap();
+        let (invitation, _) = received_invitation(&conn, &user, &uuid.into_inner().to_string())?;
+        diesel::update(&invitation)
+            .set(status.eq("declined"))
+            .execute(&conn)?;
+        let declined = group_invitations
+            .filter(id.eq(&invitation.id))
+            .first::<GroupInvitation>(&conn)?;
+        Ok(declined)
+    })
+    .then(|res| match res {
+        Ok(t) => Ok(HttpResponse::Ok().json(t)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
+
+/// Pending join requests of a group, oldest first. Admins only.
+pub fn requests(
+    user: LoggedUser,
+    uuid: web::Path<Uuid>,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    use crate::schema::group_join_requests::dsl::*;
+    use crate::schema::users::dsl::users;
+    web::block(move || -> Result<Vec<PendingMember>, ServiceError> {
+        let conn = pool.get().unwrap();
+        let group = live_group(&conn, &uuid.into_inner().to_string())?;
+        require_group_role(&conn, &user, &group.id, GroupRole::Admin)?;
+        let request_list = GroupJoinRequest::belonging_to(&group)
+            .inner_join(users)
+            .filter(status.eq("pending"))
+            .order(created_at.asc())
+            .load::<(GroupJoinRequest, User)>(&conn)?
+            .into_iter()
+            .map(|(request, requester)| PendingMember {
+                request,
+                user: PublicUser::from(requester),
+            })
+            .collect();
+        Ok(request_list)
+    })
+    .then(|res| match res {
+        Ok(t) => Ok(HttpResponse::Ok().json(t)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
+
+/// Marks a pending join request as approved or rejected. Approving adds the
+/// requester as a member; they are returned alongside the request if so.
+fn resolve_request(
+    pool: &SqlPool,
+    user: &LoggedUser,
+    path: (Uuid, Uuid),
+    approve: bool,
+) -> Result<(GroupJoinRequest, Option<LoggedUser>), ServiceError> {
+    use crate::schema::group_join_requests::dsl::*;
+    use crate::schema::users::dsl::{id as u_id, users};
+    let conn = pool.get().unwrap();
+    let (group_uuid, request_uuid) = path;
+    let group = live_group(&conn, &group_uuid.to_string())?;
+    require_group_role(&conn, user, &group.id, GroupRole::Admin)?;
+    conn.transaction(|| {
+        let request = GroupJoinRequest::belonging_to(&group)
+            .filter(id.eq(request_uuid.to_string()))
+            .filter(status.eq("pending"))
+            .first::<GroupJoinRequest>(&conn)
+            .optional()?
+            .ok_or_else(|| ServiceError::BadRequest(String::from("No pending request with that id!")))?;
+        diesel::update(&request)
+            .set((
+                status.eq(if approve { "approved" } else { "rejected" }),
+                resolved_at.eq(Utc::now().naive_utc()),
+                resolved_by.eq(&user.id),
+            ))
+            .execute(&conn)?;
+        let mut joined = None;
+        if approve {
+            let requester = LoggedUser::from(
+                users.filter(u_id.eq(&request.user_id)).first::<User>(&conn)?
+            );
+            // an invitation may have been accepted in the meantime
+            if group_role(&conn, &requester, &group.id)?.is_none() {
+                add_member(&conn, &group, &requester, GroupRole::Member)?;
+                joined = Some(requester);
+            }
+        }
+        let resolved = group_join_requests
+            .filter(id.eq(&request.id))
+            .first::<GroupJoinRequest>(&conn)?;
+        Ok((resolved, joined))
+    })
+}
+
+pub fn approve(
+    user: LoggedUser,
+    path: web::Path<(Uuid, Uuid)>,
+    pool: web::Data<SqlPool>,
+    hub: web::Data<Addr<Hub>>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    web::block(move || resolve_request(&pool, &user, path.into_inner(), true))
+    .then(move |res| match res {
+        Ok((t, joined)) => {
+            if let Some(member) = joined {
+                hub.do_send(Publish(GroupEvent::MemberJoined {
+                    group_id: t.group_id.clone(),
+                    user_id: member.id,
+                    name: member.name,
+                }));
+            }
+            Ok(HttpResponse::Ok().json(t))
+        }
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
+
+pub fn reject(
+    user: LoggedUser,
+    path: web::Path<(Uuid, Uuid)>,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    web::block(move || resolve_request(&pool, &user, path.into_inner(), false))
+    .then(|res| match res {
+        Ok((t, _)) => Ok(HttpResponse::Ok().json(t)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
@@ -0,0 +1,205 @@
+use actix::Addr;
+use actix_web::{error::BlockingError, web, HttpRequest, HttpResponse};
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::prelude::*;
+use diesel::r2d2::ConnectionManager;
+use futures::Future;
+use r2d2::Pool;
+use uuid::Uuid;
+
+use crate::errors::ServiceError;
+use crate::models::{Group, GroupJoinCode, GroupRole, LoggedUser, NewJoinCode};
+use crate::policy::require_group_role;
+use crate::ratelimit::AccountGuard;
+use crate::realtime::{GroupEvent, Hub, Publish};
+use crate::routes::groups::{add_member, live_group};
+use crate::tokens;
+
+type SqlPool = Pool<ConnectionManager<SqliteConnection>>;
+
+/// How long a code stays valid when no expiry is given.
+const DEFAULT_LIFETIME_DAYS: i64 = 7;
+
+pub fn list(
+    user: LoggedUser,
+    uuid: web::Path<Uuid>,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    use crate::schema::group_join_codes::dsl::*;
+    web::block(move || -> Result<Vec<GroupJoinCode>, ServiceError> {
+        let conn = pool.get().unwrap();
+        let group = live_group(&conn, &uuid.into_inner().to_string())?;
+        require_group_role(&conn, &user, &group.id, GroupRole::Admin)?;
+        let code_list = GroupJoinCode::belonging_to(&group)
+            .order(created_at.desc())
+            .load::<GroupJoinCode>(&conn)?;
+        Ok(code_list)
+    })
+    .then(|res| match res {
+        Ok(t) => Ok(HttpResponse::Ok().json(t)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
+
+pub fn insert(
+    user: LoggedUser,
+    uuid: web::Path<Uuid>,
+    new_code: web::Json<NewJoinCode>,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    use crate::schema::group_join_codes::dsl::*;
+    web::block(move || -> Result<GroupJoinCode, ServiceError> {
+        let conn = pool.get().unwrap();
+        let group = live_group(&conn, &uuid.into_inner().to_string())?;
+        require_group_role(&conn, &user, &group.id, GroupRole::Admin)?;
+        let new_code = new_code.into_inner();
+        let now = Utc::now().naive_utc();
+        let expiry = match new_code.expires_at {
+            Some(value) => {
+                let expiry = NaiveDateTime::parse_from_str(&value, "%Y-%m-%d %H:%M:%S")
+                    .map_err(|_| ServiceError::BadRequest(format!(
+                        "Invalid date '{}', expected YYYY-MM-DD HH:MM:SS",
+                        value
+                    )))?;
+                if expiry <= now {
+                    return Err(ServiceError::BadRequest(
+                        String::from("Expiry must be in the future!")
+                    ));
+                }
+                expiry
+            }
+            None => now + Duration::days(DEFAULT_LIFETIME_DAYS),
+        };
+        if new_code.max_uses.map(|limit| limit < 1).unwrap_or(false) {
+            return Err(ServiceError::BadRequest(
+                String::from("A code needs to allow at least one use!")
+            ));
+        }
+        // codes are short, so make sure a fresh one doesn't collide
+        let mut candidate = tokens::join_code();
+        while group_join_codes
+            .filter(code.eq(&candidate))
+            .count()
+            .get_result::<i64>(&conn)? > 0
+        {
+            candidate = tokens::join_code();
+        }
+        let join_code = GroupJoinCode::from(&group, candidate, expiry, new_code.max_uses, &user);
+        diesel::insert_into(group_join_codes)
+            .values(&join_code)
+            .execute(&conn)?;
+        Ok(join_code)
+    })
+    .then(|res| match res {
+        Ok(t) => Ok(HttpResponse::Ok().json(t)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
+
+pub fn revoke(
+    user: LoggedUser,
+    path: web::Path<(Uuid, Uuid)>,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    use crate::schema::group_join_codes::dsl::*;
+    web::block(move || -> Result<GroupJoinCode, ServiceError> {
+        let conn = pool.get().unwrap();
+        let (group_uuid, code_uuid) = path.into_inner();
+        let group = live_group(&conn, &group_uuid.to_string())?;
+        require_group_role(&conn, &user, &group.id, GroupRole::Admin)?;
+        let join_code = GroupJoinCode::belonging_to(&group)
+            .filter(id.eq(code_uuid.to_string()))
+            .first::<GroupJoinCode>(&conn)?;
+        diesel::update(&join_code)
+            .filter(revoked_at.is_null())
+            .set(revoked_at.eq(Utc::now().naive_utc()))
+            .execute(&conn)?;
+        let revoked = group_join_codes
+            .filter(id.eq(&join_code.id))
+            .first::<GroupJoinCode>(&conn)?;
+        Ok(revoked)
+    })
+    .then(|res| match res {
+        Ok(t) => Ok(HttpResponse::Ok().json(t)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
+
+#[derive(Deserialize)]
+pub struct Redemption {
+    code: String,
+}
+
+/// Joins the group behind a code. Codes are handed out by admins, so they
+/// work regardless of the group's join policy.
+pub fn redeem(
+    req: HttpRequest,
+    redemption: web::Json<Redemption>,
+    user: LoggedUser,
+    pool: web::Data<SqlPool>,
+    hub: web::Data<Addr<Hub>>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    use crate::schema::group_join_codes::dsl::*;
+    let member = user.clone();
+    let guard = AccountGuard::of(&req);
+    web::block(move || -> Result<Group, ServiceError> {
+        let conn = pool.get().unwrap();
+        let typed = tokens::normalize_join_code(&redemption.into_inner().code);
+        let invalid = || ServiceError::BadRequest(String::from("This code is no longer valid!"));
+        // codes that don't exist were guessed; they count towards a lockout
+        // that redeeming a real code doesn't lift
+        let join_code = guard
+            .count_failures(&user.id, || {
+                group_join_codes
+                    .filter(code.eq(&typed))
+                    .first::<GroupJoinCode>(&conn)
+                    .optional()?
+                    .ok_or(ServiceError::Unauthorized)
+            })
+            .map_err(|err| match err {
+                ServiceError::Unauthorized => invalid(),
+                err => err,
+            })?;
+        conn.transaction(|| {
+            if !join_code.is_usable(Utc::now().naive_utc()) {
+                return Err(invalid());
+            }
+            let group = live_group(&conn, &join_code.group_id).map_err(|_| invalid())?;
+            // counting only succeeds if nobody used or revoked the code in
+            // between
+            let claimed = diesel::update(&join_code)
+                .filter(revoked_at.is_null())
+                .filter(uses.eq(join_code.uses))
+                .set(uses.eq(uses + 1))
+                .execute(&conn)?;
+            if claimed == 0 {
+                return Err(invalid());
+            }
+            add_member(&conn, &group, &user, GroupRole::Member)?;
+            Ok(group)
+        })
+    })
+    .then(move |res| match res {
+        Ok(t) => {
+            hub.do_send(Publish(GroupEvent::MemberJoined {
+                group_id: t.id.clone(),
+                user_id: member.id,
+                name: member.name,
+            }));
+            Ok(HttpResponse::Ok().json(t))
+        }
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
@@ -0,0 +1,254 @@
+use actix_web::{error::BlockingError, web, HttpResponse};
+use chrono::Utc;
+use diesel::prelude::*;
+use diesel::r2d2::ConnectionManager;
+use futures::Future;
+use r2d2::Pool;
+use std::env;
+
+use crate::errors::ServiceError;
+use crate::models::{LoggedUser, RecoveryCode, TotpCredential};
+use crate::routes::users::confirmed_user;
+use crate::tokens;
+use crate::totp;
+
+type SqlPool = Pool<ConnectionManager<SqliteConnection>>;
+
+/// How many recovery codes a user gets when enabling two-factor
+/// authentication.
+const RECOVERY_CODES: usize = 10;
+
+/// The confirmed authenticator of a user, if they enabled two-factor
+/// authentication.
+pub fn enabled_credential(
+    conn: &SqliteConnection,
+    user: &str,
+) -> Result<Option<TotpCredential>, ServiceError> {
+    use crate::schema::totp_credentials::dsl::*;
+    let credential = totp_credentials
+        .filter(user_id.eq(user))
+        .filter(enabled_at.is_not_null())
+        .first::<TotpCredential>(conn)
+        .optional()?;
+    Ok(credential)
+}
+
+/// Checks the second factor of a login: a code from the authenticator app
+/// or, failing that, an unused recovery code, which is used up.
+pub fn check_second_factor(
+    conn: &SqliteConnection,
+    user: &str,
+    code: Option<&str>,
+    recovery_code: Option<&str>,
+) -> Result<bool, ServiceError> {
+    use crate::schema::recovery_codes::dsl as recovery_codes;
+    use crate::schema::totp_credentials::dsl::last_used_step;
+    match (code, recovery_code) {
+        (Some(code), _) => {
+            let credential = match enabled_credential(conn, user)? {
+                Some(credential) => credential,
+                None => return Ok(false),
+            };
+            let now = Utc::now().timestamp();
+            match totp::verify(&credential.secret, code, now, credential.last_used_step) {
+                Some(step) => {
+                    diesel::update(&credential)
+                        .set(last_used_step.eq(step))
+                        .execute(conn)?;
+                    Ok(true)
+                }
+                None => Ok(false),
+            }
+        }
+        (None, Some(recovery_code)) => {
+            let hash = tokens::hash(&tokens::normalize_recovery_code(recovery_code));
+            let used = diesel::update(
+                recovery_codes::recovery_codes
+                    .filter(recovery_codes::user_id.eq(user))
+                    .filter(recovery_codes::code_hash.eq(hash))
+                    .filter(recovery_codes::used_at.is_null()),
+            )
+            .set(recovery_codes::used_at.eq(Utc::now().naive_utc()))
+            .execute(conn)?;
+            Ok(used > 0)
+        }
+        (None, None) => Ok(false),
+    }
+}
+
+#[derive(Serialize)]
+pub struct MfaStatus {
+    totp_enabled: bool,
+    recovery_codes_left: i64,
+}
+
+pub fn status(
+    user: LoggedUser,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    use crate::schema::recovery_codes::dsl::*;
+    web::block(move || -> Result<MfaStatus, ServiceError> {
+        let conn = pool.get().unwrap();
+        let totp_enabled = enabled_credential(&conn, &user.id)?.is_some();
+        let recovery_codes_left = recovery_codes
+            .filter(user_id.eq(&user.id))
+            .filter(used_at.is_null())
+            .count()
+            .get_result::<i64>(&conn)?;
+        Ok(MfaStatus {
+            totp_enabled,
+            recovery_codes_left,
+        })
+    })
+    .then(|res| match res {
+        Ok(t) => Ok(HttpResponse::Ok().json(t)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
+
+#[derive(Serialize)]
+pub struct Enrollment {
+    secret: String,
+    otpauth_uri: String,
+}
+
+/// Starts setting up an authenticator app. Nothing changes for the login
+/// until a first code is confirmed.
+pub fn enroll(
+    user: LoggedUser,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    use crate::schema::totp_credentials::dsl::*;
+    web::block(move || -> Result<Enrollment, ServiceError> {
+        let conn = pool.get().unwrap();
+        if enabled_credential(&conn, &user.id)?.is_some() {
+            return Err(ServiceError::BadRequest(String::from(
+                "Two-factor authentication is already enabled!",
+            )));
+        }
+        let credential = TotpCredential::from(&user, totp::new_secret());
+        conn.transaction(|| {
+            diesel::delete(totp_credentials.filter(user_id.eq(&user.id))).execute(&conn)?;
+            diesel::insert_into(totp_credentials)
+                .values(&credential)
+                .execute(&conn)
+        })?;
+        let issuer = env::var("TOTP_ISSUER").unwrap_or_else(|_| "conduit".into());
+        Ok(Enrollment {
+            otpauth_uri: totp::uri(&issuer, &user.email, &credential.secret),
+            secret: credential.secret,
+        })
+    })
+    .then(|res| match res {
+        Ok(t) => Ok(HttpResponse::Ok().json(t)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
+
+#[derive(Deserialize)]
+pub struct Confirmation {
+    code: String,
+}
+
+#[derive(Serialize)]
+pub struct RecoveryCodes {
+    recovery_codes: Vec<String>,
+}
+
+/// Turns two-factor authentication on once the app produced a valid code.
+/// Answers with the recovery codes, which are not shown again.
+pub fn confirm(
+    user: LoggedUser,
+    data: web::Json<Confirmation>,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    use crate::schema::recovery_codes::dsl::{recovery_codes, user_id as code_user_id};
+    use crate::schema::totp_credentials::dsl::*;
+    web::block(move || -> Result<RecoveryCodes, ServiceError> {
+        let conn = pool.get().unwrap();
+        let credential = totp_credentials
+            .filter(user_id.eq(&user.id))
+            .filter(enabled_at.is_null())
+            .first::<TotpCredential>(&conn)
+            .optional()?
+            .ok_or_else(|| ServiceError::BadRequest(String::from(
+                "Start the setup of two-factor authentication first!",
+            )))?;
+        let step = totp::verify(&credential.secret, &data.code, Utc::now().timestamp(), None)
+            .ok_or_else(|| ServiceError::BadRequest(String::from("Invalid code!")))?;
+        let codes: Vec<String> = (0..RECOVERY_CODES).map(|_| tokens::recovery_code()).collect();
+        conn.transaction(|| {
+            diesel::update(&credential)
+                .set((enabled_at.eq(Utc::now().naive_utc()), last_used_step.eq(step)))
+                .execute(&conn)?;
+            diesel::delete(recovery_codes.filter(code_user_id.eq(&user.id))).execute(&conn)?;
+            for code in &codes {
+                diesel::insert_into(recovery_codes)
+                    .values(&RecoveryCode::from(&user, tokens::hash(code)))
+                    .execute(&conn)?;
+            }
+            Ok(RecoveryCodes {
+                recovery_codes: codes.clone(),
+            })
+        })
+    })
+    .then(|res| match res {
+        Ok(t) => Ok(HttpResponse::Ok().json(t)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
+
+#[derive(Deserialize)]
+pub struct Disable {
+    password: String,
+}
+
+/// Turns two-factor authentication off. Needs the password, so an open
+/// session alone isn't enough.
+pub fn disable(
+    user: LoggedUser,
+    data: web::Json<Disable>,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    use crate::schema::mfa_challenges::dsl as challenges;
+    use crate::schema::recovery_codes::dsl as recovery_codes;
+    use crate::schema::totp_credentials::dsl as totp_credentials;
+    web::block(move || -> Result<(), ServiceError> {
+        let conn = pool.get().unwrap();
+        confirmed_user(&conn, &user, &data.password)?;
+        conn.transaction(|| {
+            diesel::delete(
+                totp_credentials::totp_credentials.filter(totp_credentials::user_id.eq(&user.id)),
+            )
+            .execute(&conn)?;
+            diesel::delete(
+                recovery_codes::recovery_codes.filter(recovery_codes::user_id.eq(&user.id)),
+            )
+            .execute(&conn)?;
+            diesel::update(
+                challenges::mfa_challenges
+                    .filter(challenges::user_id.eq(&user.id))
+                    .filter(challenges::resolved.eq(0)),
+            )
+            .set(challenges::resolved.eq(1))
+            .execute(&conn)?;
+            Ok(())
+        })
+    })
+    .then(|res| match res {
+        Ok(_) => Ok(HttpResponse::Ok().finish()),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
@@ -1,14 +1,97 @@
-use actix_web::{web, Scope};
+use actix_web::{guard, web, Scope};
+use std::time::Duration;
+
+use crate::policy::{Scope as TokenScope, TokenAccess};
+use crate::ratelimit::{Bucket, Limits, RateLimit};
 
 pub mod auth;
 mod notes;
 mod users;
 mod groups;
+mod revisions;
+mod tags;
+mod attachments;
+mod trash;
+mod pagination;
+mod calendar;
+mod reminders;
+mod notifications;
+mod realtime;
+pub mod shares;
+mod grants;
+mod invitations;
+mod join_codes;
+mod sessions;
+mod access_tokens;
+mod mfa;
+mod oidc;
+
+/// Password logins, through the cookie or for tokens.
+const LOGIN_LIMITS: Limits = Limits {
+    name: "login",
+    per_ip: Bucket::new(20, Duration::from_secs(3)),
+    per_account: Bucket::new(10, Duration::from_secs(60)),
+};
+
+const REGISTRATION_LIMITS: Limits = Limits {
+    name: "registration",
+    per_ip: Bucket::new(5, Duration::from_secs(10 * 60)),
+    per_account: Bucket::new(3, Duration::from_secs(10 * 60)),
+};
+
+const CONFIRMATION_LIMITS: Limits = Limits {
+    name: "confirmation",
+    per_ip: Bucket::new(10, Duration::from_secs(30)),
+    per_account: Bucket::new(5, Duration::from_secs(60)),
+};
+
+/// Second login steps, counted against the account like passwords.
+const MFA_LIMITS: Limits = Limits {
+    name: "mfa",
+    per_ip: Bucket::new(20, Duration::from_secs(3)),
+    per_account: Bucket::new(10, Duration::from_secs(60)),
+};
+
+/// Reset mails, per address so nobody's inbox gets flooded.
+const PASSWORD_FORGOT_LIMITS: Limits = Limits {
+    name: "password-forgot",
+    per_ip: Bucket::new(5, Duration::from_secs(10 * 60)),
+    per_account: Bucket::new(3, Duration::from_secs(20 * 60)),
+};
+
+const PASSWORD_RESET_LIMITS: Limits = Limits {
+    name: "password-reset",
+    per_ip: Bucket::new(10, Duration::from_secs(30)),
+    per_account: Bucket::new(5, Duration::from_secs(60)),
+};
+
+/// Refresh token exchanges and revocations, per client. The tokens are
+/// random, so there is no account to count against.
+const REFRESH_LIMITS: Limits = Limits {
+    name: "refresh",
+    per_ip: Bucket::new(30, Duration::from_secs(2)),
+    per_account: Bucket::new(10, Duration::from_secs(60)),
+};
+
+/// Join code guesses, per client and per user.
+const REDEMPTION_LIMITS: Limits = Limits {
+    name: "redemption",
+    per_ip: Bucket::new(10, Duration::from_secs(30)),
+    per_account: Bucket::new(5, Duration::from_secs(60)),
+};
+
+/// Share links, per client and per link so link passwords can't be guessed.
+const SHARE_LIMITS: Limits = Limits {
+    name: "share",
+    per_ip: Bucket::new(30, Duration::from_secs(2)),
+    per_account: Bucket::new(20, Duration::from_secs(5)),
+};
 
 pub fn get_api() -> Scope {
     web::scope("/api")
         .service(
             web::scope("/notes")
+                .data(TokenAccess::new(TokenScope::NotesRead, TokenScope::NotesWrite))
                 .service(
                     web::resource("/")
                         .route(web::get().to_async(notes::get_user_notes))
@@ -19,13 +102,71 @@ pub fn get_api() -> Scope {
                 .service(
                     web::resource("/groups")
                         .route(web::get().to_async(groups::users_groups_notes)))
+                .service(
+                    web::resource("/shared")
+                        .route(web::get().to_async(notes::get_shared_with_me)))
+                .service(
+                    web::resource("/search")
+                        .route(web::get().to_async(notes::search)))
                 .service(
                     web::resource("/{id}")
                         .route(web::get().to_async(notes::get_note))
                         .route(web::patch().to_async(notes::update_note))
-                        .route(web::delete().to_async(notes::delete_note))))
+                        .route(web::delete().to_async(notes::delete_note)))
+                .service(
+                    web::resource("/{id}/tags")
+                        .route(web::get().to_async(tags::get_note_tags)))
+                .service(
+                    web::resource("/{id}/tags/{tag_id}")
+                        .route(web::put().to_async(tags::attach))
+                        .route(web::delete().to_async(tags::detach)))
+                .service(
+                    web::resource("/{id}/attachments")
+                        .route(web::get().to_async(attachments::list))
+                        .route(web::post().to_async(attachments::upload)))
+                .service(
+                    web::resource("/{id}/attachments/{attachment_id}")
+                        .route(web::get().to_async(attachments::download))
+                        .route(web::delete().to_async(attachments::delete)))
+                .service(
+                    web::resource("/{id}/grants")
+                        .route(web::get().to_async(grants::list))
+                        .route(web::post().to_async(grants::grant)))
+                .service(
+                    web::resource("/{id}/grants/{grant_id}")
+                        .route(web::delete().to_async(grants::revoke)))
+                .service(
+                    web::resource("/{id}/shares")
+                        .route(web::get().to_async(shares::list))
+                        .route(web::post().to_async(shares::insert)))
+                .service(
+                    web::resource("/{id}/shares/{share_id}")
+                        .route(web::delete().to_async(shares::revoke)))
+                .service(
+                    web::resource("/{id}/comments")
+                        .route(web::get().to_async(shares::get_comments)))
+                .service(
+                    web::resource("/{id}/reminders")
+                        .route(web::get().to_async(reminders::list))
+                        .route(web::post().to_async(reminders::insert)))
+                .service(
+                    web::resource("/{id}/reminders/{reminder_id}")
+                        .route(web::delete().to_async(reminders::delete)))
+                .service(
+                    web::resource("/{id}/revisions")
+                        .route(web::get().to_async(revisions::list)))
+                .service(
+                    web::resource("/{id}/revisions/diff")
+                        .route(web::get().to_async(revisions::diff)))
+                .service(
+                    web::resource("/{id}/revisions/{revision_id}")
+                        .route(web::get().to_async(revisions::get_revision)))
+                .service(
+                    web::resource("/{id}/revisions/{revision_id}/restore")
+                        .route(web::post().to_async(revisions::restore))))
         .service(
             web::scope("/groups")
+                .data(TokenAccess::new(TokenScope::GroupsRead, TokenScope::GroupsWrite))
                 .service(
                     web::resource("/")
                         .route(web::get().to_async(groups::get_user_groups))
@@ -36,27 +177,244 @@ pub fn get_api() -> Scope {
                 .service(
                     web::resource("/leave")
                         .route(web::post().to_async(groups::leave)))
+                .service(
+                    web::resource("/codes/redeem")
+                        .wrap(RateLimit::new(REDEMPTION_LIMITS))
+                        .route(web::post().to_async(join_codes::redeem)))
+                .service(
+                    web::resource("/invitations")
+                        .route(web::get().to_async(invitations::received)))
+                .service(
+                    web::resource("/invitations/{id}/accept")
+                        .route(web::post().to_async(invitations::accept)))
+                .service(
+                    web::resource("/invitations/{id}/decline")
+                        .route(web::post().to_async(invitations::decline)))
                 .service(
                     web::resource("/{id}")
+                        .data(TokenAccess::new(TokenScope::GroupsRead, TokenScope::GroupsAdmin))
                         .route(web::get().to_async(groups::group_notes))
-                        .route(web::delete().to_async(groups::delete))))
+                        .route(web::patch().to_async(groups::update))
+                        .route(web::delete().to_async(groups::delete)))
+                .service(
+                    web::resource("/{id}/invitations")
+                        .data(TokenAccess::only(TokenScope::GroupsAdmin))
+                        .route(web::get().to_async(invitations::list))
+                        .route(web::post().to_async(invitations::invite)))
+                .service(
+                    web::resource("/{id}/invitations/{invitation_id}")
+                        .data(TokenAccess::only(TokenScope::GroupsAdmin))
+                        .route(web::delete().to_async(invitations::revoke)))
+                .service(
+                    web::resource("/{id}/codes")
+                        .data(TokenAccess::only(TokenScope::GroupsAdmin))
+                        .route(web::get().to_async(join_codes::list))
+                        .route(web::post().to_async(join_codes::insert)))
+                .service(
+                    web::resource("/{id}/codes/{code_id}")
+                        .data(TokenAccess::only(TokenScope::GroupsAdmin))
+                        .route(web::delete().to_async(join_codes::revoke)))
+                .service(
+                    web::resource("/{id}/requests")
+                        .data(TokenAccess::only(TokenScope::GroupsAdmin))
+                        .route(web::get().to_async(invitations::requests)))
+                .service(
+                    web::resource("/{id}/requests/{request_id}/approve")
+                        .data(TokenAccess::only(TokenScope::GroupsAdmin))
+                        .route(web::post().to_async(invitations::approve)))
+                .service(
+                    web::resource("/{id}/requests/{request_id}/reject")
+                        .data(TokenAccess::only(TokenScope::GroupsAdmin))
+                        .route(web::post().to_async(invitations::reject)))
+                .service(
+                    web::resource("/{id}/members")
+                        .route(web::get().to_async(groups::members)))
+                .service(
+                    web::resource("/{id}/members/{user_id}")
+                        .data(TokenAccess::only(TokenScope::GroupsAdmin))
+                        .route(web::delete().to_async(groups::remove_member)))
+                .service(
+                    web::resource("/{id}/members/{user_id}/promote")
+                        .data(TokenAccess::only(TokenScope::GroupsAdmin))
+                        .route(web::post().to_async(groups::promote)))
+                .service(
+                    web::resource("/{id}/members/{user_id}/demote")
+                        .data(TokenAccess::only(TokenScope::GroupsAdmin))
+                        .route(web::post().to_async(groups::demote))))
+        .service(
+            web::scope("/trash")
+                .data(TokenAccess::new(TokenScope::NotesRead, TokenScope::NotesWrite))
+                .service(
+                    web::resource("/")
+                        .route(web::get().to_async(trash::list)))
+                .service(
+                    web::resource("/notes/{id}")
+                        .route(web::delete().to_async(trash::delete_note)))
+                .service(
+                    web::resource("/notes/{id}/restore")
+                        .route(web::post().to_async(trash::restore_note)))
+                .service(
+                    web::resource("/groups/{id}")
+                        .data(TokenAccess::only(TokenScope::GroupsAdmin))
+                        .route(web::delete().to_async(trash::delete_group)))
+                .service(
+                    web::resource("/groups/{id}/restore")
+                        .data(TokenAccess::only(TokenScope::GroupsAdmin))
+                        .route(web::post().to_async(trash::restore_group))))
+        .service(
+            web::scope("/shared")
+                .wrap(RateLimit::new(SHARE_LIMITS))
+                .service(
+                    web::resource("/{token}")
+                        .route(web::get().to_async(shares::view)))
+                .service(
+                    web::resource("/{token}/comments")
+                        .route(web::post().to_async(shares::comment))))
+        .service(
+            web::scope("/notifications")
+                .data(TokenAccess::new(TokenScope::NotesRead, TokenScope::NotesWrite))
+                .service(
+                    web::resource("/")
+                        .route(web::get().to_async(notifications::list)))
+                .service(
+                    web::resource("/read")
+                        .route(web::post().to_async(notifications::mark_all_read)))
+                .service(
+                    web::resource("/{id}/read")
+                        .route(web::post().to_async(notifications::mark_read))))
+        .service(
+            web::scope("/calendar")
+                .data(TokenAccess::new(TokenScope::NotesRead, TokenScope::NotesWrite))
+                .service(
+                    web::resource("/")
+                        .route(web::get().to_async(calendar::get_calendar)))
+                .service(
+                    web::resource("/ics")
+                        .route(web::get().to_async(calendar::export)))
+                .service(
+                    web::resource("/token")
+                        .route(web::post().to_async(calendar::create_token))
+                        .route(web::delete().to_async(calendar::revoke_token)))
+                .service(
+                    web::resource("/feed/{token}")
+                        .route(web::get().to_async(calendar::feed))))
+        .service(
+            web::scope("/tags")
+                .data(TokenAccess::new(TokenScope::NotesRead, TokenScope::NotesWrite))
+                .service(
+                    web::resource("/")
+                        .route(web::get().to_async(tags::get_user_tags))
+                        .route(web::post().to_async(tags::insert)))
+                .service(
+                    web::resource("/{id}")
+                        .route(web::patch().to_async(tags::rename))
+                        .route(web::delete().to_async(tags::delete))))
         .service(
             web::scope("/auth")
                 .service(
                     web::resource("/")
-                        .route(web::post().to_async(auth::login))
+                        .guard(guard::Post())
+                        .wrap(RateLimit::new(LOGIN_LIMITS))
+                        .route(web::post().to_async(auth::login)),
+                )
+                .service(
+                    web::resource("/")
                         .route(web::delete().to_async(auth::logout))
                         .route(web::get().to_async(auth::get_me)),
                 )
                 .service(
                     web::resource("/register/")
+                        .wrap(RateLimit::new(REGISTRATION_LIMITS))
                         .route(web::post().to_async(auth::register)))
                 .service(
                     web::resource("/register/{uuid}")
-                        .route(web::post().to_async(auth::confirm_registration))))
+                        .wrap(RateLimit::new(CONFIRMATION_LIMITS))
+                        .route(web::post().to_async(auth::confirm_registration)))
+                .service(
+                    web::resource("/token")
+                        .wrap(RateLimit::new(LOGIN_LIMITS))
+                        .route(web::post().to_async(auth::issue_token)))
+                .service(
+                    web::resource("/mfa")
+                        .wrap(RateLimit::new(MFA_LIMITS))
+                        .route(web::post().to_async(auth::complete_mfa)))
+                .service(
+                    web::resource("/token/refresh")
+                        .wrap(RateLimit::new(REFRESH_LIMITS))
+                        .route(web::post().to_async(auth::refresh_token)))
+                .service(
+                    web::resource("/token/revoke")
+                        .wrap(RateLimit::new(REFRESH_LIMITS))
+                        .route(web::post().to_async(auth::revoke_token)))
+                .service(
+                    web::resource("/sessions")
+                        .route(web::get().to_async(sessions::list))
+                        .route(web::delete().to_async(sessions::revoke_others)))
+                .service(
+                    web::resource("/sessions/{id}")
+                        .route(web::delete().to_async(sessions::revoke)))
+                .service(
+                    web::resource("/oidc")
+                        .route(web::get().to(oidc::providers)))
+                .service(
+                    web::resource("/oidc/{provider}/login")
+                        .route(web::get().to_async(oidc::login)))
+                .service(
+                    web::resource("/oidc/{provider}/link")
+                        .route(web::get().to_async(oidc::link)))
+                .service(
+                    web::resource("/oidc/{provider}/callback")
+                        .route(web::get().to_async(oidc::callback)))
+                .service(
+                    web::resource("/password/forgot")
+                        .wrap(RateLimit::new(PASSWORD_FORGOT_LIMITS))
+                        .route(web::post().to_async(auth::forgot_password)))
+                .service(
+                    web::resource("/password/reset/{token}")
+                        .wrap(RateLimit::new(PASSWORD_RESET_LIMITS))
+                        .route(web::post().to_async(auth::reset_password))))
         .service(
             web::scope("/users")
+                .service(
+                    web::resource("/me")
+                        .route(web::patch().to_async(users::update_profile))
+                        .route(web::delete().to_async(users::delete_me)))
+                .service(
+                    web::resource("/me/email")
+                        .route(web::post().to_async(users::change_email)))
+                .service(
+                    web::resource("/me/password")
+                        .route(web::post().to_async(users::change_password)))
+                .service(
+                    web::resource("/me/mfa")
+                        .route(web::get().to_async(mfa::status)))
+                .service(
+                    web::resource("/me/mfa/totp")
+                        .route(web::post().to_async(mfa::enroll))
+                        .route(web::delete().to_async(mfa::disable)))
+                .service(
+                    web::resource("/me/mfa/totp/confirm")
+                        .route(web::post().to_async(mfa::confirm)))
+                .service(
+                    web::resource("/me/tokens")
+                        .route(web::get().to_async(access_tokens::list))
+                        .route(web::post().to_async(access_tokens::insert)))
+                .service(
+                    web::resource("/me/tokens/{id}")
+                        .route(web::delete().to_async(access_tokens::revoke)))
+                .service(
+                    web::resource("/me/identities")
+                        .route(web::get().to_async(oidc::identities)))
+                .service(
+                    web::resource("/me/identities/{id}")
+                        .route(web::delete().to_async(oidc::unlink)))
+                .service(
+                    web::resource("/email/{token}")
+                        .route(web::post().to_async(users::confirm_email)))
                 .service(
                     web::resource("/{uuid}")
                         .route(web::get().to_async(users::get_user))))
+        .service(
+            web::resource("/ws")
+                .route(web::get().to_async(realtime::connect)))
 }
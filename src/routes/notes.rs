@@ -1,4 +1,6 @@
-use actix_web::{error::BlockingError, web, HttpResponse};
+use actix::Addr;
+use actix_web::{error::BlockingError, http::header, web, HttpRequest, HttpResponse};
+use chrono::Utc;
 use diesel::prelude::*;
 use diesel::r2d2::ConnectionManager;
 use futures::Future;
@@ -6,19 +8,160 @@ use r2d2::Pool;
 use uuid::Uuid;
 
 use crate::errors::ServiceError;
-use crate::models::{LoggedUser, NewNote, Note, NotePatch, GroupLink};
+use crate::models::{GroupRole, LoggedUser, NewNote, Note, NotePatch, NoteRevision, Page, SearchHit};
+use crate::notify::reschedule;
+use crate::policy::{self, deletable_note, editable_note, require_group_role, visible_note, NoteAction};
+use crate::realtime::{self, Hub};
+use crate::routes::pagination::ListQuery;
+use crate::routes::tags::set_note_tags;
 
 type SqlPool = Pool<ConnectionManager<SqliteConnection>>;
 
+const SEARCH_DEFAULT_LIMIT: i64 = 20;
+const SEARCH_MAX_LIMIT: i64 = 100;
+
+#[derive(Deserialize)]
+pub struct SearchQuery {
+    q: String,
+    limit: Option<i64>,
+}
+
+/// Turns free user input into an FTS5 match expression. Every term is quoted
+/// so operators and column filters typed by the user are matched literally,
+/// and the last term is matched as a prefix to support search-as-you-type.
+fn fts_match_expression(input: &str) -> Option<String> {
+    let terms: Vec<String> = input
+        .split_whitespace()
+        .map(|term| format!("\"{}\"", term.replace('"', "\"\"")))
+        .collect();
+    if terms.is_empty() {
+        return None;
+    }
+    Some(terms.join(" ") + "*")
+}
+
+/// Where `snippet` marks the matches. Control characters, so the snippet
+/// can be escaped before they become `<mark>` tags.
+const MATCH_START: char = '\u{2}';
+const MATCH_END: char = '\u{3}';
+
+/// Escapes a snippet for HTML and only then highlights its matches, so note
+/// content never reaches the client as markup.
+fn highlight(snippet: &str) -> String {
+    let mut out = String::with_capacity(snippet.len());
+    for c in snippet.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            MATCH_START => out.push_str("<mark>"),
+            MATCH_END => out.push_str("</mark>"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+pub fn search(
+    user: LoggedUser,
+    query: web::Query<SearchQuery>,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    use diesel::sql_types::{BigInt, Text};
+    web::block(move || -> Result<Vec<SearchHit>, ServiceError> {
+        let conn = pool.get().unwrap();
+        let expression = match fts_match_expression(&query.q) {
+            Some(expression) => expression,
+            None => return Err(ServiceError::BadRequest(
+                String::from("Search query must not be empty!")
+            )),
+        };
+        let limit = query.limit
+            .unwrap_or(SEARCH_DEFAULT_LIMIT)
+            .max(1)
+            .min(SEARCH_MAX_LIMIT);
+        let mut hits = diesel::sql_query(
+            "SELECT n.*, \
+                snippet(notes_fts, 0, char(2), char(3), '...', 8) AS title_snippet, \
+                snippet(notes_fts, 1, char(2), char(3), '...', 24) AS body_snippet, \
+                bm25(notes_fts, 5.0, 1.0) AS rank \
+            FROM notes_fts \
+            JOIN notes n ON n.search_id = notes_fts.rowid \
+            WHERE notes_fts MATCH ? \
+            AND n.deleted_at IS NULL \
+            AND (n.user_id = ? \
+                OR n.public = 1 \
+                OR n.id IN (SELECT r.note_id FROM note_grants r WHERE r.user_id = ?) \
+                OR n.group_id IN (SELECT l.group_id FROM group_links l \
+                    JOIN groups g ON g.id = l.group_id \
+                    WHERE l.user_id = ? AND g.deleted_at IS NULL)) \
+            ORDER BY rank \
+            LIMIT ?")
+            .bind::<Text, _>(&expression)
+            .bind::<Text, _>(&user.id)
+            .bind::<Text, _>(&user.id)
+            .bind::<Text, _>(&user.id)
+            .bind::<BigInt, _>(limit)
+            .load::<SearchHit>(&conn)?;
+        for hit in &mut hits {
+            hit.title_snippet = highlight(&hit.title_snippet);
+            hit.body_snippet = highlight(&hit.body_snippet);
+        }
+        Ok(hits)
+    })
+    .then(|res| match res {
+        Ok(t) => Ok(HttpResponse::Ok().json(t)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
+
 pub fn get_user_notes(
     user: LoggedUser,
+    list: web::Query<ListQuery>,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    use crate::schema::notes::dsl::*;
+    web::block(move || -> Result<Page<Note>, ServiceError> {
+        let conn = pool.get().unwrap();
+        let query = notes
+            .filter(user_id.eq(&user.id))
+            .filter(deleted_at.is_null())
+            .into_boxed();
+        list.notes_page(&conn, Some(&user), query)
+    })
+    .then(|res| match res {
+        Ok(t) => Ok(HttpResponse::Ok().json(t)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
+
+/// Notes other users granted the caller access to.
+pub fn get_shared_with_me(
+    user: LoggedUser,
+    list: web::Query<ListQuery>,
     pool: web::Data<SqlPool>,
 ) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    use crate::schema::note_grants::dsl as grants;
     use crate::schema::notes::dsl::*;
-    web::block(move || -> Result<Vec<Note>, ServiceError> {
+    web::block(move || -> Result<Page<Note>, ServiceError> {
         let conn = pool.get().unwrap();
-        let list_of_notes = notes.filter(user_id.eq(user.id)).load::<Note>(&conn)?;
-        Ok(list_of_notes)
+        let granted_ids = grants::note_grants
+            .filter(grants::user_id.eq(&user.id))
+            .select(grants::note_id)
+            .load::<String>(&conn)?;
+        let query = notes
+            .filter(id.eq_any(granted_ids))
+            .filter(deleted_at.is_null())
+            .into_boxed();
+        list.notes_page(&conn, Some(&user), query)
     })
     .then(|res| match res {
         Ok(t) => Ok(HttpResponse::Ok().json(t)),
@@ -30,13 +173,17 @@ pub fn get_user_notes(
 }
 
 pub fn get_public(
+    list: web::Query<ListQuery>,
     pool: web::Data<SqlPool>,
 ) -> impl Future<Item = HttpResponse, Error = ServiceError> {
     use crate::schema::notes::dsl::*;
-    web::block(move || -> Result<Vec<Note>, ServiceError> {
+    web::block(move || -> Result<Page<Note>, ServiceError> {
         let conn = pool.get().unwrap();
-        let list_of_notes = notes.filter(public.eq(1)).load::<Note>(&conn)?;
-        Ok(list_of_notes)
+        let query = notes
+            .filter(public.eq(1))
+            .filter(deleted_at.is_null())
+            .into_boxed();
+        list.notes_page(&conn, None, query)
     })
     .then(|res| match res {
         Ok(t) => Ok(HttpResponse::Ok().json(t)),
@@ -47,39 +194,91 @@ pub fn get_public(
     })
 }
 
+/// The `ETag` of a note, derived from its version.
+pub fn etag(note: &Note) -> String {
+    format!("\"{}\"", note.version)
+}
+
+/// Versions listed in an `If-Match` header. `None` when the header is absent
+/// or `*`, i.e. when any version may be overwritten.
+fn if_match(req: &HttpRequest) -> Option<Vec<i32>> {
+    let value = req.headers().get(header::IF_MATCH)?.to_str().unwrap_or("");
+    if value.trim() == "*" {
+        return None;
+    }
+    Some(
+        value
+            .split(',')
+            .filter_map(|tag| {
+                let tag = tag.trim();
+                let tag = if tag.starts_with("W/") { &tag[2..] } else { tag };
+                tag.trim_matches('"').parse::<i32>().ok()
+            })
+            .collect(),
+    )
+}
+
+fn conflict(note: &Note) -> ServiceError {
+    ServiceError::PreconditionFailed(serde_json::to_value(note).unwrap())
+}
+
+/// Runs `change` against a note the user may edit and records the resulting
+/// state as a new revision. Returns the updated note. When `expected` is
+/// given the note must still be at one of those versions, otherwise the
+/// edit fails with the current copy.
+pub fn edit_note<F>(
+    conn: &SqliteConnection,
+    user: &LoggedUser,
+    note_id: &str,
+    expected: Option<&[i32]>,
+    change: F,
+) -> Result<Note, ServiceError>
+where
+    F: FnOnce(&Note) -> Result<(), ServiceError>,
+{
+    use crate::schema::notes::dsl::*;
+    use crate::schema::note_revisions::dsl::note_revisions;
+    conn.transaction(|| {
+        let db_note = editable_note(conn, user, note_id)?;
+        if let Some(expected) = expected {
+            if !expected.contains(&db_note.version) {
+                return Err(conflict(&db_note));
+            }
+        }
+        let claimed = diesel::update(
+            notes.filter(id.eq(note_id)).filter(version.eq(db_note.version)))
+            .set((version.eq(version + 1), updated_at.eq(Utc::now().naive_utc())))
+            .execute(conn)?;
+        if claimed == 0 {
+            let current = notes.filter(id.eq(note_id)).first::<Note>(conn)?;
+            return Err(conflict(&current));
+        }
+        change(&db_note)?;
+        let updated_note = notes
+            .filter(id.eq(note_id))
+            .first::<Note>(conn)?;
+        diesel::insert_into(note_revisions)
+            .values(&NoteRevision::from(&updated_note, user))
+            .execute(conn)?;
+        if updated_note.date_tag != db_note.date_tag {
+            reschedule(conn, &updated_note)?;
+        }
+        Ok(updated_note)
+    })
+}
+
 pub fn get_note (
     user: LoggedUser,
     uuid: web::Path<Uuid>,
     pool: web::Data<SqlPool>,
 ) -> impl Future<Item = HttpResponse, Error = ServiceError> {
-    use crate::schema::notes::dsl::*;
-    use crate::schema::group_links::group_id as l_g_id;
     web::block(move || -> Result<Note, ServiceError> {
         let conn = pool.get().unwrap();
         let uuid = uuid.into_inner().to_string();
-        let note = notes
-            .filter(id.eq(&uuid))          
-            .first::<Note>(&conn)?;
-        if note.user_id != user.id && note.public != 1 {
-            //check groups
-            match &note.group_id {
-                Some(gid) => {
-                    let mut link = GroupLink::belonging_to(&user)
-                        .filter(l_g_id.eq(gid))
-                        .load::<GroupLink>(&conn)?;
-                    if let Some(_) = link.pop() {
-                        return Ok(note);
-                    } else {
-                        return Err(ServiceError::Forbidden);
-                    }
-                },
-                None => return Err(ServiceError::Forbidden),
-            }   
-        }
-        Ok(note)
+        visible_note(&conn, &user, &uuid)
     })
     .then(|res| match res {
-        Ok(t) => Ok(HttpResponse::Ok().json(t)),
+        Ok(t) => Ok(HttpResponse::Ok().header(header::ETAG, etag(&t)).json(t)),
         Err(err) => match err {
             BlockingError::Error(service_error) => Err(service_error),
             BlockingError::Canceled => Err(ServiceError::InternalServerError),
@@ -91,29 +290,26 @@ pub fn delete_note (
     user: LoggedUser,
     uuid: web::Path<Uuid>,
     pool: web::Data<SqlPool>,
+    hub: web::Data<Addr<Hub>>,
 ) -> impl Future<Item = HttpResponse, Error = ServiceError> {
     use crate::schema::notes::dsl::*;
-    
     web::block(move || -> Result<Note, ServiceError> {
         let conn = pool.get().unwrap();
         let uuid = uuid.into_inner().to_string();
-        let note = notes
-            .filter(id.eq(&uuid))          
+        let note = deletable_note(&conn, &user, &uuid)?;
+        diesel::update(&note)
+            .set(deleted_at.eq(Utc::now().naive_utc()))
+            .execute(&conn)?;
+        let trashed = notes
+            .filter(id.eq(&note.id))
             .first::<Note>(&conn)?;
-        if &note.user_id != &user.id {
-            return Err(ServiceError::Forbidden);
-        }
-        diesel::delete(
-                notes.filter(id.eq(&note.id))
-            ).execute(&conn)?;
-        diesel::delete(
-                GroupLink::belonging_to(&user)
-            ).execute(&conn)?;
-        
-        Ok(note)
+        Ok(trashed)
     })
-    .then(|res| match res {
-        Ok(t) => Ok(HttpResponse::Ok().json(t)),
+    .then(move |res| match res {
+        Ok(t) => {
+            realtime::note_deleted(&hub, &t);
+            Ok(HttpResponse::Ok().json(t))
+        }
         Err(err) => match err {
             BlockingError::Error(service_error) => Err(service_error),
             BlockingError::Canceled => Err(ServiceError::InternalServerError),
@@ -122,37 +318,54 @@ pub fn delete_note (
 }
 
 pub fn update_note(
+    req: HttpRequest,
     user: LoggedUser,
     uuid: web::Path<Uuid>,
     note: web::Json<NotePatch>,
     pool: web::Data<SqlPool>,
+    hub: web::Data<Addr<Hub>>,
 ) -> impl Future<Item = HttpResponse, Error = ServiceError> {
-    use crate::schema::notes::dsl::*;
-    web::block(move || -> Result<Note, ServiceError> {
+    let expected = if_match(&req);
+    web::block(move || -> Result<(Option<String>, Note), ServiceError> {
         let conn = pool.get().unwrap();
         let uuid = uuid.into_inner().to_string();
-        let note = note.into_inner();
-        let mut result = notes
-            .filter(id.eq(&uuid))
-            .load::<Note>(&conn)?;
-        if let Some(db_note) = result.pop(){
-            if &db_note.user_id != &user.id {
+        let patch = note.into_inner();
+        let mut previous_group = None;
+        let note = edit_note(&conn, &user, &uuid, expected.as_ref().map(Vec::as_slice), |db_note| {
+            previous_group = db_note.group_id.clone();
+            // moving, publishing and pinning are the owner's call, editors
+            // only change the contents
+            let changes = &patch.changes;
+            let manages_note = changes.user_id.is_some()
+                || changes.group_id.is_some()
+                || changes.public.is_some()
+                || changes.pinned.is_some();
+            if manages_note && !policy::allows(&conn, &user, db_note, NoteAction::Manage)? {
                 return Err(ServiceError::Forbidden);
             }
-            diesel::update(&db_note)
-                .set(&note)
-                .execute(&conn)?;
-            let updated_note = notes
-                .filter(id.eq(&uuid))
-                .first::<Note>(&conn)?;
-            return Ok(updated_note);
-        }
-        Err(ServiceError::BadRequest(
-            String::from("Invalid note identifiers!")
-        ))
+            // viewers can read a group's notes but not post into it
+            if let Some(target) = &patch.changes.group_id {
+                if db_note.group_id.as_ref() != Some(target) {
+                    require_group_role(&conn, &user, target, GroupRole::Member)?;
+                }
+            }
+            if !patch.changes.is_empty() {
+                diesel::update(db_note)
+                    .set(&patch.changes)
+                    .execute(&conn)?;
+            }
+            if let Some(tag_names) = &patch.tags {
+                set_note_tags(&conn, &user, &db_note.id, tag_names)?;
+            }
+            Ok(())
+        })?;
+        Ok((previous_group, note))
     })
-    .then(|res| match res {
-        Ok(t) => Ok(HttpResponse::Ok().json(t)),
+    .then(move |res| match res {
+        Ok((previous_group, t)) => {
+            realtime::note_saved(&hub, previous_group, &t);
+            Ok(HttpResponse::Ok().header(header::ETAG, etag(&t)).json(t))
+        }
         Err(err) => match err {
             BlockingError::Error(service_error) => Err(service_error),
             BlockingError::Canceled => Err(ServiceError::InternalServerError),
@@ -165,16 +378,34 @@ pub fn insert(
     user: LoggedUser,
     note: web::Json<NewNote>,
     pool: web::Data<SqlPool>,
+    hub: web::Data<Addr<Hub>>,
 ) -> impl Future<Item = HttpResponse, Error = ServiceError> {
     use crate::schema::notes::dsl::*;
     web::block(move || -> Result<Note, ServiceError> {
+        use crate::schema::note_revisions::dsl::note_revisions;
         let conn = pool.get().unwrap();
-        let note = Note::from(note.into_inner(), user);
-        diesel::insert_into(notes).values(&note).execute(&conn)?;
-        Ok(note)
+        let new_note = note.into_inner();
+        let tag_names = new_note.tags.clone();
+        if let Some(target) = &new_note.group_id {
+            require_group_role(&conn, &user, target, GroupRole::Member)?;
+        }
+        let note = Note::from(new_note, user.clone());
+        conn.transaction(|| {
+            diesel::insert_into(notes).values(&note).execute(&conn)?;
+            diesel::insert_into(note_revisions)
+                .values(&NoteRevision::from(&note, &user))
+                .execute(&conn)?;
+            if let Some(tag_names) = &tag_names {
+                set_note_tags(&conn, &user, &note.id, tag_names)?;
+            }
+            Ok(note)
+        })
     })
-    .then(|res| match res {
-        Ok(t) => Ok(HttpResponse::Ok().json(t)),
+    .then(move |res| match res {
+        Ok(t) => {
+            realtime::note_saved(&hub, None, &t);
+            Ok(HttpResponse::Ok().json(t))
+        }
         Err(err) => match err {
             BlockingError::Error(service_error) => Err(service_error),
             BlockingError::Canceled => Err(ServiceError::InternalServerError),
@@ -0,0 +1,109 @@
+use actix_web::{error::BlockingError, web, HttpResponse};
+use chrono::Utc;
+use diesel::prelude::*;
+use diesel::r2d2::ConnectionManager;
+use futures::Future;
+use r2d2::Pool;
+use uuid::Uuid;
+
+use crate::errors::ServiceError;
+use crate::models::{LoggedUser, Notification};
+
+type SqlPool = Pool<ConnectionManager<SqliteConnection>>;
+
+const DEFAULT_LIMIT: i64 = 50;
+const MAX_LIMIT: i64 = 200;
+
+#[derive(Deserialize)]
+pub struct InboxQuery {
+    unread: Option<i32>,
+    limit: Option<i64>,
+}
+
+/// The caller's in-app inbox, newest first.
+pub fn list(
+    user: LoggedUser,
+    query: web::Query<InboxQuery>,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    use crate::schema::notifications::dsl::*;
+    web::block(move || -> Result<Vec<Notification>, ServiceError> {
+        let conn = pool.get().unwrap();
+        let mut inbox = notifications
+            .filter(user_id.eq(&user.id))
+            .into_boxed();
+        if query.unread.unwrap_or(0) != 0 {
+            inbox = inbox.filter(read_at.is_null());
+        }
+        let limit = query.limit.unwrap_or(DEFAULT_LIMIT).max(1).min(MAX_LIMIT);
+        let notification_list = inbox
+            .order(created_at.desc())
+            .limit(limit)
+            .load::<Notification>(&conn)?;
+        Ok(notification_list)
+    })
+    .then(|res| match res {
+        Ok(t) => Ok(HttpResponse::Ok().json(t)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
+
+pub fn mark_read(
+    user: LoggedUser,
+    uuid: web::Path<Uuid>,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    use crate::schema::notifications::dsl::*;
+    web::block(move || -> Result<Notification, ServiceError> {
+        let conn = pool.get().unwrap();
+        let notification = notifications
+            .filter(id.eq(uuid.into_inner().to_string()))
+            .first::<Notification>(&conn)?;
+        if notification.user_id != user.id {
+            return Err(ServiceError::Forbidden);
+        }
+        diesel::update(&notification)
+            .filter(read_at.is_null())
+            .set(read_at.eq(Utc::now().naive_utc()))
+            .execute(&conn)?;
+        let updated = notifications
+            .filter(id.eq(&notification.id))
+            .first::<Notification>(&conn)?;
+        Ok(updated)
+    })
+    .then(|res| match res {
+        Ok(t) => Ok(HttpResponse::Ok().json(t)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
+
+pub fn mark_all_read(
+    user: LoggedUser,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    use crate::schema::notifications::dsl::*;
+    web::block(move || -> Result<usize, ServiceError> {
+        let conn = pool.get().unwrap();
+        let updated = diesel::update(
+            notifications
+                .filter(user_id.eq(&user.id))
+                .filter(read_at.is_null()),
+        )
+        .set(read_at.eq(Utc::now().naive_utc()))
+        .execute(&conn)?;
+        Ok(updated)
+    })
+    .then(|res| match res {
+        Ok(_) => Ok(HttpResponse::Ok().finish()),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
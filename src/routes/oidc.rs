@@ -0,0 +1,406 @@
+use actix_identity::Identity;
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::{error::BlockingError, http::header, web, HttpMessage, HttpRequest, HttpResponse};
+use chrono::Utc;
+use diesel::prelude::*;
+use diesel::r2d2::ConnectionManager;
+use futures::{future, Future};
+use r2d2::Pool;
+use std::env;
+
+use crate::email_service::frontend_link;
+use crate::errors::ServiceError;
+use crate::models::{ExternalIdentity, LoggedUser, OidcState, Session, User};
+use crate::oidc::{self, IdClaims, Provider};
+use crate::routes::auth::{mfa_challenge, remember, start_session, Device, Login};
+use crate::tokens;
+
+type SqlPool = Pool<ConnectionManager<SqliteConnection>>;
+
+fn unblock(err: BlockingError<ServiceError>) -> ServiceError {
+    match err {
+        BlockingError::Error(service_error) => service_error,
+        BlockingError::Canceled => ServiceError::InternalServerError,
+    }
+}
+
+fn find_provider(providers: &[Provider], name: &str) -> Result<Provider, ServiceError> {
+    providers
+        .iter()
+        .find(|provider| provider.name == name)
+        .cloned()
+        .ok_or_else(|| ServiceError::BadRequest(String::from("Unknown identity provider!")))
+}
+
+/// Holds the state of a login in the browser that started it, so a
+/// callback only completes in that browser.
+const STATE_COOKIE: &str = "oidc_state";
+
+fn state_cookie(state: &str) -> Cookie<'static> {
+    Cookie::build(STATE_COOKIE, state.to_string())
+        .path("/api/auth/oidc")
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .max_age(10 * 60)
+        .finish()
+}
+
+fn redirect(location: String) -> HttpResponse {
+    HttpResponse::Found()
+        .header(header::LOCATION, location)
+        .del_cookie(&state_cookie(""))
+        .finish()
+}
+
+#[derive(Serialize)]
+pub struct ProviderInfo {
+    name: String,
+    login_url: String,
+}
+
+pub fn providers(providers: web::Data<Vec<Provider>>) -> HttpResponse {
+    let list: Vec<ProviderInfo> = providers
+        .iter()
+        .map(|provider| ProviderInfo {
+            name: provider.name.clone(),
+            login_url: format!("/api/auth/oidc/{}/login", provider.name),
+        })
+        .collect();
+    HttpResponse::Ok().json(list)
+}
+
+/// Sends the browser to the provider's sign in page. The state, nonce and
+/// PKCE verifier of the login are kept until the provider calls back.
+fn start(
+    provider: Provider,
+    user: Option<LoggedUser>,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    oidc::discover(&provider).and_then(move |discovery| {
+        let state = tokens::generate();
+        let nonce = tokens::generate();
+        let (verifier, challenge) = oidc::pkce_pair();
+        let location = oidc::authorization_url(&provider, &discovery, &state, &nonce, &challenge);
+        let pending = OidcState::from(&provider.name, user.as_ref(), tokens::hash(&state), nonce, verifier);
+        web::block(move || -> Result<(), ServiceError> {
+            use crate::schema::oidc_states::dsl::*;
+            let conn = pool.get().unwrap();
+            diesel::delete(oidc_states.filter(expires_at.lt(Utc::now().naive_utc())))
+                .execute(&conn)?;
+            diesel::insert_into(oidc_states)
+                .values(&pending)
+                .execute(&conn)?;
+            Ok(())
+        })
+        .map_err(unblock)
+        .map(move |_| {
+            HttpResponse::Found()
+                .header(header::LOCATION, location)
+                .cookie(state_cookie(&state))
+                .finish()
+        })
+    })
+}
+
+pub fn login(
+    name: web::Path<String>,
+    providers: web::Data<Vec<Provider>>,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    future::result(find_provider(&providers, &name))
+        .and_then(move |provider| start(provider, None, pool))
+}
+
+/// Like `login`, but the provider's account is linked to the signed in
+/// user on the way back. This is how accounts with a password get a
+/// provider to sign in with.
+pub fn link(
+    user: LoggedUser,
+    name: web::Path<String>,
+    providers: web::Data<Vec<Provider>>,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    future::result(find_provider(&providers, &name))
+        .and_then(move |provider| start(provider, Some(user), pool))
+}
+
+#[derive(Deserialize)]
+pub struct Callback {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+}
+
+/// Uses up the login `state` belongs to. Each state works once, for the
+/// provider it was started with, and only for ten minutes.
+fn claim_state(conn: &SqliteConnection, provider_name: &str, state: &str) -> Result<OidcState, ServiceError> {
+    use crate::schema::oidc_states::dsl::*;
+    conn.transaction(|| {
+        let pending = oidc_states
+            .filter(state_hash.eq(tokens::hash(state)))
+            .filter(provider.eq(provider_name))
+            .filter(resolved.eq(0))
+            .filter(expires_at.gt(Utc::now().naive_utc()))
+            .first::<OidcState>(conn)
+            .optional()?
+            .ok_or(ServiceError::Unauthorized)?;
+        diesel::update(&pending).set(resolved.eq(1)).execute(conn)?;
+        Ok(pending)
+    })
+}
+
+/// The account an external identity signs in to. Known identities sign in
+/// to their linked account. New ones get a new account, or are linked to
+/// the account with the same email address if that one signs in through
+/// providers already. Either needs the provider to have verified the
+/// address. Accounts with a password have to link providers themselves,
+/// so nobody can take over an account by registering its address first.
+fn linked_user(conn: &SqliteConnection, provider_name: &str, claims: IdClaims) -> Result<User, ServiceError> {
+    use crate::schema::external_identities::dsl::*;
+    use crate::schema::users::dsl as users;
+    conn.transaction(|| {
+        let linked = external_identities
+            .filter(provider.eq(provider_name))
+            .filter(subject.eq(&claims.sub))
+            .first::<ExternalIdentity>(conn)
+            .optional()?;
+        if let Some(identity) = linked {
+            diesel::update(&identity)
+                .set((last_login_at.eq(Utc::now().naive_utc()), email.eq(&claims.email)))
+                .execute(conn)?;
+            let user = users::users
+                .filter(users::id.eq(&identity.user_id))
+                .first::<User>(conn)?;
+            return Ok(user);
+        }
+        let address = match (&claims.email, claims.email_verified) {
+            (Some(address), Some(true)) => address.clone(),
+            _ => {
+                return Err(ServiceError::BadRequest(String::from(
+                    "The identity provider did not share a verified email address!",
+                )))
+            }
+        };
+        let existing = users::users
+            .filter(users::email.eq(&address))
+            .first::<User>(conn)
+            .optional()?;
+        let user = match existing {
+            Some(user) => {
+                let provisioned = external_identities
+                    .filter(user_id.eq(&user.id))
+                    .count()
+                    .get_result::<i64>(conn)?
+                    > 0;
+                if !provisioned {
+                    return Err(ServiceError::BadRequest(String::from(
+                        "An account with this email address exists already. Sign in with your password and link the provider in your account settings!",
+                    )));
+                }
+                user
+            }
+            None => {
+                let name = claims
+                    .name
+                    .clone()
+                    .or_else(|| claims.preferred_username.clone())
+                    .unwrap_or_else(|| address.clone());
+                let user = User::provisioned(name, address.clone());
+                diesel::insert_into(users::users)
+                    .values(&user)
+                    .execute(conn)?;
+                user
+            }
+        };
+        diesel::insert_into(external_identities)
+            .values(&ExternalIdentity::from(&user, provider_name, claims.sub, Some(address)))
+            .execute(conn)?;
+        Ok(user)
+    })
+}
+
+/// Links the provider's account to the user who started linking it.
+fn link_identity(
+    conn: &SqliteConnection,
+    provider_name: &str,
+    user: &str,
+    claims: IdClaims,
+) -> Result<(), ServiceError> {
+    use crate::schema::external_identities::dsl::*;
+    use crate::schema::users::dsl as users;
+    conn.transaction(|| {
+        let linked = external_identities
+            .filter(provider.eq(provider_name))
+            .filter(subject.eq(&claims.sub))
+            .first::<ExternalIdentity>(conn)
+            .optional()?;
+        match linked {
+            Some(identity) if identity.user_id == user => Ok(()),
+            Some(_) => Err(ServiceError::BadRequest(String::from(
+                "This account of the provider is linked to someone else!",
+            ))),
+            None => {
+                let account = users::users.filter(users::id.eq(user)).first::<User>(conn)?;
+                diesel::insert_into(external_identities)
+                    .values(&ExternalIdentity::from(&account, provider_name, claims.sub, claims.email))
+                    .execute(conn)?;
+                Ok(())
+            }
+        }
+    })
+}
+
+/// What a callback ends in.
+enum Outcome {
+    SignedIn(Login<Session>),
+    Linked,
+}
+
+/// Where the provider sends the browser back to. Checks the state, trades
+/// the code for an ID token, validates it against the provider's keys and
+/// signs the linked account in with a cookie session. Accounts with
+/// two-factor authentication are sent to `OIDC_MFA_REDIRECT` with an
+/// `mfa_token` in the fragment, to finish at `/auth/mfa` as usual.
+pub fn callback(
+    req: HttpRequest,
+    name: web::Path<String>,
+    query: web::Query<Callback>,
+    id: Identity,
+    providers: web::Data<Vec<Provider>>,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    let device = Device::of(&req);
+    let query = query.into_inner();
+    let started = find_provider(&providers, &name).and_then(|provider| {
+        if let Some(error) = query.error {
+            return Err(ServiceError::BadRequest(format!(
+                "The identity provider refused the login: {}",
+                error
+            )));
+        }
+        let (code, state) = match (query.code, query.state) {
+            (Some(code), Some(state)) => (code, state),
+            _ => return Err(ServiceError::BadRequest(String::from("Missing code or state!"))),
+        };
+        // a callback from a login started somewhere else, maybe to sign
+        // this browser in to someone else's account
+        match req.cookie(STATE_COOKIE) {
+            Some(cookie) if cookie.value() == state => Ok((provider, code, state)),
+            _ => Err(ServiceError::Unauthorized),
+        }
+    });
+    let claim_pool = pool.clone();
+    future::result(started)
+        .and_then(move |(provider, code, state)| {
+            let provider_name = provider.name.clone();
+            web::block(move || claim_state(&claim_pool.get().unwrap(), &provider_name, &state))
+                .map_err(unblock)
+                .and_then(move |pending| {
+                    let linking = pending.user_id.clone();
+                    oidc::discover(&provider).and_then(move |discovery| {
+                        oidc::exchange_code(&provider, &discovery, &code, &pending.code_verifier)
+                            .and_then(move |response| {
+                                oidc::validate_id_token(
+                                    &provider,
+                                    &discovery,
+                                    response.id_token,
+                                    pending.nonce,
+                                )
+                                .map(move |claims| (provider, linking, claims))
+                            })
+                    })
+                })
+        })
+        .and_then(move |(provider, linking, claims)| {
+            web::block(move || -> Result<Outcome, ServiceError> {
+                let conn = pool.get().unwrap();
+                if let Some(user) = linking {
+                    link_identity(&conn, &provider.name, &user, claims)?;
+                    return Ok(Outcome::Linked);
+                }
+                let user = linked_user(&conn, &provider.name, claims)?;
+                if let Some(pending) = mfa_challenge(&conn, &user, "cookie")? {
+                    return Ok(Outcome::SignedIn(Login::Pending(pending)));
+                }
+                let session = start_session(&conn, &user, "cookie", device)?;
+                Ok(Outcome::SignedIn(Login::Complete(session)))
+            })
+            .map_err(unblock)
+        })
+        .map(move |outcome| match outcome {
+            Outcome::Linked => {
+                let path = env::var("OIDC_LINK_REDIRECT").unwrap_or_else(|_| "/settings".into());
+                redirect(frontend_link(&path))
+            }
+            Outcome::SignedIn(Login::Complete(session)) => {
+                remember(&id, &session);
+                let path = env::var("OIDC_LOGIN_REDIRECT").unwrap_or_else(|_| "/".into());
+                redirect(frontend_link(&path))
+            }
+            Outcome::SignedIn(Login::Pending(pending)) => {
+                let path = env::var("OIDC_MFA_REDIRECT").unwrap_or_else(|_| "/login/mfa".into());
+                let fragment = serde_urlencoded::to_string(&pending).unwrap();
+                redirect(format!("{}#{}", frontend_link(&path), fragment))
+            }
+        })
+}
+
+pub fn identities(
+    user: LoggedUser,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    use crate::schema::external_identities::dsl::*;
+    web::block(move || -> Result<Vec<ExternalIdentity>, ServiceError> {
+        let conn = pool.get().unwrap();
+        let list = external_identities
+            .filter(user_id.eq(&user.id))
+            .order_by(created_at.asc())
+            .load::<ExternalIdentity>(&conn)?;
+        Ok(list)
+    })
+    .then(|res| match res {
+        Ok(t) => Ok(HttpResponse::Ok().json(t)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
+
+/// Unlinks a provider. The last one stays while the account has no
+/// password, so it can still be signed in to.
+pub fn unlink(
+    user: LoggedUser,
+    identity: web::Path<String>,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    use crate::schema::external_identities::dsl::*;
+    use crate::schema::users::dsl as users;
+    web::block(move || -> Result<(), ServiceError> {
+        let conn = pool.get().unwrap();
+        conn.transaction(|| {
+            let linked = external_identities
+                .filter(user_id.eq(&user.id))
+                .load::<ExternalIdentity>(&conn)?;
+            let target = linked
+                .iter()
+                .find(|linked| linked.id == *identity)
+                .ok_or_else(|| ServiceError::BadRequest(String::from("Invalid identity!")))?;
+            let account = users::users.filter(users::id.eq(&user.id)).first::<User>(&conn)?;
+            if linked.len() == 1 && account.password.is_empty() {
+                return Err(ServiceError::BadRequest(String::from(
+                    "Set a password before unlinking the last provider!",
+                )));
+            }
+            diesel::delete(target).execute(&conn)?;
+            Ok(())
+        })
+    })
+    .then(|res| match res {
+        Ok(_) => Ok(HttpResponse::Ok().finish()),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
@@ -0,0 +1,254 @@
+use chrono::{Duration, NaiveDate, NaiveDateTime};
+use diesel::prelude::*;
+use diesel::sqlite::Sqlite;
+
+use crate::errors::ServiceError;
+use crate::models::{Group, LoggedUser, Note, Page};
+use crate::routes::tags::TagFilter;
+use crate::schema::{groups, notes};
+
+const DEFAULT_LIMIT: i64 = 50;
+const MAX_LIMIT: i64 = 200;
+
+/// Shared query string for list endpoints: page size, the opaque cursor
+/// returned as `next_cursor` by the previous page, sort order and filters.
+/// Filters that do not apply to a listing are ignored.
+#[derive(Deserialize)]
+pub struct ListQuery {
+    limit: Option<i64>,
+    cursor: Option<String>,
+    sort: Option<String>,
+    pinned: Option<i32>,
+    public: Option<i32>,
+    from: Option<String>,
+    to: Option<String>,
+    group_id: Option<String>,
+    tags: Option<String>,
+    #[serde(rename = "match")]
+    tag_match: Option<String>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Sort {
+    Title,
+    DateTag,
+    PinnedFirst,
+}
+
+impl Sort {
+    fn name(self) -> &'static str {
+        match self {
+            Sort::Title => "title",
+            Sort::DateTag => "date_tag",
+            Sort::PinnedFirst => "pinned",
+        }
+    }
+}
+
+/// Position after the last row of a page: the sort key of that row and its id
+/// as a tie breaker. Sent to clients base64 encoded.
+#[derive(Serialize, Deserialize)]
+struct Cursor {
+    sort: String,
+    key: Option<String>,
+    id: String,
+}
+
+impl Cursor {
+    fn encode(&self) -> String {
+        base64::encode_config(
+            &serde_json::to_vec(self).unwrap(),
+            base64::URL_SAFE_NO_PAD,
+        )
+    }
+
+    fn decode(raw: &str) -> Result<Self, ServiceError> {
+        base64::decode_config(raw, base64::URL_SAFE_NO_PAD)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .ok_or_else(|| ServiceError::BadRequest(String::from("Invalid cursor!")))
+    }
+}
+
+pub fn parse_bound(value: &str, end_of_day: bool) -> Result<NaiveDateTime, ServiceError> {
+    if let Ok(date_time) = NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S") {
+        return Ok(date_time);
+    }
+    match NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        Ok(date) if end_of_day => Ok(date.and_hms(0, 0, 0) + Duration::days(1)),
+        Ok(date) => Ok(date.and_hms(0, 0, 0)),
+        Err(_) => Err(ServiceError::BadRequest(format!(
+            "Invalid date '{}', expected YYYY-MM-DD or YYYY-MM-DD HH:MM:SS",
+            value
+        ))),
+    }
+}
+
+impl ListQuery {
+    fn limit(&self) -> i64 {
+        self.limit.unwrap_or(DEFAULT_LIMIT).max(1).min(MAX_LIMIT)
+    }
+
+    fn sort(&self) -> Result<Sort, ServiceError> {
+        match self.sort.as_ref().map(String::as_str) {
+            None | Some("pinned") => Ok(Sort::PinnedFirst),
+            Some("title") => Ok(Sort::Title),
+            Some("date_tag") => Ok(Sort::DateTag),
+            Some(other) => Err(ServiceError::BadRequest(format!(
+                "Unknown sort '{}', expected title, date_tag or pinned",
+                other
+            ))),
+        }
+    }
+
+    /// The cursor to continue from, checked against the requested sort so a
+    /// cursor from one ordering cannot be replayed against another.
+    fn cursor(&self, sort: Sort) -> Result<Option<Cursor>, ServiceError> {
+        match &self.cursor {
+            Some(raw) => {
+                let cursor = Cursor::decode(raw)?;
+                if cursor.sort != sort.name() {
+                    return Err(ServiceError::BadRequest(
+                        String::from("Cursor does not match the requested sort!")
+                    ));
+                }
+                Ok(Some(cursor))
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub fn tag_filter(&self) -> TagFilter {
+        TagFilter::new(self.tags.clone(), self.tag_match.clone())
+    }
+
+    /// Applies filters, sort order and the cursor to a note query and loads
+    /// one page of it. Tag filters need a caller and are skipped without one.
+    pub fn notes_page<'a>(
+        &self,
+        conn: &SqliteConnection,
+        user: Option<&LoggedUser>,
+        mut query: notes::BoxedQuery<'a, Sqlite>,
+    ) -> Result<Page<Note>, ServiceError> {
+        use crate::schema::notes::dsl::*;
+        if let Some(value) = self.pinned {
+            query = query.filter(pinned.eq(value));
+        }
+        if let Some(value) = self.public {
+            query = query.filter(public.eq(value));
+        }
+        if let Some(value) = &self.from {
+            query = query.filter(date_tag.ge(parse_bound(value, false)?));
+        }
+        if let Some(value) = &self.to {
+            query = query.filter(date_tag.lt(parse_bound(value, true)?));
+        }
+        if let Some(value) = &self.group_id {
+            query = query.filter(group_id.eq(value.clone()));
+        }
+        if let Some(user) = user {
+            if let Some(ids) = self.tag_filter().note_ids(conn, user)? {
+                query = query.filter(id.eq_any(ids));
+            }
+        }
+
+        let sort = self.sort()?;
+        if let Some(cursor) = self.cursor(sort)? {
+            let after = cursor.id;
+            query = match (sort, cursor.key) {
+                (Sort::Title, Some(key)) => query.filter(
+                    title.gt(key.clone()).or(title.eq(key).and(id.gt(after)))),
+                (Sort::DateTag, Some(key)) => {
+                    let key = NaiveDateTime::parse_from_str(&key, "%Y-%m-%d %H:%M:%S%.f")
+                        .map_err(|_| ServiceError::BadRequest(String::from("Invalid cursor!")))?;
+                    query.filter(
+                        date_tag.is_null()
+                            .or(date_tag.gt(key))
+                            .or(date_tag.eq(key).and(id.gt(after))))
+                }
+                (Sort::DateTag, None) => query.filter(date_tag.is_null().and(id.gt(after))),
+                (Sort::PinnedFirst, Some(key)) => {
+                    let key = key.parse::<i32>()
+                        .map_err(|_| ServiceError::BadRequest(String::from("Invalid cursor!")))?;
+                    query.filter(pinned.lt(key).or(pinned.eq(key).and(id.gt(after))))
+                }
+                (_, None) => return Err(ServiceError::BadRequest(String::from("Invalid cursor!"))),
+            };
+        }
+        query = match sort {
+            Sort::Title => query.order_by(title.asc()).then_order_by(id.asc()),
+            // notes without a date go last
+            Sort::DateTag => query
+                .order_by(date_tag.is_null().asc())
+                .then_order_by(date_tag.asc())
+                .then_order_by(id.asc()),
+            Sort::PinnedFirst => query.order_by(pinned.desc()).then_order_by(id.asc()),
+        };
+
+        let limit = self.limit();
+        let mut items = query.limit(limit + 1).load::<Note>(conn)?;
+        let next_cursor = if items.len() as i64 > limit {
+            items.truncate(limit as usize);
+            items.last().map(|last| Cursor {
+                sort: sort.name().to_string(),
+                key: match sort {
+                    Sort::Title => Some(last.title.clone()),
+                    Sort::DateTag => last.date_tag.map(|date| date.format("%Y-%m-%d %H:%M:%S%.f").to_string()),
+                    Sort::PinnedFirst => Some(last.pinned.to_string()),
+                },
+                id: last.id.clone(),
+            }.encode())
+        } else {
+            None
+        };
+        Ok(Page { items, next_cursor })
+    }
+
+    /// Loads one page of a group query. Groups are sorted by name, or by
+    /// creation time for `sort=date_tag`; note filters do not apply.
+    pub fn groups_page<'a>(
+        &self,
+        conn: &SqliteConnection,
+        mut query: groups::BoxedQuery<'a, Sqlite>,
+    ) -> Result<Page<Group>, ServiceError> {
+        use crate::schema::groups::dsl::*;
+        let sort = match self.sort()? {
+            Sort::DateTag => Sort::DateTag,
+            _ => Sort::Title,
+        };
+        if let Some(cursor) = self.cursor(sort)? {
+            let after = cursor.id;
+            let key = cursor.key
+                .ok_or_else(|| ServiceError::BadRequest(String::from("Invalid cursor!")))?;
+            query = match sort {
+                Sort::DateTag => {
+                    let key = NaiveDateTime::parse_from_str(&key, "%Y-%m-%d %H:%M:%S%.f")
+                        .map_err(|_| ServiceError::BadRequest(String::from("Invalid cursor!")))?;
+                    query.filter(created_at.gt(key).or(created_at.eq(key).and(id.gt(after))))
+                }
+                _ => query.filter(name.gt(key.clone()).or(name.eq(key).and(id.gt(after)))),
+            };
+        }
+        query = match sort {
+            Sort::DateTag => query.order_by(created_at.asc()).then_order_by(id.asc()),
+            _ => query.order_by(name.asc()).then_order_by(id.asc()),
+        };
+
+        let limit = self.limit();
+        let mut items = query.limit(limit + 1).load::<Group>(conn)?;
+        let next_cursor = if items.len() as i64 > limit {
+            items.truncate(limit as usize);
+            items.last().map(|last| Cursor {
+                sort: sort.name().to_string(),
+                key: Some(match sort {
+                    Sort::DateTag => last.created_at.format("%Y-%m-%d %H:%M:%S%.f").to_string(),
+                    _ => last.name.clone(),
+                }),
+                id: last.id.clone(),
+            }.encode())
+        } else {
+            None
+        };
+        Ok(Page { items, next_cursor })
+    }
+}
@@ -0,0 +1,51 @@
+use actix::Addr;
+use actix_web::{error::BlockingError, web, Error, HttpRequest, HttpResponse};
+use actix_web_actors::ws;
+use diesel::prelude::*;
+use diesel::r2d2::ConnectionManager;
+use futures::Future;
+use r2d2::Pool;
+
+use crate::errors::ServiceError;
+use crate::models::LoggedUser;
+use crate::realtime::{Hub, WsSession};
+
+type SqlPool = Pool<ConnectionManager<SqliteConnection>>;
+
+/// Upgrades to a WebSocket that receives events for all of the user's live
+/// groups.
+pub fn connect(
+    req: HttpRequest,
+    stream: web::Payload,
+    user: LoggedUser,
+    pool: web::Data<SqlPool>,
+    hub: web::Data<Addr<Hub>>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    use crate::schema::group_links::dsl as links;
+    use crate::schema::groups::dsl as groups;
+    let user_id = user.id.clone();
+    web::block(move || -> Result<Vec<String>, ServiceError> {
+        let conn = pool.get().unwrap();
+        let group_ids = links::group_links
+            .filter(links::user_id.eq(&user.id))
+            .select(links::group_id)
+            .load::<String>(&conn)?;
+        let live_ids = groups::groups
+            .filter(groups::id.eq_any(group_ids))
+            .filter(groups::deleted_at.is_null())
+            .select(groups::id)
+            .load::<String>(&conn)?;
+        Ok(live_ids)
+    })
+    .map_err(|err| match err {
+        BlockingError::Error(service_error) => Error::from(service_error),
+        BlockingError::Canceled => Error::from(ServiceError::InternalServerError),
+    })
+    .and_then(move |group_ids| {
+        ws::start(
+            WsSession::new(user_id, group_ids, hub.get_ref().clone()),
+            &req,
+            stream,
+        )
+    })
+}
@@ -0,0 +1,119 @@
+use actix_web::{error::BlockingError, web, HttpResponse};
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use diesel::r2d2::ConnectionManager;
+use futures::Future;
+use r2d2::Pool;
+use uuid::Uuid;
+
+use crate::errors::ServiceError;
+use crate::models::{LoggedUser, NewReminder, Reminder};
+use crate::policy::visible_note;
+
+type SqlPool = Pool<ConnectionManager<SqliteConnection>>;
+
+/// Lists the caller's reminders on a note.
+pub fn list(
+    user: LoggedUser,
+    uuid: web::Path<Uuid>,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    use crate::schema::reminders::dsl::*;
+    web::block(move || -> Result<Vec<Reminder>, ServiceError> {
+        let conn = pool.get().unwrap();
+        let note = visible_note(&conn, &user, &uuid.into_inner().to_string())?;
+        let reminder_list = Reminder::belonging_to(&note)
+            .filter(user_id.eq(&user.id))
+            .order(created_at.asc())
+            .load::<Reminder>(&conn)?;
+        Ok(reminder_list)
+    })
+    .then(|res| match res {
+        Ok(t) => Ok(HttpResponse::Ok().json(t)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
+
+pub fn insert(
+    user: LoggedUser,
+    uuid: web::Path<Uuid>,
+    new_reminder: web::Json<NewReminder>,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    use crate::schema::reminders::dsl::*;
+    web::block(move || -> Result<Reminder, ServiceError> {
+        let conn = pool.get().unwrap();
+        let note = visible_note(&conn, &user, &uuid.into_inner().to_string())?;
+        let new_reminder = new_reminder.into_inner();
+        let reminder = match (new_reminder.offset_minutes, new_reminder.remind_at) {
+            (Some(offset), None) => {
+                if offset < 0 {
+                    return Err(ServiceError::BadRequest(
+                        String::from("Reminder offset can not be negative!")
+                    ));
+                }
+                if note.date_tag.is_none() {
+                    return Err(ServiceError::BadRequest(
+                        String::from("Offset reminders need a note with a date!")
+                    ));
+                }
+                Reminder::from(&note, &user, Some(offset), None)
+            }
+            (None, Some(at)) => {
+                let at = NaiveDateTime::parse_from_str(&at, "%Y-%m-%d %H:%M:%S")
+                    .map_err(|_| ServiceError::BadRequest(format!(
+                        "Invalid date '{}', expected YYYY-MM-DD HH:MM:SS",
+                        at
+                    )))?;
+                Reminder::from(&note, &user, None, Some(at))
+            }
+            _ => {
+                return Err(ServiceError::BadRequest(
+                    String::from("Set either offset_minutes or remind_at!")
+                ))
+            }
+        };
+        diesel::insert_into(reminders)
+            .values(&reminder)
+            .execute(&conn)?;
+        Ok(reminder)
+    })
+    .then(|res| match res {
+        Ok(t) => Ok(HttpResponse::Ok().json(t)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
+
+pub fn delete(
+    user: LoggedUser,
+    path: web::Path<(Uuid, Uuid)>,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    use crate::schema::reminders::dsl::*;
+    web::block(move || -> Result<Reminder, ServiceError> {
+        let conn = pool.get().unwrap();
+        let (note_uuid, reminder_uuid) = path.into_inner();
+        let reminder = reminders
+            .filter(id.eq(reminder_uuid.to_string()))
+            .filter(note_id.eq(note_uuid.to_string()))
+            .first::<Reminder>(&conn)?;
+        if reminder.user_id != user.id {
+            return Err(ServiceError::Forbidden);
+        }
+        diesel::delete(&reminder).execute(&conn)?;
+        Ok(reminder)
+    })
+    .then(|res| match res {
+        Ok(t) => Ok(HttpResponse::Ok().json(t)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
@@ -0,0 +1,156 @@
+use actix::Addr;
+use actix_web::{error::BlockingError, web, HttpResponse};
+use diesel::prelude::*;
+use diesel::r2d2::ConnectionManager;
+use futures::Future;
+use r2d2::Pool;
+use uuid::Uuid;
+
+use crate::diff::{line_diff, DiffLine};
+use crate::errors::ServiceError;
+use crate::models::{LoggedUser, Note, NoteRevision};
+use crate::policy::{allows, visible_note, NoteAction};
+use crate::realtime::{self, Hub};
+use crate::routes::notes::edit_note;
+
+type SqlPool = Pool<ConnectionManager<SqliteConnection>>;
+
+#[derive(Deserialize)]
+pub struct DiffQuery {
+    from: Uuid,
+    to: Uuid,
+}
+
+#[derive(Serialize)]
+pub struct RevisionDiff {
+    from: NoteRevision,
+    to: NoteRevision,
+    title: Vec<DiffLine>,
+    body: Vec<DiffLine>,
+}
+
+fn load_revision(
+    conn: &SqliteConnection,
+    note: &Note,
+    revision_id: &str,
+) -> Result<NoteRevision, ServiceError> {
+    use crate::schema::note_revisions::dsl::*;
+    let mut result = NoteRevision::belonging_to(note)
+        .filter(id.eq(revision_id))
+        .load::<NoteRevision>(conn)?;
+    match result.pop() {
+        Some(revision) => Ok(revision),
+        None => Err(ServiceError::BadRequest(
+            String::from("Invalid revision identifiers!")
+        )),
+    }
+}
+
+pub fn list(
+    user: LoggedUser,
+    uuid: web::Path<Uuid>,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    use crate::schema::note_revisions::dsl::*;
+    web::block(move || -> Result<Vec<NoteRevision>, ServiceError> {
+        let conn = pool.get().unwrap();
+        let note = visible_note(&conn, &user, &uuid.into_inner().to_string())?;
+        let revisions = NoteRevision::belonging_to(&note)
+            .order(created_at.desc())
+            .load::<NoteRevision>(&conn)?;
+        Ok(revisions)
+    })
+    .then(|res| match res {
+        Ok(t) => Ok(HttpResponse::Ok().json(t)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
+
+pub fn get_revision(
+    user: LoggedUser,
+    path: web::Path<(Uuid, Uuid)>,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    web::block(move || -> Result<NoteRevision, ServiceError> {
+        let conn = pool.get().unwrap();
+        let (note_uuid, revision_uuid) = path.into_inner();
+        let note = visible_note(&conn, &user, &note_uuid.to_string())?;
+        load_revision(&conn, &note, &revision_uuid.to_string())
+    })
+    .then(|res| match res {
+        Ok(t) => Ok(HttpResponse::Ok().json(t)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
+
+pub fn diff(
+    user: LoggedUser,
+    uuid: web::Path<Uuid>,
+    query: web::Query<DiffQuery>,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    web::block(move || -> Result<RevisionDiff, ServiceError> {
+        let conn = pool.get().unwrap();
+        let note = visible_note(&conn, &user, &uuid.into_inner().to_string())?;
+        let from = load_revision(&conn, &note, &query.from.to_string())?;
+        let to = load_revision(&conn, &note, &query.to.to_string())?;
+        let too_large = || {
+            ServiceError::BadRequest(String::from("These revisions differ in too many lines to compare!"))
+        };
+        Ok(RevisionDiff {
+            title: line_diff(&from.title, &to.title).ok_or_else(too_large)?,
+            body: line_diff(&from.body, &to.body).ok_or_else(too_large)?,
+            from,
+            to,
+        })
+    })
+    .then(|res| match res {
+        Ok(t) => Ok(HttpResponse::Ok().json(t)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
+
+pub fn restore(
+    user: LoggedUser,
+    path: web::Path<(Uuid, Uuid)>,
+    pool: web::Data<SqlPool>,
+    hub: web::Data<Addr<Hub>>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    web::block(move || -> Result<Note, ServiceError> {
+        let conn = pool.get().unwrap();
+        let (note_uuid, revision_uuid) = path.into_inner();
+        let note_uuid = note_uuid.to_string();
+        edit_note(&conn, &user, &note_uuid, None, |db_note| {
+            let revision = load_revision(&conn, db_note, &revision_uuid.to_string())?;
+            // bringing back an old public or pinned flag is the owner's call,
+            // same as when patching the note
+            let manages_note = revision.public != db_note.public || revision.pinned != db_note.pinned;
+            if manages_note && !allows(&conn, &user, db_note, NoteAction::Manage)? {
+                return Err(ServiceError::Forbidden);
+            }
+            diesel::update(db_note)
+                .set(&revision.restore())
+                .execute(&conn)?;
+            Ok(())
+        })
+    })
+    .then(move |res| match res {
+        Ok(t) => {
+            realtime::note_saved(&hub, t.group_id.clone(), &t);
+            Ok(HttpResponse::Ok().json(t))
+        }
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
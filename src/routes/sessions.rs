@@ -0,0 +1,105 @@
+use actix_identity::Identity;
+use actix_web::{error::BlockingError, web, HttpResponse};
+use chrono::Utc;
+use diesel::prelude::*;
+use diesel::r2d2::ConnectionManager;
+use futures::Future;
+use r2d2::Pool;
+use uuid::Uuid;
+
+use crate::errors::ServiceError;
+use crate::models::{ListedSession, Session};
+use crate::routes::auth::{end_other_sessions, end_session, CurrentSession};
+
+type SqlPool = Pool<ConnectionManager<SqliteConnection>>;
+
+/// The live sessions of the logged in user, most recently used first.
+pub fn list(
+    current: CurrentSession,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    use crate::schema::sessions::dsl::*;
+    web::block(move || -> Result<Vec<ListedSession>, ServiceError> {
+        let conn = pool.get().unwrap();
+        let session_list = sessions
+            .filter(user_id.eq(&current.user.id))
+            .filter(revoked_at.is_null())
+            .filter(expires_at.gt(Utc::now().naive_utc()))
+            .order(last_seen_at.desc())
+            .load::<Session>(&conn)?
+            .into_iter()
+            .map(|session| ListedSession {
+                current: session.id == current.id,
+                session,
+            })
+            .collect();
+        Ok(session_list)
+    })
+    .then(|res| match res {
+        Ok(t) => Ok(HttpResponse::Ok().json(t)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
+
+/// Ends one of the user's sessions. Ending the current one also logs out.
+pub fn revoke(
+    id: Identity,
+    current: CurrentSession,
+    session_id: web::Path<Uuid>,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    use crate::schema::sessions::dsl::{id as s_id, sessions, user_id};
+    web::block(move || -> Result<bool, ServiceError> {
+        let conn = pool.get().unwrap();
+        let session = sessions
+            .filter(s_id.eq(session_id.into_inner().to_string()))
+            .filter(user_id.eq(&current.user.id))
+            .first::<Session>(&conn)
+            .optional()?
+            .ok_or(ServiceError::Forbidden)?;
+        end_session(&conn, &session.id)?;
+        Ok(session.id == current.id)
+    })
+    .then(move |res| match res {
+        Ok(was_current) => {
+            if was_current {
+                id.forget();
+            }
+            Ok(HttpResponse::Ok().finish())
+        }
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
+
+#[derive(Serialize)]
+struct Revoked {
+    revoked: usize,
+    revoked_tokens: usize,
+}
+
+/// Ends every session and personal access token of the user but the
+/// current one.
+pub fn revoke_others(
+    current: CurrentSession,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    web::block(move || -> Result<Revoked, ServiceError> {
+        let conn = pool.get().unwrap();
+        let (revoked, revoked_tokens) =
+            end_other_sessions(&conn, &current.user.id, Some(&current.id), "signed_out_everywhere")?;
+        Ok(Revoked { revoked, revoked_tokens })
+    })
+    .then(|res| match res {
+        Ok(t) => Ok(HttpResponse::Ok().json(t)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
@@ -0,0 +1,284 @@
+use actix_web::{error::BlockingError, web, HttpRequest, HttpResponse};
+use chrono::{NaiveDateTime, Utc};
+use diesel::prelude::*;
+use diesel::r2d2::ConnectionManager;
+use futures::Future;
+use r2d2::Pool;
+use uuid::Uuid;
+
+use crate::errors::ServiceError;
+use crate::models::{
+    CreatedShare, LoggedUser, NewComment, NewShare, Note, NoteComment, NoteShare, SharedNote,
+};
+use crate::policy::{owned_note, visible_note};
+use crate::ratelimit::AccountGuard;
+use crate::routes::auth::{hash_password, verify};
+use crate::tokens;
+
+type SqlPool = Pool<ConnectionManager<SqliteConnection>>;
+
+/// Header carrying the password of a protected share link.
+pub const SHARE_PASSWORD_HEADER: &str = "x-share-password";
+
+const PERMISSIONS: [&str; 2] = ["read", "comment"];
+
+/// Looks up a live share link by its secret and checks its password.
+/// Unknown, revoked and expired links all fail the same way. Wrong
+/// passwords count towards a lockout of the link.
+fn open_share(
+    conn: &SqliteConnection,
+    guard: &AccountGuard,
+    secret: &str,
+    password: Option<&str>,
+) -> Result<(NoteShare, Note), ServiceError> {
+    use crate::schema::note_shares::dsl::*;
+    use crate::schema::notes::dsl as notes;
+    let share = note_shares
+        .filter(token_hash.eq(tokens::hash(secret)))
+        .filter(revoked_at.is_null())
+        .first::<NoteShare>(conn)
+        .optional()?
+        .ok_or(ServiceError::Unauthorized)?;
+    if let Some(expiry) = share.expires_at {
+        if expiry <= Utc::now().naive_utc() {
+            return Err(ServiceError::Unauthorized);
+        }
+    }
+    if let Some(hash) = &share.password_hash {
+        guard.attempt(&format!("share:{}", share.id), || match password {
+            Some(password) if verify(hash, password)? => Ok(()),
+            _ => Err(ServiceError::Unauthorized),
+        })?;
+    }
+    let note = notes::notes
+        .filter(notes::id.eq(&share.note_id))
+        .filter(notes::deleted_at.is_null())
+        .first::<Note>(conn)
+        .optional()?
+        .ok_or(ServiceError::Unauthorized)?;
+    Ok((share, note))
+}
+
+fn share_password(req: &HttpRequest) -> Option<String> {
+    req.headers()
+        .get(SHARE_PASSWORD_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(String::from)
+}
+
+/// Opens a share link. Works without a session.
+pub fn view(
+    req: HttpRequest,
+    secret: web::Path<String>,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    use crate::schema::note_shares::dsl::*;
+    let password = share_password(&req);
+    let guard = AccountGuard::of(&req);
+    web::block(move || -> Result<SharedNote, ServiceError> {
+        let conn = pool.get().unwrap();
+        let (share, note) = open_share(&conn, &guard, &secret, password.as_ref().map(String::as_str))?;
+        diesel::update(&share)
+            .set(views.eq(views + 1))
+            .execute(&conn)?;
+        let comments = if share.permission == "comment" {
+            Some(
+                NoteComment::belonging_to(&note)
+                    .order(crate::schema::note_comments::created_at.asc())
+                    .load::<NoteComment>(&conn)?,
+            )
+        } else {
+            None
+        };
+        Ok(SharedNote {
+            note,
+            permission: share.permission,
+            comments,
+        })
+    })
+    .then(|res| match res {
+        Ok(t) => Ok(HttpResponse::Ok().json(t)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
+
+/// Leaves a comment through a share link with the comment permission.
+pub fn comment(
+    req: HttpRequest,
+    secret: web::Path<String>,
+    new_comment: web::Json<NewComment>,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    use crate::schema::note_comments::dsl::*;
+    let password = share_password(&req);
+    let guard = AccountGuard::of(&req);
+    web::block(move || -> Result<NoteComment, ServiceError> {
+        let conn = pool.get().unwrap();
+        let (share, _) = open_share(&conn, &guard, &secret, password.as_ref().map(String::as_str))?;
+        if share.permission != "comment" {
+            return Err(ServiceError::Forbidden);
+        }
+        let new_comment = new_comment.into_inner();
+        if new_comment.name.trim().is_empty() || new_comment.body.trim().is_empty() {
+            return Err(ServiceError::BadRequest(
+                String::from("Comments need a name and a body!")
+            ));
+        }
+        let note_comment = NoteComment::from(new_comment, &share);
+        diesel::insert_into(note_comments)
+            .values(&note_comment)
+            .execute(&conn)?;
+        Ok(note_comment)
+    })
+    .then(|res| match res {
+        Ok(t) => Ok(HttpResponse::Ok().json(t)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
+
+/// Comments left on a note through its share links.
+pub fn get_comments(
+    user: LoggedUser,
+    uuid: web::Path<Uuid>,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    use crate::schema::note_comments::dsl::*;
+    web::block(move || -> Result<Vec<NoteComment>, ServiceError> {
+        let conn = pool.get().unwrap();
+        let note = visible_note(&conn, &user, &uuid.into_inner().to_string())?;
+        let comment_list = NoteComment::belonging_to(&note)
+            .order(created_at.asc())
+            .load::<NoteComment>(&conn)?;
+        Ok(comment_list)
+    })
+    .then(|res| match res {
+        Ok(t) => Ok(HttpResponse::Ok().json(t)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
+
+pub fn list(
+    user: LoggedUser,
+    uuid: web::Path<Uuid>,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    use crate::schema::note_shares::dsl::*;
+    web::block(move || -> Result<Vec<NoteShare>, ServiceError> {
+        let conn = pool.get().unwrap();
+        let note = owned_note(&conn, &user, &uuid.into_inner().to_string())?;
+        let share_list = NoteShare::belonging_to(&note)
+            .order(created_at.desc())
+            .load::<NoteShare>(&conn)?;
+        Ok(share_list)
+    })
+    .then(|res| match res {
+        Ok(t) => Ok(HttpResponse::Ok().json(t)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
+
+pub fn insert(
+    user: LoggedUser,
+    uuid: web::Path<Uuid>,
+    new_share: web::Json<NewShare>,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    use crate::schema::note_shares::dsl::*;
+    web::block(move || -> Result<CreatedShare, ServiceError> {
+        let conn = pool.get().unwrap();
+        let note = owned_note(&conn, &user, &uuid.into_inner().to_string())?;
+        let new_share = new_share.into_inner();
+        let share_permission = new_share.permission.unwrap_or_else(|| String::from("read"));
+        if !PERMISSIONS.contains(&share_permission.as_str()) {
+            return Err(ServiceError::BadRequest(format!(
+                "Unknown permission '{}', expected read or comment",
+                share_permission
+            )));
+        }
+        let expiry = match new_share.expires_at {
+            Some(value) => {
+                let expiry = NaiveDateTime::parse_from_str(&value, "%Y-%m-%d %H:%M:%S")
+                    .map_err(|_| ServiceError::BadRequest(format!(
+                        "Invalid date '{}', expected YYYY-MM-DD HH:MM:SS",
+                        value
+                    )))?;
+                if expiry <= Utc::now().naive_utc() {
+                    return Err(ServiceError::BadRequest(
+                        String::from("Expiry must be in the future!")
+                    ));
+                }
+                Some(expiry)
+            }
+            None => None,
+        };
+        let hash = match new_share.password {
+            Some(password) if !password.is_empty() => Some(hash_password(&password)?),
+            _ => None,
+        };
+        let secret = tokens::generate();
+        let share = NoteShare::from(
+            &note,
+            &user,
+            tokens::hash(&secret),
+            share_permission,
+            hash,
+            expiry,
+        );
+        diesel::insert_into(note_shares)
+            .values(&share)
+            .execute(&conn)?;
+        Ok(CreatedShare {
+            url: format!("/api/shared/{}", secret),
+            share,
+            secret,
+        })
+    })
+    .then(|res| match res {
+        Ok(t) => Ok(HttpResponse::Ok().json(t)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
+
+pub fn revoke(
+    user: LoggedUser,
+    path: web::Path<(Uuid, Uuid)>,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    use crate::schema::note_shares::dsl::*;
+    web::block(move || -> Result<NoteShare, ServiceError> {
+        let conn = pool.get().unwrap();
+        let (note_uuid, share_uuid) = path.into_inner();
+        let note = owned_note(&conn, &user, &note_uuid.to_string())?;
+        let share = NoteShare::belonging_to(&note)
+            .filter(id.eq(share_uuid.to_string()))
+            .first::<NoteShare>(&conn)?;
+        diesel::update(&share)
+            .filter(revoked_at.is_null())
+            .set(revoked_at.eq(Utc::now().naive_utc()))
+            .execute(&conn)?;
+        let revoked = note_shares.filter(id.eq(&share.id)).first::<NoteShare>(&conn)?;
+        Ok(revoked)
+    })
+    .then(|res| match res {
+        Ok(t) => Ok(HttpResponse::Ok().json(t)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
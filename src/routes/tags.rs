@@ -0,0 +1,327 @@
+use actix_web::{error::BlockingError, web, HttpResponse};
+use diesel::prelude::*;
+use diesel::r2d2::ConnectionManager;
+use futures::Future;
+use r2d2::Pool;
+use std::collections::HashMap;
+use uuid::Uuid;
+
+use crate::errors::ServiceError;
+use crate::models::{LoggedUser, NewTag, NoteTag, Tag};
+use crate::policy::visible_note;
+
+type SqlPool = Pool<ConnectionManager<SqliteConnection>>;
+
+/// Narrows note listings down by the caller's tags. `tags` is a comma
+/// separated list of tag names, `mode` is either `all` (the default) or `any`.
+pub struct TagFilter {
+    tags: Option<String>,
+    mode: Option<String>,
+}
+
+impl TagFilter {
+    pub fn new(tags: Option<String>, mode: Option<String>) -> Self {
+        TagFilter { tags, mode }
+    }
+
+    /// Ids of the notes matching the filter, or `None` when no tags were
+    /// requested and the listing should not be narrowed.
+    pub fn note_ids(
+        &self,
+        conn: &SqliteConnection,
+        user: &LoggedUser,
+    ) -> Result<Option<Vec<String>>, ServiceError> {
+        use crate::schema::note_tags::dsl::{note_id, note_tags, tag_id};
+        use crate::schema::tags::dsl::{id, name, tags, user_id};
+        let names: Vec<String> = match &self.tags {
+            Some(list) => normalize_names(list.split(',')),
+            None => return Ok(None),
+        };
+        if names.is_empty() {
+            return Ok(None);
+        }
+        let match_all = match self.mode.as_ref().map(String::as_str) {
+            None | Some("all") => true,
+            Some("any") => false,
+            Some(_) => return Err(ServiceError::BadRequest(
+                String::from("Tag match must be either 'all' or 'any'!")
+            )),
+        };
+        let tag_ids = tags
+            .filter(user_id.eq(&user.id))
+            .filter(name.eq_any(&names))
+            .select(id)
+            .load::<String>(conn)?;
+        if match_all && tag_ids.len() < names.len() {
+            return Ok(Some(vec![]));
+        }
+        let links = note_tags
+            .filter(tag_id.eq_any(&tag_ids))
+            .select(note_id)
+            .load::<String>(conn)?;
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        for link in links {
+            *counts.entry(link).or_insert(0) += 1;
+        }
+        Ok(Some(counts
+            .into_iter()
+            .filter(|(_, count)| !match_all || *count == tag_ids.len())
+            .map(|(note, _)| note)
+            .collect()))
+    }
+}
+
+fn normalize_names<'a, I: Iterator<Item = &'a str>>(names: I) -> Vec<String> {
+    let mut out: Vec<String> = names
+        .map(|tag_name| tag_name.trim().to_string())
+        .filter(|tag_name| !tag_name.is_empty())
+        .collect();
+    out.sort();
+    out.dedup();
+    out
+}
+
+/// Replaces the user's tags on a note with `names`, creating any tag the
+/// user does not have yet. Tags other users put on the note are kept. Blank
+/// names are skipped, other invalid ones refused.
+pub fn set_note_tags(
+    conn: &SqliteConnection,
+    user: &LoggedUser,
+    target_note: &str,
+    names: &[String],
+) -> Result<Vec<Tag>, ServiceError> {
+    use crate::schema::note_tags::dsl::{note_id, note_tags, tag_id};
+    use crate::schema::tags::dsl::{id, name, tags, user_id};
+    let names = normalize_names(names.iter().map(String::as_str));
+    for tag_name in &names {
+        validate_name(tag_name)?;
+    }
+    let mut existing = tags
+        .filter(user_id.eq(&user.id))
+        .filter(name.eq_any(&names))
+        .load::<Tag>(conn)?;
+    for tag_name in &names {
+        if !existing.iter().any(|tag| &tag.name == tag_name) {
+            let tag = Tag::from(NewTag { name: tag_name.clone() }, user);
+            diesel::insert_into(tags).values(&tag).execute(conn)?;
+            existing.push(tag);
+        }
+    }
+    let own_tags = tags
+        .filter(user_id.eq(&user.id))
+        .select(id);
+    diesel::delete(
+        note_tags
+            .filter(note_id.eq(target_note))
+            .filter(tag_id.eq_any(own_tags)))
+        .execute(conn)?;
+    let links: Vec<NoteTag> = existing
+        .iter()
+        .map(|tag| NoteTag::from(target_note, &tag.id))
+        .collect();
+    diesel::insert_into(note_tags).values(&links).execute(conn)?;
+    Ok(existing)
+}
+
+fn own_tag(
+    conn: &SqliteConnection,
+    user: &LoggedUser,
+    uuid: &str,
+) -> Result<Tag, ServiceError> {
+    use crate::schema::tags::dsl::*;
+    let tag = tags.filter(id.eq(uuid)).first::<Tag>(conn)?;
+    if tag.user_id != user.id {
+        return Err(ServiceError::Forbidden);
+    }
+    Ok(tag)
+}
+
+fn validate_name(tag_name: &str) -> Result<(), ServiceError> {
+    if tag_name.trim().is_empty() || tag_name.contains(',') {
+        return Err(ServiceError::BadRequest(
+            String::from("Tag names must be non-empty and may not contain commas!")
+        ));
+    }
+    Ok(())
+}
+
+pub fn get_user_tags(
+    user: LoggedUser,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    use crate::schema::tags::dsl::*;
+    web::block(move || -> Result<Vec<Tag>, ServiceError> {
+        let conn = pool.get().unwrap();
+        let tag_list = tags
+            .filter(user_id.eq(&user.id))
+            .order(name.asc())
+            .load::<Tag>(&conn)?;
+        Ok(tag_list)
+    })
+    .then(|res| match res {
+        Ok(t) => Ok(HttpResponse::Ok().json(t)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
+
+pub fn insert(
+    user: LoggedUser,
+    new_tag: web::Json<NewTag>,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    use crate::schema::tags::dsl::*;
+    web::block(move || -> Result<Tag, ServiceError> {
+        let conn = pool.get().unwrap();
+        let new_tag = new_tag.into_inner();
+        validate_name(&new_tag.name)?;
+        let tag = Tag::from(new_tag, &user);
+        diesel::insert_into(tags).values(&tag).execute(&conn)?;
+        Ok(tag)
+    })
+    .then(|res| match res {
+        Ok(t) => Ok(HttpResponse::Ok().json(t)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
+
+pub fn rename(
+    user: LoggedUser,
+    uuid: web::Path<Uuid>,
+    patch: web::Json<NewTag>,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    use crate::schema::tags::dsl::*;
+    web::block(move || -> Result<Tag, ServiceError> {
+        let conn = pool.get().unwrap();
+        validate_name(&patch.name)?;
+        let tag = own_tag(&conn, &user, &uuid.into_inner().to_string())?;
+        diesel::update(&tag)
+            .set(name.eq(patch.name.trim()))
+            .execute(&conn)?;
+        let renamed = tags.filter(id.eq(&tag.id)).first::<Tag>(&conn)?;
+        Ok(renamed)
+    })
+    .then(|res| match res {
+        Ok(t) => Ok(HttpResponse::Ok().json(t)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
+
+pub fn delete(
+    user: LoggedUser,
+    uuid: web::Path<Uuid>,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    web::block(move || -> Result<Tag, ServiceError> {
+        let conn = pool.get().unwrap();
+        let tag = own_tag(&conn, &user, &uuid.into_inner().to_string())?;
+        conn.transaction(|| {
+            diesel::delete(NoteTag::belonging_to(&tag)).execute(&conn)?;
+            diesel::delete(&tag).execute(&conn)?;
+            Ok(tag)
+        })
+    })
+    .then(|res| match res {
+        Ok(t) => Ok(HttpResponse::Ok().json(t)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
+
+pub fn get_note_tags(
+    user: LoggedUser,
+    uuid: web::Path<Uuid>,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    use crate::schema::note_tags::dsl::{note_id, note_tags};
+    use crate::schema::tags::dsl::{name, tags, user_id};
+    web::block(move || -> Result<Vec<Tag>, ServiceError> {
+        let conn = pool.get().unwrap();
+        let note = visible_note(&conn, &user, &uuid.into_inner().to_string())?;
+        let tag_list = tags
+            .inner_join(note_tags)
+            .filter(note_id.eq(&note.id))
+            .filter(user_id.eq(&user.id))
+            .order(name.asc())
+            .select(tags::all_columns())
+            .load::<Tag>(&conn)?;
+        Ok(tag_list)
+    })
+    .then(|res| match res {
+        Ok(t) => Ok(HttpResponse::Ok().json(t)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
+
+pub fn attach(
+    user: LoggedUser,
+    path: web::Path<(Uuid, Uuid)>,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    use crate::schema::note_tags::dsl::*;
+    web::block(move || -> Result<Tag, ServiceError> {
+        let conn = pool.get().unwrap();
+        let (note_uuid, tag_uuid) = path.into_inner();
+        let note = visible_note(&conn, &user, &note_uuid.to_string())?;
+        let tag = own_tag(&conn, &user, &tag_uuid.to_string())?;
+        let existing = note_tags
+            .filter(note_id.eq(&note.id))
+            .filter(tag_id.eq(&tag.id))
+            .count()
+            .get_result::<i64>(&conn)?;
+        if existing == 0 {
+            diesel::insert_into(note_tags)
+                .values(&NoteTag::from(&note.id, &tag.id))
+                .execute(&conn)?;
+        }
+        Ok(tag)
+    })
+    .then(|res| match res {
+        Ok(t) => Ok(HttpResponse::Ok().json(t)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
+
+pub fn detach(
+    user: LoggedUser,
+    path: web::Path<(Uuid, Uuid)>,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    use crate::schema::note_tags::dsl::*;
+    web::block(move || -> Result<Tag, ServiceError> {
+        let conn = pool.get().unwrap();
+        let (note_uuid, tag_uuid) = path.into_inner();
+        let note = visible_note(&conn, &user, &note_uuid.to_string())?;
+        let tag = own_tag(&conn, &user, &tag_uuid.to_string())?;
+        diesel::delete(
+            note_tags
+                .filter(note_id.eq(&note.id))
+                .filter(tag_id.eq(&tag.id)))
+            .execute(&conn)?;
+        Ok(tag)
+    })
+    .then(|res| match res {
+        Ok(t) => Ok(HttpResponse::Ok().json(t)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
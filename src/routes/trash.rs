@@ -0,0 +1,191 @@
+use actix::Addr;
+use actix_web::{error::BlockingError, web, HttpResponse};
+use diesel::prelude::*;
+use diesel::r2d2::ConnectionManager;
+use futures::Future;
+use r2d2::Pool;
+use uuid::Uuid;
+
+use crate::errors::ServiceError;
+use crate::models::{Group, GroupLink, GroupRole, LoggedUser, Note, TrashContents};
+use crate::policy::{allows, member_role, NoteAction};
+use crate::realtime::{GroupEvent, Hub, Publish};
+use crate::storage::Storage;
+use crate::trash::{drop_unused_blobs, purge_group, purge_note};
+
+type SqlPool = Pool<ConnectionManager<SqliteConnection>>;
+
+fn trashed_note(
+    conn: &SqliteConnection,
+    user: &LoggedUser,
+    note_id: &str,
+) -> Result<Note, ServiceError> {
+    use crate::schema::notes::dsl::*;
+    let note = notes
+        .filter(id.eq(note_id))
+        .filter(deleted_at.is_not_null())
+        .first::<Note>(conn)?;
+    if !allows(conn, user, &note, NoteAction::Manage)? {
+        return Err(ServiceError::Forbidden);
+    }
+    Ok(note)
+}
+
+fn trashed_group(
+    conn: &SqliteConnection,
+    user: &LoggedUser,
+    group_id: &str,
+) -> Result<Group, ServiceError> {
+    use crate::schema::groups::dsl::*;
+    let group = groups
+        .filter(id.eq(group_id))
+        .filter(deleted_at.is_not_null())
+        .first::<Group>(conn)?;
+    // same owners as for moving the group to the trash
+    if member_role(conn, user, &group.id)? != Some(GroupRole::Owner) {
+        return Err(ServiceError::Forbidden);
+    }
+    Ok(group)
+}
+
+pub fn list(
+    user: LoggedUser,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    use crate::schema::group_links::dsl as links;
+    use crate::schema::groups::dsl as groups;
+    use crate::schema::notes::dsl as notes;
+    web::block(move || -> Result<TrashContents, ServiceError> {
+        let conn = pool.get().unwrap();
+        let owned_ids = GroupLink::belonging_to(&user)
+            .filter(links::role.eq(GroupRole::Owner.as_str()))
+            .select(links::group_id)
+            .load::<String>(&conn)?;
+        let note_list = notes::notes
+            .filter(notes::user_id.eq(&user.id))
+            .filter(notes::deleted_at.is_not_null())
+            .order(notes::deleted_at.desc())
+            .load::<Note>(&conn)?;
+        let group_list = groups::groups
+            .filter(groups::id.eq_any(&owned_ids))
+            .filter(groups::deleted_at.is_not_null())
+            .order(groups::deleted_at.desc())
+            .load::<Group>(&conn)?;
+        Ok(TrashContents {
+            notes: note_list,
+            groups: group_list,
+        })
+    })
+    .then(|res| match res {
+        Ok(t) => Ok(HttpResponse::Ok().json(t)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
+
+pub fn restore_note(
+    user: LoggedUser,
+    uuid: web::Path<Uuid>,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    use crate::schema::notes::dsl::*;
+    web::block(move || -> Result<Note, ServiceError> {
+        let conn = pool.get().unwrap();
+        let note = trashed_note(&conn, &user, &uuid.into_inner().to_string())?;
+        diesel::update(&note)
+            .set(deleted_at.eq(None::<chrono::NaiveDateTime>))
+            .execute(&conn)?;
+        let restored = notes.filter(id.eq(&note.id)).first::<Note>(&conn)?;
+        Ok(restored)
+    })
+    .then(|res| match res {
+        Ok(t) => Ok(HttpResponse::Ok().json(t)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
+
+pub fn delete_note(
+    user: LoggedUser,
+    uuid: web::Path<Uuid>,
+    pool: web::Data<SqlPool>,
+    storage: web::Data<Storage>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    web::block(move || -> Result<Note, ServiceError> {
+        let conn = pool.get().unwrap();
+        let note = trashed_note(&conn, &user, &uuid.into_inner().to_string())?;
+        let digests = purge_note(&conn, &note.id)?;
+        drop_unused_blobs(&conn, &storage, &digests);
+        Ok(note)
+    })
+    .then(|res| match res {
+        Ok(t) => Ok(HttpResponse::Ok().json(t)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
+
+pub fn restore_group(
+    user: LoggedUser,
+    uuid: web::Path<Uuid>,
+    pool: web::Data<SqlPool>,
+    hub: web::Data<Addr<Hub>>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    use crate::schema::group_links::dsl as links;
+    use crate::schema::groups::dsl::*;
+    web::block(move || -> Result<(Group, Vec<String>), ServiceError> {
+        let conn = pool.get().unwrap();
+        let group = trashed_group(&conn, &user, &uuid.into_inner().to_string())?;
+        diesel::update(&group)
+            .set(deleted_at.eq(None::<chrono::NaiveDateTime>))
+            .execute(&conn)?;
+        let restored = groups.filter(id.eq(&group.id)).first::<Group>(&conn)?;
+        let members = GroupLink::belonging_to(&restored)
+            .select(links::user_id)
+            .load::<String>(&conn)?;
+        Ok((restored, members))
+    })
+    .then(move |res| match res {
+        Ok((t, members)) => {
+            hub.do_send(Publish(GroupEvent::GroupRestored {
+                group_id: t.id.clone(),
+                members,
+            }));
+            Ok(HttpResponse::Ok().json(t))
+        }
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
+
+pub fn delete_group(
+    user: LoggedUser,
+    uuid: web::Path<Uuid>,
+    pool: web::Data<SqlPool>,
+    hub: web::Data<Addr<Hub>>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    web::block(move || -> Result<Group, ServiceError> {
+        let conn = pool.get().unwrap();
+        let group = trashed_group(&conn, &user, &uuid.into_inner().to_string())?;
+        purge_group(&conn, &group.id)?;
+        Ok(group)
+    })
+    .then(move |res| match res {
+        Ok(t) => {
+            hub.do_send(Publish(GroupEvent::GroupDeleted { group_id: t.id.clone() }));
+            Ok(HttpResponse::Ok().json(t))
+        }
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
@@ -1,12 +1,25 @@
+use actix::Addr;
+use actix_identity::Identity;
 use actix_web::{error::BlockingError, web, HttpResponse};
+use chrono::Utc;
 use diesel::prelude::*;
 use diesel::r2d2::ConnectionManager;
 use futures::Future;
 use r2d2::Pool;
+use std::env;
 use uuid::Uuid;
 
+use crate::email_service::{self, frontend_link, Template};
 use crate::errors::ServiceError;
-use crate::models::{User, PublicUser};
+use crate::models::{
+    new_security_stamp, EmailChange, Group, GroupLink, GroupRole, LoggedUser, PublicUser, Session,
+    User,
+};
+use crate::realtime::{GroupEvent, Hub, Publish};
+use crate::routes::auth::{end_other_sessions, hash_password, verify, CurrentSession};
+use crate::storage::Storage;
+use crate::tokens;
+use crate::trash::{drop_unused_blobs, purge_group, purge_note};
 
 type SqlPool = Pool<ConnectionManager<SqliteConnection>>;
 
@@ -30,4 +43,453 @@ pub fn get_user(
             BlockingError::Canceled => Err(ServiceError::InternalServerError),
         },
     })
-}
\ No newline at end of file
+}
+
+/// Sessions younger than this may set the first password of an account
+/// that signs in through identity providers only.
+const FIRST_PASSWORD_MINUTES: i64 = 10;
+
+/// Loads the logged in user and checks their current password.
+pub fn confirmed_user(
+    conn: &SqliteConnection,
+    user: &LoggedUser,
+    current_password: &str,
+) -> Result<User, ServiceError> {
+    use crate::schema::users::dsl::*;
+    let account = users.filter(id.eq(&user.id)).first::<User>(conn)?;
+    if account.password.is_empty() {
+        return Err(ServiceError::BadRequest(String::from(
+            "Set a password for your account first!",
+        )));
+    }
+    if !verify(&account.password, current_password)? {
+        return Err(ServiceError::Unauthorized);
+    }
+    Ok(account)
+}
+
+#[derive(Deserialize)]
+pub struct ProfileChanges {
+    name: String,
+}
+
+pub fn update_profile(
+    user: LoggedUser,
+    changes: web::Json<ProfileChanges>,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    use crate::schema::users::dsl::*;
+    web::block(move || -> Result<LoggedUser, ServiceError> {
+        let conn = pool.get().unwrap();
+        let new_name = changes.into_inner().name.trim().to_string();
+        if new_name.is_empty() {
+            return Err(ServiceError::BadRequest(String::from("Name can't be empty!")));
+        }
+        diesel::update(users.filter(id.eq(&user.id)))
+            .set(name.eq(&new_name))
+            .execute(&conn)?;
+        let account = users.filter(id.eq(&user.id)).first::<User>(&conn)?;
+        Ok(LoggedUser::from(account))
+    })
+    .then(|res| match res {
+        Ok(t) => Ok(HttpResponse::Ok().json(t)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
+
+#[derive(Deserialize)]
+pub struct NewEmail {
+    email: String,
+    password: String,
+}
+
+/// Sends a confirmation link to the new address. The account keeps its old
+/// address until the link is used.
+pub fn change_email(
+    user: LoggedUser,
+    data: web::Json<NewEmail>,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    use crate::schema::email_changes::dsl::*;
+    use crate::schema::users::dsl::{email, users};
+    web::block(move || -> Result<(), ServiceError> {
+        let conn = pool.get().unwrap();
+        let data = data.into_inner();
+        let account = confirmed_user(&conn, &user, &data.password)?;
+        let address = data.email.trim().to_string();
+        if address.is_empty() || !address.contains('@') {
+            return Err(ServiceError::BadRequest(String::from("Invalid email address!")));
+        }
+        let taken = users.filter(email.eq(&address)).count().get_result::<i64>(&conn)?;
+        if taken > 0 {
+            return Err(ServiceError::BadRequest(String::from("This email is already in use!")));
+        }
+        let secret = tokens::generate();
+        let confirmation_path = env::var("EMAIL_CONFIRMATION_URL")
+            .unwrap_or_else(|_| "/email/confirm/".into());
+        conn.transaction(|| {
+            // only the newest link works
+            diesel::update(email_changes.filter(user_id.eq(&account.id)).filter(resolved.eq(0)))
+                .set(resolved.eq(1))
+                .execute(&conn)?;
+            diesel::insert_into(email_changes)
+                .values(&EmailChange::from(&account, address.clone(), tokens::hash(&secret)))
+                .execute(&conn)?;
+            email_service::queue(&conn, &address, Template::ConfirmEmailChange {
+                name: &account.name,
+                link: frontend_link(&(confirmation_path + &secret)),
+            })
+        })
+    })
+    .then(|res| match res {
+        Ok(_) => Ok(HttpResponse::Accepted().finish()),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
+
+/// Switches the account to the address the token was sent to and lets the
+/// old address know.
+pub fn confirm_email(
+    secret: web::Path<String>,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    use crate::schema::email_changes::dsl::*;
+    use crate::schema::users::dsl::{email, id as u_id, users};
+    web::block(move || -> Result<LoggedUser, ServiceError> {
+        let conn = pool.get().unwrap();
+        conn.transaction(|| {
+            let change = email_changes
+                .filter(token_hash.eq(tokens::hash(&secret)))
+                .filter(resolved.eq(0))
+                .filter(expires_at.gt(Utc::now().naive_utc()))
+                .first::<EmailChange>(&conn)
+                .optional()?
+                .ok_or(ServiceError::Unauthorized)?;
+            diesel::update(&change).set(resolved.eq(1)).execute(&conn)?;
+            let taken = users
+                .filter(email.eq(&change.new_email))
+                .count()
+                .get_result::<i64>(&conn)?;
+            if taken > 0 {
+                return Err(ServiceError::BadRequest(String::from("This email is already in use!")));
+            }
+            let account = users.filter(u_id.eq(&change.user_id)).first::<User>(&conn)?;
+            diesel::update(&account)
+                .set(email.eq(&change.new_email))
+                .execute(&conn)?;
+            email_service::queue(&conn, &account.email, Template::EmailChanged {
+                name: &account.name,
+                new_email: &change.new_email,
+            })?;
+            let updated = users.filter(u_id.eq(&change.user_id)).first::<User>(&conn)?;
+            Ok(LoggedUser::from(updated))
+        })
+    })
+    .then(|res| match res {
+        Ok(t) => Ok(HttpResponse::Ok().json(t)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
+
+#[derive(Deserialize)]
+pub struct PasswordChange {
+    #[serde(default)]
+    current_password: Option<String>,
+    new_password: String,
+}
+
+/// Loads the user for a password change. Accounts created through an
+/// identity provider have no password to confirm; they may set their first
+/// one right after signing in, which stands in for the confirmation.
+fn password_owner(
+    conn: &SqliteConnection,
+    current: &CurrentSession,
+    current_password: Option<&str>,
+) -> Result<User, ServiceError> {
+    use crate::schema::sessions::dsl as sessions;
+    use crate::schema::users::dsl::*;
+    let account = users.filter(id.eq(&current.user.id)).first::<User>(conn)?;
+    if !account.password.is_empty() {
+        return confirmed_user(conn, &current.user, current_password.unwrap_or_default());
+    }
+    let session = sessions::sessions
+        .filter(sessions::id.eq(&current.id))
+        .first::<Session>(conn)?;
+    if Utc::now().naive_utc() - session.created_at > chrono::Duration::minutes(FIRST_PASSWORD_MINUTES) {
+        return Err(ServiceError::BadRequest(String::from(
+            "Sign in again through your identity provider to set a password!",
+        )));
+    }
+    Ok(account)
+}
+
+/// Changes the password, or sets the first one. Every other session and
+/// personal access token of the user ends, the current one stays.
+pub fn change_password(
+    current: CurrentSession,
+    data: web::Json<PasswordChange>,
+    pool: web::Data<SqlPool>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    web::block(move || -> Result<LoggedUser, ServiceError> {
+        use crate::schema::sessions::dsl as sessions;
+        use crate::schema::users::dsl::*;
+        let conn = pool.get().unwrap();
+        let account = password_owner(&conn, &current, data.current_password.as_ref().map(String::as_str))?;
+        if data.new_password.is_empty() {
+            return Err(ServiceError::BadRequest(String::from("Password can't be empty!")));
+        }
+        let stamp = new_security_stamp();
+        let hash = hash_password(&data.new_password)?;
+        conn.transaction(|| {
+            diesel::update(&account)
+                .set((password.eq(hash), security_stamp.eq(&stamp)))
+                .execute(&conn)?;
+            diesel::update(sessions::sessions.filter(sessions::id.eq(&current.id)))
+                .set(sessions::security_stamp.eq(&stamp))
+                .execute(&conn)?;
+            end_other_sessions(&conn, &account.id, Some(&current.id), "password_changed")?;
+            Ok(current.user)
+        })
+    })
+    .then(|res| match res {
+        Ok(t) => Ok(HttpResponse::Ok().json(t)),
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
+
+#[derive(Deserialize)]
+pub struct AccountDeletion {
+    password: String,
+    /// `delete` removes every note of the user and the groups they created.
+    /// `transfer` keeps the groups going: the groups pass to another member
+    /// and the notes posted into groups to the group owners. Personal notes
+    /// have nobody to go to, so they are deleted either way.
+    content: String,
+}
+
+/// Purges every group created by `user`. The other members keep their notes
+/// from it as personal ones.
+fn delete_groups(conn: &SqliteConnection, user: &User) -> Result<(), ServiceError> {
+    use crate::schema::groups::dsl::*;
+    for group_id in groups.filter(created_by.eq(&user.id)).select(id).load::<String>(conn)? {
+        purge_group(conn, &group_id)?;
+    }
+    Ok(())
+}
+
+/// Hands every group created by `user` to its highest ranking, longest
+/// standing other member. Groups nobody else is in are purged.
+fn transfer_groups(conn: &SqliteConnection, user: &User) -> Result<(), ServiceError> {
+    use crate::schema::group_links::dsl::{role, user_id};
+    use crate::schema::groups::dsl::*;
+    for group in groups.filter(created_by.eq(&user.id)).load::<Group>(conn)? {
+        let successor = GroupLink::belonging_to(&group)
+            .filter(user_id.ne(&user.id))
+            .load::<GroupLink>(conn)?
+            .into_iter()
+            .max_by(|a, b| {
+                let rank = |link: &GroupLink| GroupRole::parse(&link.role).unwrap_or(GroupRole::Viewer);
+                rank(a).cmp(&rank(b)).then(b.joined_at.cmp(&a.joined_at))
+            });
+        match successor {
+            Some(link) => {
+                diesel::update(&group)
+                    .set(created_by.eq(&link.user_id))
+                    .execute(conn)?;
+                diesel::update(&link)
+                    .set(role.eq(GroupRole::Owner.as_str()))
+                    .execute(conn)?;
+            }
+            None => purge_group(conn, &group.id)?,
+        }
+    }
+    Ok(())
+}
+
+/// Removes the account and everything it owns. Returns the attachment
+/// digests of purged notes, whose blobs go after the transaction committed.
+fn delete_account(
+    conn: &SqliteConnection,
+    user: &User,
+    transfer: bool,
+) -> Result<Vec<String>, ServiceError> {
+    use crate::schema::calendar_tokens::dsl as calendar_tokens;
+    use crate::schema::email_changes::dsl as email_changes;
+    use crate::schema::external_identities::dsl as external_identities;
+    use crate::schema::group_invitations::dsl as group_invitations;
+    use crate::schema::group_join_requests::dsl as join_requests;
+    use crate::schema::group_links::dsl as links;
+    use crate::schema::groups::dsl as groups;
+    use crate::schema::invitations::dsl as invitations;
+    use crate::schema::mfa_challenges::dsl as mfa_challenges;
+    use crate::schema::note_grants::dsl as grants;
+    use crate::schema::note_shares::dsl as shares;
+    use crate::schema::note_tags::dsl as note_tags;
+    use crate::schema::notes::dsl as notes;
+    use crate::schema::notification_deliveries::dsl as deliveries;
+    use crate::schema::notifications::dsl as notifications;
+    use crate::schema::oidc_states::dsl as oidc_states;
+    use crate::schema::password_resets::dsl as password_resets;
+    use crate::schema::personal_access_tokens::dsl as access_tokens;
+    use crate::schema::recovery_codes::dsl as recovery_codes;
+    use crate::schema::refresh_tokens::dsl as refresh_tokens;
+    use crate::schema::reminders::dsl as reminders;
+    use crate::schema::sessions::dsl as sessions;
+    use crate::schema::tags::dsl as tags;
+    use crate::schema::totp_credentials::dsl as totp_credentials;
+    use crate::schema::users::dsl as users;
+    conn.transaction(|| {
+        if transfer {
+            transfer_groups(conn, user)?;
+        } else {
+            delete_groups(conn, user)?;
+        }
+        let mut digests = Vec::new();
+        let note_list = notes::notes
+            .filter(notes::user_id.eq(&user.id))
+            .select((notes::id, notes::group_id))
+            .load::<(String, Option<String>)>(conn)?;
+        for (note_id, note_group) in note_list {
+            let new_owner = match (&note_group, transfer) {
+                (Some(gid), true) => groups::groups
+                    .filter(groups::id.eq(gid))
+                    .select(groups::created_by)
+                    .first::<String>(conn)
+                    .optional()?,
+                _ => None,
+            };
+            match new_owner {
+                Some(owner) => {
+                    diesel::update(notes::notes.filter(notes::id.eq(&note_id)))
+                        .set(notes::user_id.eq(owner))
+                        .execute(conn)?;
+                }
+                None => digests.extend(purge_note(conn, &note_id)?),
+            }
+        }
+        let tag_ids = tags::tags
+            .filter(tags::user_id.eq(&user.id))
+            .select(tags::id)
+            .load::<String>(conn)?;
+        diesel::delete(note_tags::note_tags.filter(note_tags::tag_id.eq_any(&tag_ids))).execute(conn)?;
+        diesel::delete(tags::tags.filter(tags::user_id.eq(&user.id))).execute(conn)?;
+        let notification_ids = notifications::notifications
+            .filter(notifications::user_id.eq(&user.id))
+            .select(notifications::id)
+            .load::<String>(conn)?;
+        diesel::delete(
+            deliveries::notification_deliveries
+                .filter(deliveries::notification_id.eq_any(&notification_ids)),
+        )
+        .execute(conn)?;
+        diesel::delete(notifications::notifications.filter(notifications::user_id.eq(&user.id)))
+            .execute(conn)?;
+        diesel::delete(reminders::reminders.filter(reminders::user_id.eq(&user.id))).execute(conn)?;
+        diesel::delete(grants::note_grants.filter(grants::user_id.eq(&user.id))).execute(conn)?;
+        // share links handed out on notes that moved to a group owner
+        diesel::delete(shares::note_shares.filter(shares::user_id.eq(&user.id))).execute(conn)?;
+        diesel::delete(links::group_links.filter(links::user_id.eq(&user.id))).execute(conn)?;
+        diesel::delete(
+            group_invitations::group_invitations.filter(
+                group_invitations::user_id
+                    .eq(&user.id)
+                    .or(group_invitations::email.eq(&user.email)),
+            ),
+        )
+        .execute(conn)?;
+        diesel::delete(join_requests::group_join_requests.filter(join_requests::user_id.eq(&user.id)))
+            .execute(conn)?;
+        diesel::delete(calendar_tokens::calendar_tokens.filter(calendar_tokens::user_id.eq(&user.id)))
+            .execute(conn)?;
+        diesel::delete(password_resets::password_resets.filter(password_resets::user_id.eq(&user.id)))
+            .execute(conn)?;
+        diesel::delete(email_changes::email_changes.filter(email_changes::user_id.eq(&user.id)))
+            .execute(conn)?;
+        diesel::delete(refresh_tokens::refresh_tokens.filter(refresh_tokens::user_id.eq(&user.id)))
+            .execute(conn)?;
+        diesel::delete(sessions::sessions.filter(sessions::user_id.eq(&user.id))).execute(conn)?;
+        diesel::delete(
+            external_identities::external_identities
+                .filter(external_identities::user_id.eq(&user.id)),
+        )
+        .execute(conn)?;
+        diesel::delete(oidc_states::oidc_states.filter(oidc_states::user_id.eq(&user.id)))
+            .execute(conn)?;
+        diesel::delete(
+            access_tokens::personal_access_tokens.filter(access_tokens::user_id.eq(&user.id)),
+        )
+        .execute(conn)?;
+        diesel::delete(
+            totp_credentials::totp_credentials.filter(totp_credentials::user_id.eq(&user.id)),
+        )
+        .execute(conn)?;
+        diesel::delete(recovery_codes::recovery_codes.filter(recovery_codes::user_id.eq(&user.id)))
+            .execute(conn)?;
+        diesel::delete(mfa_challenges::mfa_challenges.filter(mfa_challenges::user_id.eq(&user.id)))
+            .execute(conn)?;
+        diesel::delete(invitations::invitations.filter(invitations::email.eq(&user.email)))
+            .execute(conn)?;
+        diesel::delete(users::users.filter(users::id.eq(&user.id))).execute(conn)?;
+        Ok(digests)
+    })
+}
+
+/// Deletes the account of the logged in user and ends the session.
+pub fn delete_me(
+    id: Identity,
+    user: LoggedUser,
+    data: web::Json<AccountDeletion>,
+    pool: web::Data<SqlPool>,
+    storage: web::Data<Storage>,
+    hub: web::Data<Addr<Hub>>,
+) -> impl Future<Item = HttpResponse, Error = ServiceError> {
+    use crate::schema::group_links::dsl as links;
+    web::block(move || -> Result<(String, Vec<String>), ServiceError> {
+        let conn = pool.get().unwrap();
+        let account = confirmed_user(&conn, &user, &data.password)?;
+        let transfer = match data.content.as_str() {
+            "delete" => false,
+            "transfer" => true,
+            other => {
+                return Err(ServiceError::BadRequest(format!(
+                    "Unknown choice '{}', expected delete or transfer",
+                    other
+                )))
+            }
+        };
+        let group_ids = GroupLink::belonging_to(&user)
+            .select(links::group_id)
+            .load::<String>(&conn)?;
+        let digests = delete_account(&conn, &account, transfer)?;
+        drop_unused_blobs(&conn, &storage, &digests);
+        Ok((account.id, group_ids))
+    })
+    .then(move |res| match res {
+        Ok((user_id, group_ids)) => {
+            id.forget();
+            for group_id in group_ids {
+                hub.do_send(Publish(GroupEvent::MemberLeft {
+                    group_id,
+                    user_id: user_id.clone(),
+                }));
+            }
+            Ok(HttpResponse::Ok().finish())
+        }
+        Err(err) => match err {
+            BlockingError::Error(service_error) => Err(service_error),
+            BlockingError::Canceled => Err(ServiceError::InternalServerError),
+        },
+    })
+}
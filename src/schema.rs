@@ -5,6 +5,7 @@ table! {
         email -> Text,
         password -> Text,
         active -> Integer,
+        security_stamp -> Text,
     }
 }
 
@@ -18,6 +19,9 @@ table! {
         body -> Text,
         public -> Integer,
         pinned -> Integer,
+        deleted_at -> Nullable<Timestamp>,
+        version -> Integer,
+        updated_at -> Timestamp,
     }
 }
 
@@ -28,17 +32,160 @@ table! {
         created_by -> Text,
         name -> Text,
         color -> Text,
+        deleted_at -> Nullable<Timestamp>,
+        join_policy -> Text,
     }
 }
 
 table! {
     group_links (id) {
         id -> Text,
+        user_id -> Text,
         group_id -> Text,
+        role -> Text,
+        joined_at -> Timestamp,
+    }
+}
+
+joinable!(group_links -> users (user_id));
+
+table! {
+    note_revisions (id) {
+        id -> Text,
+        note_id -> Text,
+        user_id -> Text,
+        created_at -> Timestamp,
+        group_id -> Nullable<Text>,
+        title -> Text,
+        date_tag -> Nullable<Timestamp>,
+        body -> Text,
+        public -> Integer,
+        pinned -> Integer,
+    }
+}
+
+table! {
+    tags (id) {
+        id -> Text,
+        user_id -> Text,
+        name -> Text,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    note_tags (id) {
+        id -> Text,
+        note_id -> Text,
+        tag_id -> Text,
+    }
+}
+
+joinable!(note_tags -> tags (tag_id));
+
+table! {
+    attachments (id) {
+        id -> Text,
+        note_id -> Text,
+        user_id -> Text,
+        filename -> Text,
+        mime_type -> Text,
+        size -> BigInt,
+        sha256 -> Text,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    note_grants (id) {
+        id -> Text,
+        note_id -> Text,
+        user_id -> Text,
+        role -> Text,
+        granted_by -> Text,
+        created_at -> Timestamp,
+    }
+}
+
+joinable!(note_grants -> users (user_id));
+
+table! {
+    note_shares (id) {
+        id -> Text,
+        note_id -> Text,
+        user_id -> Text,
+        token_hash -> Text,
+        permission -> Text,
+        password_hash -> Nullable<Text>,
+        expires_at -> Nullable<Timestamp>,
+        views -> Integer,
+        created_at -> Timestamp,
+        revoked_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    note_comments (id) {
+        id -> Text,
+        note_id -> Text,
+        share_id -> Nullable<Text>,
+        author_name -> Text,
+        body -> Text,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    reminders (id) {
+        id -> Text,
+        note_id -> Text,
         user_id -> Text,
+        offset_minutes -> Nullable<Integer>,
+        remind_at -> Nullable<Timestamp>,
+        fire_at -> Nullable<Timestamp>,
+        fired_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
     }
 }
 
+joinable!(reminders -> notes (note_id));
+
+table! {
+    notifications (id) {
+        id -> Text,
+        user_id -> Text,
+        note_id -> Nullable<Text>,
+        reminder_id -> Nullable<Text>,
+        title -> Text,
+        body -> Text,
+        created_at -> Timestamp,
+        read_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    notification_deliveries (id) {
+        id -> Text,
+        notification_id -> Text,
+        channel -> Text,
+        attempts -> Integer,
+        next_attempt_at -> Timestamp,
+        delivered_at -> Nullable<Timestamp>,
+        last_error -> Nullable<Text>,
+    }
+}
+
+joinable!(notification_deliveries -> notifications (notification_id));
+
+table! {
+    calendar_tokens (id) {
+        id -> Text,
+        user_id -> Text,
+        token_hash -> Text,
+        created_at -> Timestamp,
+        revoked_at -> Nullable<Timestamp>,
+    }
+}
 
 table! {
     invitations (id) {
@@ -49,9 +196,232 @@ table! {
     }
 }
 
+table! {
+    group_invitations (id) {
+        id -> Text,
+        group_id -> Text,
+        email -> Nullable<Text>,
+        user_id -> Nullable<Text>,
+        role -> Text,
+        status -> Text,
+        invited_by -> Text,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+    }
+}
+
+joinable!(group_invitations -> groups (group_id));
+
+table! {
+    group_join_requests (id) {
+        id -> Text,
+        group_id -> Text,
+        user_id -> Text,
+        status -> Text,
+        created_at -> Timestamp,
+        resolved_at -> Nullable<Timestamp>,
+        resolved_by -> Nullable<Text>,
+    }
+}
+
+joinable!(group_join_requests -> users (user_id));
+
+table! {
+    group_join_codes (id) {
+        id -> Text,
+        group_id -> Text,
+        code -> Text,
+        created_by -> Text,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+        max_uses -> Nullable<Integer>,
+        uses -> Integer,
+        revoked_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    outgoing_emails (id) {
+        id -> Text,
+        recipient -> Text,
+        subject -> Text,
+        body -> Text,
+        created_at -> Timestamp,
+        attempts -> Integer,
+        next_attempt_at -> Timestamp,
+        sent_at -> Nullable<Timestamp>,
+        last_error -> Nullable<Text>,
+    }
+}
+
+table! {
+    password_resets (id) {
+        id -> Text,
+        user_id -> Text,
+        token_hash -> Text,
+        expires_at -> Timestamp,
+        resolved -> Integer,
+    }
+}
+
+table! {
+    email_changes (id) {
+        id -> Text,
+        user_id -> Text,
+        new_email -> Text,
+        token_hash -> Text,
+        expires_at -> Timestamp,
+        resolved -> Integer,
+    }
+}
+
+table! {
+    refresh_tokens (id) {
+        id -> Text,
+        user_id -> Text,
+        family_id -> Text,
+        token_hash -> Text,
+        security_stamp -> Text,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+        revoked_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    sessions (id) {
+        id -> Text,
+        user_id -> Text,
+        kind -> Text,
+        user_agent -> Nullable<Text>,
+        ip_address -> Nullable<Text>,
+        security_stamp -> Text,
+        created_at -> Timestamp,
+        last_seen_at -> Timestamp,
+        expires_at -> Timestamp,
+        revoked_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    personal_access_tokens (id) {
+        id -> Text,
+        user_id -> Text,
+        name -> Text,
+        token_hash -> Text,
+        scopes -> Text,
+        created_at -> Timestamp,
+        expires_at -> Nullable<Timestamp>,
+        last_used_at -> Nullable<Timestamp>,
+        revoked_at -> Nullable<Timestamp>,
+        revoked_reason -> Nullable<Text>,
+    }
+}
+
+table! {
+    totp_credentials (user_id) {
+        user_id -> Text,
+        secret -> Text,
+        created_at -> Timestamp,
+        enabled_at -> Nullable<Timestamp>,
+        last_used_step -> Nullable<BigInt>,
+    }
+}
+
+table! {
+    recovery_codes (id) {
+        id -> Text,
+        user_id -> Text,
+        code_hash -> Text,
+        used_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    mfa_challenges (id) {
+        id -> Text,
+        user_id -> Text,
+        token_hash -> Text,
+        kind -> Text,
+        attempts -> Integer,
+        expires_at -> Timestamp,
+        resolved -> Integer,
+    }
+}
+
+table! {
+    oidc_states (id) {
+        id -> Text,
+        provider -> Text,
+        user_id -> Nullable<Text>,
+        state_hash -> Text,
+        nonce -> Text,
+        code_verifier -> Text,
+        expires_at -> Timestamp,
+        resolved -> Integer,
+    }
+}
+
+table! {
+    external_identities (id) {
+        id -> Text,
+        user_id -> Text,
+        provider -> Text,
+        subject -> Text,
+        email -> Nullable<Text>,
+        created_at -> Timestamp,
+        last_login_at -> Timestamp,
+    }
+}
+
+table! {
+    rate_limit_buckets (id) {
+        id -> Text,
+        tokens -> Double,
+        refilled_at -> Timestamp,
+    }
+}
+
+table! {
+    login_failures (id) {
+        id -> Text,
+        failures -> Integer,
+        last_failure_at -> Timestamp,
+        locked_until -> Nullable<Timestamp>,
+    }
+}
+
 allow_tables_to_appear_in_same_query! {
     users,
     notes,
     invitations,
     groups,
+    group_links,
+    note_revisions,
+    tags,
+    note_tags,
+    attachments,
+    calendar_tokens,
+    reminders,
+    notifications,
+    notification_deliveries,
+    note_shares,
+    note_comments,
+    note_grants,
+    group_invitations,
+    group_join_requests,
+    group_join_codes,
+    outgoing_emails,
+    password_resets,
+    email_changes,
+    refresh_tokens,
+    sessions,
+    personal_access_tokens,
+    totp_credentials,
+    recovery_codes,
+    mfa_challenges,
+    oidc_states,
+    external_identities,
+    rate_limit_buckets,
+    login_failures,
 }
@@ -0,0 +1,80 @@
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// Blob storage for attachment contents. Blobs are addressed by the hex
+/// encoded sha256 digest of their contents, so identical uploads share one
+/// stored copy.
+pub trait BlobStore: Send + Sync {
+    /// Stores `data` and returns the key it can be read back with.
+    fn put(&self, data: &[u8]) -> io::Result<String>;
+
+    /// Reads `len` bytes of the blob starting at byte `start`.
+    fn read_range(&self, key: &str, start: u64, len: u64) -> io::Result<Vec<u8>>;
+
+    fn remove(&self, key: &str) -> io::Result<()>;
+}
+
+pub type Storage = Arc<dyn BlobStore>;
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+/// Content addressed directory on the local filesystem. Blobs live in
+/// `<root>/<first two hex chars>/<digest>`.
+pub struct LocalStore {
+    root: PathBuf,
+}
+
+impl LocalStore {
+    pub fn new<P: Into<PathBuf>>(root: P) -> io::Result<Self> {
+        let root = root.into();
+        fs::create_dir_all(&root)?;
+        Ok(LocalStore { root })
+    }
+
+    fn path(&self, key: &str) -> io::Result<PathBuf> {
+        if key.len() < 3 || !key.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid blob key"));
+        }
+        Ok(self.root.join(&key[..2]).join(key))
+    }
+}
+
+impl BlobStore for LocalStore {
+    fn put(&self, data: &[u8]) -> io::Result<String> {
+        let key = sha256_hex(data);
+        let path = self.path(&key)?;
+        if path.exists() {
+            return Ok(key);
+        }
+        let dir = path.parent().expect("blob paths always have a parent");
+        fs::create_dir_all(dir)?;
+        // write to a temporary name first so readers never see partial blobs
+        let tmp = dir.join(format!(".{}.tmp", Uuid::new_v4()));
+        let mut file = File::create(&tmp)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &path)?;
+        Ok(key)
+    }
+
+    fn read_range(&self, key: &str, start: u64, len: u64) -> io::Result<Vec<u8>> {
+        let mut file = File::open(self.path(key)?)?;
+        file.seek(SeekFrom::Start(start))?;
+        let mut buf = Vec::with_capacity(len as usize);
+        file.take(len).read_to_end(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn remove(&self, key: &str) -> io::Result<()> {
+        match fs::remove_file(self.path(key)?) {
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            other => other,
+        }
+    }
+}
@@ -0,0 +1,87 @@
+use ring::rand::{SecureRandom, SystemRandom};
+use uuid::Uuid;
+
+use crate::storage::sha256_hex;
+
+/// Generates an unguessable secret for use in URLs. Only its `hash` is ever
+/// stored.
+pub fn generate() -> String {
+    format!("{}{}", Uuid::new_v4().to_simple(), Uuid::new_v4().to_simple())
+}
+
+pub fn hash(token: &str) -> String {
+    sha256_hex(token.as_bytes())
+}
+
+/// Characters used in join codes, leaving out the ones that are easy to mix
+/// up when read aloud or typed (0/O, 1/I/L).
+const CODE_ALPHABET: &[u8] = b"23456789ABCDEFGHJKMNPQRSTUVWXYZ";
+
+/// Draws `len` characters from `CODE_ALPHABET`. Bytes past the last whole
+/// multiple of the alphabet size are thrown away, so every character is
+/// equally likely.
+fn random_code(len: usize) -> Vec<char> {
+    let alphabet = CODE_ALPHABET.len();
+    let limit = 256 - 256 % alphabet;
+    let mut chars = Vec::with_capacity(len);
+    while chars.len() < len {
+        for byte in random_bytes(len) {
+            if (byte as usize) < limit && chars.len() < len {
+                chars.push(CODE_ALPHABET[byte as usize % alphabet] as char);
+            }
+        }
+    }
+    chars
+}
+
+/// Generates a short code like `K7F-29Q` that people can type in by hand.
+pub fn join_code() -> String {
+    let chars = random_code(6);
+    format!(
+        "{}-{}",
+        chars[..3].iter().collect::<String>(),
+        chars[3..].iter().collect::<String>()
+    )
+}
+
+/// Puts a join code typed in by a user back into its canonical form.
+pub fn normalize_join_code(code: &str) -> String {
+    let chars: String = code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+    if chars.len() == 6 {
+        format!("{}-{}", &chars[..3], &chars[3..])
+    } else {
+        chars
+    }
+}
+
+pub fn random_bytes(len: usize) -> Vec<u8> {
+    let mut bytes = vec![0; len];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .expect("The system random number generator failed");
+    bytes
+}
+
+/// Generates a one-time recovery code like `7KQ2M-X9RTD`.
+pub fn recovery_code() -> String {
+    let chars: String = random_code(10).into_iter().collect();
+    format!("{}-{}", &chars[..5], &chars[5..])
+}
+
+/// Puts a recovery code typed in by a user back into its canonical form.
+pub fn normalize_recovery_code(code: &str) -> String {
+    let chars: String = code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+    if chars.len() == 10 {
+        format!("{}-{}", &chars[..5], &chars[5..])
+    } else {
+        chars
+    }
+}
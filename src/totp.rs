@@ -0,0 +1,192 @@
+use ring::digest::SHA1;
+use ring::hmac;
+
+use crate::tokens::random_bytes;
+
+const STEP_SECONDS: i64 = 30;
+const DIGITS: u32 = 6;
+/// Steps before and after the current one that are still accepted, to
+/// allow for clock drift.
+const WINDOW: i64 = 1;
+const BASE32_ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// A new shared secret, base32 encoded the way authenticator apps expect.
+pub fn new_secret() -> String {
+    base32_encode(&random_bytes(20))
+}
+
+pub fn base32_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::new();
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for byte in bytes {
+        buffer = (buffer << 8) | u32::from(*byte);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(BASE32_ALPHABET[((buffer >> bits) & 31) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        encoded.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+    encoded
+}
+
+pub fn base32_decode(encoded: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for c in encoded.chars().filter(|c| *c != '=' && !c.is_whitespace()) {
+        let value = BASE32_ALPHABET
+            .iter()
+            .position(|a| *a as char == c.to_ascii_uppercase())?;
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+/// The code for one time step (RFC 4226 with the step as counter).
+pub fn code_at(secret: &[u8], step: i64) -> u32 {
+    let key = hmac::SigningKey::new(&SHA1, secret);
+    let signature = hmac::sign(&key, &step.to_be_bytes());
+    let hash = signature.as_ref();
+    let offset = (hash[hash.len() - 1] & 0xf) as usize;
+    let binary = (u32::from(hash[offset]) & 0x7f) << 24
+        | u32::from(hash[offset + 1]) << 16
+        | u32::from(hash[offset + 2]) << 8
+        | u32::from(hash[offset + 3]);
+    binary % 10u32.pow(DIGITS)
+}
+
+/// Checks a typed code against the steps around `now` (a unix timestamp),
+/// as in RFC 6238 with the settings authenticator apps default to.
+/// Returns the matching step. Steps up to `last_step` were used already and
+/// are refused, so a code can't be replayed.
+pub fn verify(secret: &str, code: &str, now: i64, last_step: Option<i64>) -> Option<i64> {
+    let secret = base32_decode(secret)?;
+    let code: String = code.chars().filter(|c| !c.is_whitespace()).collect();
+    if code.len() != DIGITS as usize {
+        return None;
+    }
+    let code = code.parse::<u32>().ok()?;
+    let current = now / STEP_SECONDS;
+    (current - WINDOW..=current + WINDOW)
+        .filter(|step| last_step.map(|last| *step > last).unwrap_or(true))
+        .find(|step| code_at(&secret, *step) == code)
+}
+
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/// The `otpauth://` URI authenticator apps read from a QR code.
+pub fn uri(issuer: &str, account: &str, secret: &str) -> String {
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        percent_encode(issuer),
+        percent_encode(account),
+        secret,
+        percent_encode(issuer),
+        DIGITS,
+        STEP_SECONDS
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The SHA1 secret of RFC 6238 Appendix B.
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+    fn code_for(now: i64) -> String {
+        format!("{:06}", code_at(RFC_SECRET, now / STEP_SECONDS))
+    }
+
+    #[test]
+    fn matches_rfc_6238_vectors() {
+        // the appendix lists 8 digits, a 6 digit code is their tail
+        let vectors = [
+            (59, "94287082"),
+            (1_111_111_109, "07081804"),
+            (1_111_111_111, "14050471"),
+            (1_234_567_890, "89005924"),
+            (2_000_000_000, "69279037"),
+            (20_000_000_000, "65353130"),
+        ];
+        for (time, expected) in vectors.iter() {
+            assert_eq!(code_for(*time), expected[2..], "at {}", time);
+        }
+    }
+
+    #[test]
+    fn base32_round_trip() {
+        assert_eq!(base32_encode(b"foobar"), "MZXW6YTBOI");
+        assert_eq!(base32_decode("mzxw 6ytb oi======"), Some(b"foobar".to_vec()));
+        assert_eq!(base32_decode("MZXW1"), None);
+        let secret = base32_encode(RFC_SECRET);
+        assert_eq!(base32_decode(&secret), Some(RFC_SECRET.to_vec()));
+    }
+
+    #[test]
+    fn accepts_codes_within_the_window() {
+        let secret = base32_encode(RFC_SECRET);
+        let now = 1_111_111_111;
+        let current = now / STEP_SECONDS;
+        for offset in -WINDOW..=WINDOW {
+            let code = code_for(now + offset * STEP_SECONDS);
+            assert_eq!(verify(&secret, &code, now, None), Some(current + offset));
+        }
+        let spaced = format!("{} {}", &code_for(now)[..3], &code_for(now)[3..]);
+        assert_eq!(verify(&secret, &spaced, now, None), Some(current));
+    }
+
+    #[test]
+    fn refuses_codes_outside_the_window() {
+        let secret = base32_encode(RFC_SECRET);
+        let now = 1_111_111_111;
+        let early = code_for(now - (WINDOW + 1) * STEP_SECONDS);
+        let late = code_for(now + (WINDOW + 1) * STEP_SECONDS);
+        assert_eq!(verify(&secret, &early, now, None), None);
+        assert_eq!(verify(&secret, &late, now, None), None);
+    }
+
+    #[test]
+    fn refuses_replayed_steps() {
+        let secret = base32_encode(RFC_SECRET);
+        let now = 1_111_111_111;
+        let current = now / STEP_SECONDS;
+        let code = code_for(now);
+        assert_eq!(verify(&secret, &code, now, Some(current)), None);
+        assert_eq!(verify(&secret, &code, now, Some(current + 1)), None);
+        assert_eq!(verify(&secret, &code, now, Some(current - 1)), Some(current));
+        // an older code stays refused once a newer one was used
+        let previous = code_for(now - STEP_SECONDS);
+        assert_eq!(verify(&secret, &previous, now, Some(current)), None);
+    }
+
+    #[test]
+    fn refuses_malformed_codes() {
+        let secret = base32_encode(RFC_SECRET);
+        let now = 1_111_111_111;
+        let code = code_for(now);
+        assert_eq!(verify(&secret, &code[..5], now, None), None);
+        assert_eq!(verify(&secret, &format!("{}0", code), now, None), None);
+        assert_eq!(verify(&secret, "12a456", now, None), None);
+        assert_eq!(verify("not base32!", &code, now, None), None);
+    }
+}
@@ -0,0 +1,167 @@
+use actix::{Actor, AsyncContext, Context};
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::prelude::*;
+use diesel::r2d2::ConnectionManager;
+use r2d2::Pool;
+
+use crate::errors::ServiceError;
+use crate::models::{Attachment, Note};
+use crate::storage::Storage;
+
+type SqlPool = Pool<ConnectionManager<SqliteConnection>>;
+
+/// Drops the blobs behind `digests` that no attachment refers to any more.
+/// Call it once the rows are gone for good, so a rolled back delete never
+/// loses a blob. Failures are only logged, the database is consistent
+/// either way.
+pub fn drop_unused_blobs(conn: &SqliteConnection, storage: &Storage, digests: &[String]) {
+    use crate::schema::attachments::dsl::*;
+    for digest in digests {
+        let remaining = attachments
+            .filter(sha256.eq(digest))
+            .count()
+            .get_result::<i64>(conn);
+        match remaining {
+            Ok(0) => {
+                if let Err(err) = storage.remove(digest) {
+                    println!("Removing blob {} failed: {}", digest, err);
+                }
+            }
+            Ok(_) => {}
+            Err(err) => println!("Checking blob {} failed: {}", digest, err),
+        }
+    }
+}
+
+/// Permanently removes a note together with everything that hangs off it.
+/// Returns the digests of its attachments for `drop_unused_blobs`.
+pub fn purge_note(conn: &SqliteConnection, note_id: &str) -> Result<Vec<String>, ServiceError> {
+    use crate::schema::note_comments::dsl as comments;
+    use crate::schema::note_grants::dsl as grants;
+    use crate::schema::note_revisions::dsl as revisions;
+    use crate::schema::note_shares::dsl as shares;
+    use crate::schema::note_tags::dsl as note_tags;
+    use crate::schema::notes::dsl as notes;
+    use crate::schema::notifications::dsl as notifications;
+    use crate::schema::reminders::dsl as reminders;
+    conn.transaction(|| {
+        let note = notes::notes
+            .filter(notes::id.eq(note_id))
+            .first::<Note>(conn)?;
+        let digests = Attachment::belonging_to(&note)
+            .load::<Attachment>(conn)?
+            .into_iter()
+            .map(|attachment| attachment.sha256)
+            .collect();
+        diesel::delete(Attachment::belonging_to(&note)).execute(conn)?;
+        diesel::delete(note_tags::note_tags.filter(note_tags::note_id.eq(note_id)))
+            .execute(conn)?;
+        diesel::delete(revisions::note_revisions.filter(revisions::note_id.eq(note_id)))
+            .execute(conn)?;
+        diesel::delete(reminders::reminders.filter(reminders::note_id.eq(note_id)))
+            .execute(conn)?;
+        diesel::delete(shares::note_shares.filter(shares::note_id.eq(note_id)))
+            .execute(conn)?;
+        diesel::delete(grants::note_grants.filter(grants::note_id.eq(note_id)))
+            .execute(conn)?;
+        diesel::delete(comments::note_comments.filter(comments::note_id.eq(note_id)))
+            .execute(conn)?;
+        // delivered notifications stay in the inbox without a link
+        diesel::update(notifications::notifications.filter(notifications::note_id.eq(note_id)))
+            .set(notifications::note_id.eq(None::<String>))
+            .execute(conn)?;
+        diesel::delete(&note).execute(conn)?;
+        Ok(digests)
+    })
+}
+
+/// Permanently removes a group. Notes posted to it stay with their authors
+/// as personal notes.
+pub fn purge_group(conn: &SqliteConnection, group_id: &str) -> Result<(), ServiceError> {
+    use crate::schema::group_invitations::dsl as invitations;
+    use crate::schema::group_join_codes::dsl as codes;
+    use crate::schema::group_join_requests::dsl as requests;
+    use crate::schema::group_links::dsl as links;
+    use crate::schema::groups::dsl as groups;
+    use crate::schema::notes::dsl as notes;
+    conn.transaction(|| {
+        diesel::update(notes::notes.filter(notes::group_id.eq(group_id)))
+            .set(notes::group_id.eq(None::<String>))
+            .execute(conn)?;
+        diesel::delete(links::group_links.filter(links::group_id.eq(group_id)))
+            .execute(conn)?;
+        diesel::delete(invitations::group_invitations.filter(invitations::group_id.eq(group_id)))
+            .execute(conn)?;
+        diesel::delete(requests::group_join_requests.filter(requests::group_id.eq(group_id)))
+            .execute(conn)?;
+        diesel::delete(codes::group_join_codes.filter(codes::group_id.eq(group_id)))
+            .execute(conn)?;
+        diesel::delete(groups::groups.filter(groups::id.eq(group_id))).execute(conn)?;
+        Ok(())
+    })
+}
+
+/// Purges every note and group that was trashed before `cutoff`.
+pub fn purge_expired(
+    conn: &SqliteConnection,
+    storage: &Storage,
+    cutoff: NaiveDateTime,
+) -> Result<(usize, usize), ServiceError> {
+    use crate::schema::groups::dsl as groups;
+    use crate::schema::notes::dsl as notes;
+    let note_ids = notes::notes
+        .filter(notes::deleted_at.lt(cutoff))
+        .select(notes::id)
+        .load::<String>(conn)?;
+    for note_id in &note_ids {
+        let digests = purge_note(conn, note_id)?;
+        drop_unused_blobs(conn, storage, &digests);
+    }
+    let group_ids = groups::groups
+        .filter(groups::deleted_at.lt(cutoff))
+        .select(groups::id)
+        .load::<String>(conn)?;
+    for group_id in &group_ids {
+        purge_group(conn, group_id)?;
+    }
+    Ok((note_ids.len(), group_ids.len()))
+}
+
+/// Background actor that periodically empties the trash of everything older
+/// than the retention period.
+pub struct TrashPurger {
+    pub pool: SqlPool,
+    pub storage: Storage,
+    pub retention: Duration,
+    pub interval: std::time::Duration,
+}
+
+impl TrashPurger {
+    fn purge(&self) {
+        let conn = match self.pool.get() {
+            Ok(conn) => conn,
+            Err(err) => {
+                println!("Trash purge skipped, no database connection: {}", err);
+                return;
+            }
+        };
+        let cutoff = Utc::now().naive_utc() - self.retention;
+        match purge_expired(&conn, &self.storage, cutoff) {
+            Ok((0, 0)) => {}
+            Ok((note_count, group_count)) => println!(
+                "Purged {} notes and {} groups from the trash",
+                note_count, group_count
+            ),
+            Err(err) => println!("Trash purge failed: {}", err),
+        }
+    }
+}
+
+impl Actor for TrashPurger {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.purge();
+        ctx.run_interval(self.interval, |purger, _| purger.purge());
+    }
+}